@@ -0,0 +1,408 @@
+use core::cell::Cell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use atomic_enum::atomic_enum;
+use chrono::{prelude::*, Duration};
+use critical_section::Mutex;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use heapless::String;
+
+use crate::{
+    buzzer::Buzzer,
+    ds3231::{self, DS3231},
+    i2c::I2c1Handle,
+    joystick::Joystick,
+    slot::Slot,
+};
+
+use super::{
+    layout, local_timezone, navigation::NavigationIcons, repeater::EditRepeater, strings::Str,
+    AppSharedState, AppStateTrait,
+};
+
+/// Alarm time before user sets it
+const DEFAULT_ALARM_HOUR: u32 = 7;
+/// How many ticks alarm rings before auto silence
+const RING_DURATION_TICKS: u32 = 60;
+/// Delay of alarm after snooze
+const SNOOZE_MINUTES: i64 = 5;
+/// Characters of shown alarm time "HH:MM". Edited fields are placed by it
+const TIME_CHARS: u32 = 5;
+
+#[atomic_enum]
+enum EditField {
+    Hours,
+    Minutes,
+}
+
+impl AtomicEditField {
+    fn next(&self) {
+        let new_field = match self.load(Ordering::Acquire) {
+            EditField::Hours => EditField::Minutes,
+            EditField::Minutes => EditField::Hours,
+        };
+
+        self.store(new_field, Ordering::Release);
+    }
+
+    fn prev(&self) {
+        // will work only with 2 fields
+        self.next();
+    }
+
+    fn edit_duration(&self) -> Duration {
+        match self.load(Ordering::Relaxed) {
+            EditField::Hours => Duration::hours(1),
+            EditField::Minutes => Duration::minutes(1),
+        }
+    }
+
+    fn time_add(&self, time: &Mutex<Cell<NaiveTime>>) {
+        let edit_amount = self.edit_duration();
+        critical_section::with(|cs| {
+            let t = time.borrow(cs);
+            t.set(t.get() + edit_amount);
+        });
+    }
+
+    fn time_sub(&self, time: &Mutex<Cell<NaiveTime>>) {
+        let edit_amount = self.edit_duration();
+        critical_section::with(|cs| {
+            let t = time.borrow(cs);
+            t.set(t.get() - edit_amount);
+        });
+    }
+}
+
+pub struct AlarmState {
+    state: Slot<AppSharedState>,
+
+    rtc: DS3231<I2c1Handle>,
+    buzzer: &'static Buzzer,
+
+    alarm_time: Mutex<Cell<NaiveTime>>,
+    /// Time of next ring after snooze
+    snooze_time: Mutex<Cell<Option<NaiveTime>>>,
+    enabled: AtomicBool,
+    /// Alarm already fired in current minute
+    fired: AtomicBool,
+    ringing: AtomicBool,
+    ring_ticks: AtomicU32,
+
+    edit_mode: AtomicBool,
+    edit_field: AtomicEditField,
+    edit_repeater: EditRepeater,
+}
+
+impl AlarmState {
+    pub fn new(rtc: DS3231<I2c1Handle>, buzzer: &'static Buzzer) -> Self {
+        Self {
+            state: Slot::empty(),
+            rtc,
+            buzzer,
+
+            alarm_time: Mutex::new(Cell::new(Self::default_alarm())),
+            snooze_time: Mutex::new(Cell::new(None)),
+            enabled: AtomicBool::new(false),
+            fired: AtomicBool::new(false),
+            ringing: AtomicBool::new(false),
+            ring_ticks: AtomicU32::new(0),
+
+            edit_mode: AtomicBool::new(false),
+            edit_field: AtomicEditField::new(EditField::Minutes),
+            edit_repeater: Default::default(),
+        }
+    }
+
+    fn default_alarm() -> NaiveTime {
+        NaiveTime::from_hms_opt(DEFAULT_ALARM_HOUR, 0, 0).unwrap()
+    }
+
+    /// Disables alarm and sets default time
+    pub fn reset(&self) {
+        self.set_alarm(Self::default_alarm(), false);
+        critical_section::with(|cs| self.snooze_time.borrow(cs).set(None));
+    }
+
+    /// Alarm time and whether it enabled
+    pub fn alarm(&self) -> (NaiveTime, bool) {
+        let time = critical_section::with(|cs| self.alarm_time.borrow(cs).get());
+        (time, self.enabled.load(Ordering::Relaxed))
+    }
+
+    pub fn set_alarm(&self, time: NaiveTime, enabled: bool) {
+        critical_section::with(|cs| self.alarm_time.borrow(cs).set(time));
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn ringing(&self) -> bool {
+        self.ringing.load(Ordering::Relaxed)
+    }
+
+    /// Counts ringing time. Must be called each second regardless of current state
+    ///
+    /// Returns true if alarm must be checked against RTC by [AlarmState::check_alarm]
+    pub fn tick_alarm(&self) -> bool {
+        if self.ringing() {
+            if self.ring_ticks.fetch_add(1, Ordering::Relaxed) >= RING_DURATION_TICKS {
+                // Nobody reacted, silence
+                self.dismiss();
+            }
+            return false;
+        }
+
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Checks alarm against RTC. Does blocking I2C read, must be called from low priority task
+    pub fn check_alarm(&self) {
+        if self.ringing() || !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Alarm is set in local time
+        let now = match self.rtc.update_time() {
+            Ok(t) => t.with_timezone(&local_timezone()).time(),
+            Err(ds3231::Error::Bus(_)) => {
+                AppSharedState::report_i2c_error();
+                return;
+            }
+            Err(_) => return,
+        };
+
+        let (alarm, snooze) = critical_section::with(|cs| {
+            (
+                self.alarm_time.borrow(cs).get(),
+                self.snooze_time.borrow(cs).get(),
+            )
+        });
+
+        let is_match = |t: NaiveTime| t.hour() == now.hour() && t.minute() == now.minute();
+        let matched = is_match(alarm) || snooze.map_or(false, is_match);
+
+        if !matched {
+            self.fired.store(false, Ordering::Relaxed);
+            return;
+        }
+
+        if !self.fired.swap(true, Ordering::Relaxed) {
+            critical_section::with(|cs| self.snooze_time.borrow(cs).set(None));
+            self.ring_ticks.store(0, Ordering::Relaxed);
+            self.ringing.store(true, Ordering::Relaxed);
+            self.buzzer.enable();
+        }
+    }
+
+    /// Stops ringing until next day
+    fn dismiss(&self) {
+        self.ringing.store(false, Ordering::Relaxed);
+        self.buzzer.disable();
+    }
+
+    /// Stops ringing and rings again after [SNOOZE_MINUTES]
+    fn snooze(&self) {
+        self.dismiss();
+
+        if let Ok(now) = self.rtc.update_time() {
+            let next =
+                now.with_timezone(&local_timezone()).time() + Duration::minutes(SNOOZE_MINUTES);
+            critical_section::with(|cs| self.snooze_time.borrow(cs).set(Some(next)));
+        }
+    }
+
+    /// Handles input while alarm rings. Works in any app state
+    pub fn handle_input_ringing<J: Joystick>(&self, j: &J) {
+        if j.clicked() && j.position().is_some() {
+            let pos = j.position().as_ref().unwrap();
+
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                Center => self.dismiss(),
+                _ => self.snooze(),
+            }
+        }
+    }
+
+    /// In normal mode allow navigation and mode switch
+    fn handle_input_normal_mode<J: Joystick>(&self, j: &J) -> bool {
+        if j.clicked() && j.position().is_some() {
+            let pos = j.position().as_ref().unwrap();
+
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                Left => {
+                    super::request_switch(false);
+                }
+                Right => {
+                    super::request_switch(true);
+                }
+                Center => {
+                    self.edit_mode.store(true, Ordering::Release);
+                }
+                Down => {
+                    self.enabled.fetch_xor(true, Ordering::Relaxed);
+                    critical_section::with(|cs| self.snooze_time.borrow(cs).set(None));
+                }
+
+                _ => return false,
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    /// In edit mode navigation unavaiable
+    fn handle_input_edit_mode<J: Joystick>(&self, j: &J) -> bool {
+        if j.position().is_none() {
+            return false;
+        }
+
+        if j.clicked() {
+            let pos = j.position().as_ref().unwrap();
+
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                // Up pressed
+                Up => self.edit_field.time_add(&self.alarm_time),
+                // Down pressed
+                Down => self.edit_field.time_sub(&self.alarm_time),
+                // Left pressed
+                Left => self.edit_field.prev(),
+                // Right pressed
+                Right => self.edit_field.next(),
+                Center => {
+                    // Editing alarm implies enabling it
+                    self.enabled.store(true, Ordering::Relaxed);
+                    self.fired.store(false, Ordering::Relaxed);
+                    self.edit_mode.store(false, Ordering::Release);
+                }
+            }
+        }
+
+        self.edit_repeater.update(j, |pos| {
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                // Up held
+                Up => self.edit_field.time_add(&self.alarm_time),
+                // Down held
+                Down => self.edit_field.time_sub(&self.alarm_time),
+                _ => {}
+            }
+        });
+
+        j.clicked()
+    }
+}
+
+impl AppStateTrait for AlarmState {
+    fn enter(&mut self, state: AppSharedState) {
+        self.state.put(state);
+    }
+
+    fn exit(&mut self) -> AppSharedState {
+        self.edit_mode.store(false, Ordering::Release);
+        self.state.take()
+    }
+
+    fn state(&self) -> &AppSharedState {
+        self.state.get().unwrap()
+    }
+
+    /// Ringing starts and ends on tick
+    fn version(&self) -> u32 {
+        self.ringing() as u32
+    }
+
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
+        if self.edit_mode.load(Ordering::Acquire) {
+            self.handle_input_edit_mode(j)
+        } else {
+            self.handle_input_normal_mode(j)
+        }
+    }
+
+    fn switch_allowed(&self) -> bool {
+        !self.edit_mode.load(Ordering::Relaxed)
+    }
+}
+
+impl Drawable for AlarmState {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.draw_header(target, Str::Alarm.text())?;
+
+        let is_edit = self.edit_mode.load(Ordering::Relaxed);
+        let state = self.state();
+
+        // Draw UI hints
+        if is_edit {
+            let field = self.edit_field.load(Ordering::Relaxed);
+            let x_pos = layout::field_x(TIME_CHARS, field as u32);
+
+            state.navigation_icons.draw_icon(
+                target,
+                NavigationIcons::Up,
+                Point::new(x_pos, layout::FIELD_ABOVE),
+            )?;
+
+            state.navigation_icons.draw_icon(
+                target,
+                NavigationIcons::Down,
+                Point::new(x_pos, layout::FIELD_BELOW),
+            )?;
+        } else {
+            self.draw_navigation(target)?;
+
+            let enable_hint = if self.enabled.load(Ordering::Relaxed) {
+                Str::Disable
+            } else {
+                Str::Enable
+            };
+
+            self.draw_hint(
+                target,
+                NavigationIcons::Down,
+                layout::HINT_UPPER,
+                enable_hint.text(),
+            )?;
+        }
+
+        let center_button_hint = if self.ringing() {
+            Str::Dismiss
+        } else if is_edit {
+            Str::Apply
+        } else {
+            Str::Edit
+        };
+
+        self.draw_hint(
+            target,
+            NavigationIcons::Center,
+            layout::HINT_LOWER,
+            center_button_hint.text(),
+        )?;
+
+        // Draw alarm time
+        let mut buf: String<32> = Default::default();
+        let time = critical_section::with(|cs| self.alarm_time.borrow(cs).get());
+
+        write!(&mut buf, "{:02}:{:02}", time.hour(), time.minute()).unwrap();
+
+        self.draw_content(target, &buf)?;
+
+        Ok(())
+    }
+}
@@ -1,10 +1,10 @@
 use core::cell::Cell;
 use core::fmt::Write;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use atomic_enum::atomic_enum;
 use chrono::{prelude::*, Duration};
-use critical_section::Mutex;
+use critical_section::{CriticalSection, Mutex};
 use embedded_graphics::{
     pixelcolor::BinaryColor,
     prelude::*,
@@ -12,38 +12,60 @@ use embedded_graphics::{
 };
 use heapless::String;
 
-use crate::{ds3231::DS3231, i2c::I2c1Handle, joystick::Joystick, speedchanger::SpeedChanger};
+use crate::{
+    ds3231::{self, DS3231},
+    format::format_temperature,
+    i2c::I2c1Handle,
+    joystick::Joystick,
+    slot::Slot,
+    uptime::uptime_seconds,
+};
 
-use super::{navigation::NavigationIcons, AppSharedState, AppStateTrait};
+use super::{
+    layout, navigation::NavigationIcons, repeater::EditRepeater, strings::Str, write_utc_offset,
+    AppSharedState, AppStateTrait,
+};
 
-const SPEED_STEPS: u32 = 8;
-const ACCELERAION_TICKS: u32 = 10;
+/// Displayed time is counted in software and corrected from RTC once per this amount of ticks
+const RTC_SYNC_TICKS: u32 = 60;
+/// Date and world clock take turns below time with this period in seconds
+const WORLD_CLOCK_SWITCH_SECONDS: i64 = 5;
+/// Characters of shown time "HH:MM:SS". Edited fields are placed by it
+const TIME_CHARS: u32 = 8;
 
 #[atomic_enum]
 enum EditField {
     Hours,
     Minutes,
+    Seconds,
 }
 
 impl AtomicEditField {
     fn next(&self) {
         let new_field = match self.load(Ordering::Acquire) {
             EditField::Hours => EditField::Minutes,
-            EditField::Minutes => EditField::Hours,
+            EditField::Minutes => EditField::Seconds,
+            EditField::Seconds => EditField::Hours,
         };
 
         self.store(new_field, Ordering::Release);
     }
 
     fn prev(&self) {
-        // will work only with 2 fields
-        self.next();
+        let new_field = match self.load(Ordering::Acquire) {
+            EditField::Hours => EditField::Seconds,
+            EditField::Minutes => EditField::Hours,
+            EditField::Seconds => EditField::Minutes,
+        };
+
+        self.store(new_field, Ordering::Release);
     }
 
     fn edit_duration(&self) -> Duration {
         match self.load(Ordering::Relaxed) {
             EditField::Hours => Duration::hours(1),
             EditField::Minutes => Duration::minutes(1),
+            EditField::Seconds => Duration::seconds(1),
         }
     }
 
@@ -65,33 +87,153 @@ impl AtomicEditField {
 }
 
 pub struct ClockState {
-    state: Option<AppSharedState>,
+    state: Slot<AppSharedState>,
 
     rtc: DS3231<I2c1Handle>,
+    /// Software clock. Advanced here by seconds counted in [crate::uptime], which `tick` task owns and updates
+    /// without state lock, so seconds passed while lock was busy are caught up on next tick
     display_time: Mutex<Cell<DateTime<Utc>>>,
+    /// Uptime seconds already added to `display_time`
+    counted_uptime: AtomicU32,
+    /// Last RTC access failed, displayed time is counted only in software
+    rtc_error: AtomicBool,
+    /// Ticks since last sync with RTC
+    ticks_since_sync: AtomicU32,
 
     edit_mode: AtomicBool,
     edit_field: AtomicEditField,
-    edit_speed: SpeedChanger<SPEED_STEPS>,
-    edit_acceleration: SpeedChanger<ACCELERAION_TICKS>,
+    edit_repeater: EditRepeater,
 }
 
 impl ClockState {
     pub fn new(rtc: DS3231<I2c1Handle>) -> Self {
         Self {
-            state: None,
+            state: Slot::empty(),
             rtc,
             display_time: Mutex::new(Cell::new(Default::default())),
+            counted_uptime: AtomicU32::new(0),
+            rtc_error: AtomicBool::new(false),
+            ticks_since_sync: AtomicU32::new(0),
 
             edit_mode: AtomicBool::new(false),
             edit_field: AtomicEditField::new(EditField::Minutes),
-            edit_speed: Default::default(),
-            edit_acceleration: Default::default(),
+            edit_repeater: Default::default(),
+        }
+    }
+
+    /// Remembers result of RTC access. Bus failure is also reported to header error icon
+    fn set_rtc_error(&self, error: Option<ds3231::Error>) {
+        self.rtc_error.store(error.is_some(), Ordering::Relaxed);
+        if let Some(ds3231::Error::Bus(_)) = error {
+            AppSharedState::report_i2c_error();
+        }
+    }
+
+    /// Replaces displayed time. Seconds counted before are already included in `time`
+    fn set_display_time(&self, cs: CriticalSection, time: DateTime<Utc>) {
+        self.display_time.borrow(cs).set(time);
+        self.counted_uptime
+            .store(uptime_seconds(), Ordering::Relaxed);
+    }
+
+    /// Reads time from RTC and replaces displayed one unless user edits it
+    pub fn sync_rtc(&self) {
+        if self.edit_mode.load(Ordering::Acquire) {
+            return;
+        }
+
+        match self.rtc.update_time() {
+            Ok(time) => {
+                self.set_rtc_error(None);
+                critical_section::with(|cs| {
+                    // Edit may be started while reading
+                    if !self.edit_mode.load(Ordering::Acquire) {
+                        self.set_display_time(cs, time);
+                    }
+                });
+            }
+            Err(e) => self.set_rtc_error(Some(e)),
         }
     }
 
+    /// Draw weekday and date below time. Also drawn in edit mode: hours edit can cross midnight
+    fn draw_date<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        time: &DateTime<FixedOffset>,
+    ) -> Result<(), D::Error> {
+        let weekday = Str::Weekday(time.weekday()).text();
+
+        let mut buf: String<32> = Default::default();
+        write!(
+            &mut buf,
+            "{} {:02}.{:02}.{:04}",
+            weekday,
+            time.day(),
+            time.month(),
+            time.year()
+        )
+        .unwrap();
+
+        // Between time and center button hint
+        Text::with_alignment(
+            &buf,
+            layout::SUBLINE,
+            self.state().small_text_style,
+            Alignment::Center,
+        )
+        .draw(target)?;
+
+        Ok(())
+    }
+
+    /// Draw world clock in place of date. Day shift is shown if world clock is on other date than local one
+    fn draw_world_time<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        time: &DateTime<FixedOffset>,
+        world_offset: i8,
+    ) -> Result<(), D::Error> {
+        let state = self.state();
+        let world_time = time.with_timezone(&super::offset_timezone(world_offset));
+
+        let mut buf: String<32> = Default::default();
+        write_utc_offset(&mut buf, world_offset).unwrap();
+        write!(
+            &mut buf,
+            " {:02}:{:02}",
+            display_hour(world_time.hour(), state.hour_format_12()),
+            world_time.minute()
+        )
+        .unwrap();
+
+        if state.hour_format_12() {
+            buf.push_str(am_pm(world_time.hour())).unwrap();
+        }
+
+        // Offsets may put world clock on other day, chrono handles wrap
+        let day_shift = world_time
+            .naive_local()
+            .date()
+            .signed_duration_since(time.naive_local().date())
+            .num_days();
+        if day_shift != 0 {
+            write!(&mut buf, " {:+}{}", day_shift, Str::Days.text()).unwrap();
+        }
+
+        Text::with_alignment(
+            &buf,
+            layout::SUBLINE,
+            state.small_text_style,
+            Alignment::Center,
+        )
+        .draw(target)?;
+
+        Ok(())
+    }
+
     /// In normal mode allow navigation and mode switch
-    fn handle_input_normal_mode<J: Joystick>(&self, j: &J) {
+    fn handle_input_normal_mode<J: Joystick>(&self, j: &J) -> bool {
         if j.clicked() && j.position().is_some() {
             let pos = j.position().as_ref().unwrap();
 
@@ -101,10 +243,10 @@ impl ClockState {
                 Left => {
                     // Request from app mode switch
                     // It will run after exit from this function due low priority
-                    crate::app::change_state::spawn(false).ok();
+                    super::request_switch(false);
                 }
                 Right => {
-                    crate::app::change_state::spawn(true).ok();
+                    super::request_switch(true);
                 }
                 Center => {
                     self.edit_mode.store(true, Ordering::Release);
@@ -115,17 +257,19 @@ impl ClockState {
                     })
                 }
 
-                _ => {}
+                _ => return false,
             }
+
+            return true;
         }
+
+        false
     }
 
     /// In edit mode navigation unavaiable
-    fn handle_input_edit_mode<J: Joystick>(&self, j: &J) {
-        const HOLD_DURATION_TICK: u32 = 10;
-
+    fn handle_input_edit_mode<J: Joystick>(&self, j: &J) -> bool {
         if j.position().is_none() {
-            return;
+            return false;
         }
 
         if j.clicked() {
@@ -144,76 +288,94 @@ impl ClockState {
                 Right => self.edit_field.next(),
                 Center => {
                     // Set time and exit form edit mode
-                    critical_section::with(|cs| {
-                        let dt = self.display_time.borrow(cs);
-                        self.rtc.set_time(dt.get()).unwrap();
-                    });
+                    // Bus accessed outside of critical section: it must be free to finish DMA transfer
+                    let dt = critical_section::with(|cs| self.display_time.borrow(cs).get());
+                    let result = self.rtc.set_time(dt);
+                    if result.is_ok() {
+                        super::request_stopwatch_backup();
+                    }
+                    self.set_rtc_error(result.err());
                     self.edit_mode.store(false, Ordering::Release);
                 }
             }
         }
 
-        if j.hold_time() > HOLD_DURATION_TICK {
-            let pos = j.position().as_ref().unwrap();
-
+        self.edit_repeater.update(j, |pos| {
             use crate::joystick::JoystickButton::*;
-            self.edit_speed.execute(|| {
-                match pos {
-                    // Up pressed
-                    Up => self.edit_field.time_add(&self.display_time),
-                    // Down pressed
-                    Down => self.edit_field.time_sub(&self.display_time),
-                    _ => {}
-                }
-            });
 
-            self.edit_acceleration.execute(|| {
-                self.edit_speed.decrement_max_div();
-            });
-        } else {
-            self.edit_speed.reset();
-            self.edit_acceleration.reset();
-        }
+            match pos {
+                // Up held
+                Up => self.edit_field.time_add(&self.display_time),
+                // Down held
+                Down => self.edit_field.time_sub(&self.display_time),
+                _ => {}
+            }
+        });
+
+        j.clicked()
     }
 }
 
 impl AppStateTrait for ClockState {
     fn enter(&mut self, state: AppSharedState) {
-        assert!(self.state.is_none());
-        self.state = Some(state);
+        self.state.put(state);
 
-        // Get time from RTC module
-        let time = self.rtc.update_time().unwrap();
-        critical_section::with(|cs| {
-            self.display_time.borrow(cs).set(time);
-        });
+        // Get time from RTC module. Without RTC keep counting from current value
+        match self.rtc.update_time() {
+            Ok(time) => {
+                self.set_rtc_error(None);
+                critical_section::with(|cs| self.set_display_time(cs, time));
+            }
+            Err(e) => self.set_rtc_error(Some(e)),
+        }
     }
 
     fn exit(&mut self) -> AppSharedState {
-        self.state.take().expect("exit called without enter")
+        self.state.take()
     }
 
     fn state(&self) -> &AppSharedState {
-        self.state.as_ref().unwrap()
+        self.state.get().unwrap()
     }
 
     fn tick(&self) {
-        // On tick increment time if not in edit mode
-        if !self.edit_mode.load(Ordering::Relaxed) {
-            critical_section::with(|cs| {
+        // Usually one second, more if previous ticks could not reach state
+        let elapsed = critical_section::with(|cs| {
+            let uptime = uptime_seconds();
+            let elapsed = uptime.wrapping_sub(self.counted_uptime.swap(uptime, Ordering::Relaxed));
+
+            // Time stands still in edit mode
+            if !self.edit_mode.load(Ordering::Relaxed) {
                 let dt = self.display_time.borrow(cs);
-                dt.set(dt.get() + Duration::seconds(1))
-            });
+                dt.set(dt.get() + Duration::seconds(elapsed as i64));
+            }
+
+            elapsed
+        });
+
+        // Correct software clock drift. Reading here would block high priority task on bus
+        if self.ticks_since_sync.fetch_add(elapsed, Ordering::Relaxed) + elapsed >= RTC_SYNC_TICKS {
+            self.ticks_since_sync.store(0, Ordering::Relaxed);
+            crate::app::sync_rtc::spawn().ok();
         }
     }
 
-    fn handle_input<J: Joystick>(&self, j: &J) {
+    /// Displayed time changes on tick and on edit
+    fn version(&self) -> u32 {
+        critical_section::with(|cs| self.display_time.borrow(cs).get()).timestamp() as u32
+    }
+
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
         if self.edit_mode.load(Ordering::Acquire) {
             self.handle_input_edit_mode(j)
         } else {
             self.handle_input_normal_mode(j)
         }
     }
+
+    fn switch_allowed(&self) -> bool {
+        !self.edit_mode.load(Ordering::Relaxed)
+    }
 }
 
 impl Drawable for ClockState {
@@ -224,82 +386,135 @@ impl Drawable for ClockState {
     where
         D: DrawTarget<Color = Self::Color>,
     {
-        self.draw_header(target, "ЧАСЫ")?;
+        self.draw_header(target, Str::Clock.text())?;
 
         let is_edit = self.edit_mode.load(Ordering::Relaxed);
 
         // Draw UI hints
 
         if is_edit {
-            let y_above = 19;
-            let y_below = 40;
-
             let field = self.edit_field.load(Ordering::Relaxed);
-            let x_pos = match field {
-                EditField::Hours => 36,
-                EditField::Minutes => 64,
-            };
+            let x_pos = layout::field_x(TIME_CHARS, field as u32);
 
             self.state().navigation_icons.draw_icon(
                 target,
                 NavigationIcons::Up,
-                Point {
-                    x: x_pos,
-                    y: y_above,
-                },
+                Point::new(x_pos, layout::FIELD_ABOVE),
             )?;
 
             self.state().navigation_icons.draw_icon(
                 target,
                 NavigationIcons::Down,
-                Point {
-                    x: x_pos,
-                    y: y_below,
-                },
+                Point::new(x_pos, layout::FIELD_BELOW),
             )?;
         } else {
             self.draw_navigation(target)?;
         }
 
-        let center_button_hint = if is_edit {
-            "Применить"
-        } else {
-            "Изменить"
-        };
+        let center_button_hint = if is_edit { Str::Apply } else { Str::Edit };
 
         let state = self.state();
-        state.navigation_icons.draw_icon_and_text(
+        self.draw_hint(
             target,
             NavigationIcons::Center,
-            Point::new(20, 56),
-            Text::new(
-                center_button_hint,
-                Default::default(),
-                state.small_text_style,
-            ),
+            layout::HINT_LOWER,
+            center_button_hint.text(),
         )?;
 
         // Draw time
         let mut buf: String<32> = Default::default();
-        let time = critical_section::with(|cs| self.display_time.borrow(cs).get());
+        let utc_time = critical_section::with(|cs| self.display_time.borrow(cs).get());
+        // RTC keeps UTC. Local time may be on other day, chrono handles wrap
+        let time = utc_time.with_timezone(&state.timezone());
+
+        // Only display is affected, edit works with 24-hour value
+        let hour_format_12 = state.hour_format_12();
+        let hour = display_hour(time.hour(), hour_format_12);
+
+        // Colons are visible on even seconds. Font is monospaced so space keeps centered layout
+        let separator = if is_edit || time.second() % 2 == 0 {
+            ':'
+        } else {
+            ' '
+        };
 
         write!(
             &mut buf,
-            "{:02}:{:02}:{:02}",
-            time.hour(),
+            "{:02}{}{:02}{}{:02}",
+            hour,
+            separator,
             time.minute(),
+            separator,
             time.second()
         )
         .unwrap();
 
-        Text::with_alignment(
-            &buf,
-            Point { x: 64, y: 34 },
-            self.state().content_style,
-            Alignment::Center,
-        )
-        .draw(target)?;
+        if self.rtc_error.load(Ordering::Relaxed) {
+            // Date is meaningless without RTC, show problem in its place
+            Text::with_alignment(
+                Str::RtcError.text(),
+                layout::SUBLINE,
+                state.small_text_style,
+                Alignment::Center,
+            )
+            .draw(target)?;
+        } else {
+            // Edit shows date only: hours edit can cross midnight
+            match state.world_offset().filter(|_| !is_edit) {
+                Some(offset) if utc_time.timestamp() / WORLD_CLOCK_SWITCH_SECONDS % 2 != 0 => {
+                    self.draw_world_time(target, &time, offset)?
+                }
+                _ => self.draw_date(target, &time)?,
+            }
+        }
+
+        // Hint row has free space right of center hint
+        if let Some(celsius) = state.temperature() {
+            let mut temperature_buf = [0_u8; 16];
+            if let Ok(temperature) = format_temperature(&mut temperature_buf, celsius) {
+                Text::with_alignment(
+                    temperature,
+                    layout::STATUS_LOWER_RIGHT,
+                    state.small_text_style,
+                    Alignment::Right,
+                )
+                .draw(target)?;
+            }
+        }
+
+        // Suffix drawn with small font right to time so digits stay in place of 24-hour layout
+        if hour_format_12 {
+            Text::new(
+                am_pm(time.hour()),
+                layout::content_suffix(TIME_CHARS),
+                state.small_text_style,
+            )
+            .draw(target)?;
+        }
+
+        self.draw_content(target, &buf)?;
 
         Ok(())
     }
 }
+
+/// Hour as displayed in selected format
+fn display_hour(hour: u32, hour_format_12: bool) -> u32 {
+    if !hour_format_12 {
+        return hour;
+    }
+
+    match hour % 12 {
+        0 => 12,
+        h => h,
+    }
+}
+
+/// Suffix of 12-hour format
+fn am_pm(hour: u32) -> &'static str {
+    if hour < 12 {
+        "AM"
+    } else {
+        "PM"
+    }
+}
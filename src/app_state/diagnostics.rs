@@ -0,0 +1,217 @@
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, text::Text};
+use heapless::String;
+
+use crate::{
+    ds3231::{self, DS3231},
+    format::format_temperature,
+    i2c::{self, I2c1Handle},
+    joystick::Joystick,
+    slot::Slot,
+};
+
+use super::{strings::Str, AppSharedState, AppStateTrait};
+
+/// Baseline of first info row
+const FIRST_ROW_Y: i32 = 24;
+const ROW_HEIGHT: i32 = 10;
+const ROW_X: i32 = 4;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Screen is shown, so `diagnose` task keeps refreshing info
+static SHOWN: AtomicBool = AtomicBool::new(false);
+/// RTC temperature of last refresh or why it failed. None until first refresh after enter
+static RTC_TEMPERATURE: Mutex<Cell<Option<Result<f32, Str>>>> = Mutex::new(Cell::new(None));
+/// Addresses answered last bus scan, bit per address. None until first refresh after enter
+static FOUND_DEVICES: Mutex<Cell<Option<u128>>> = Mutex::new(Cell::new(None));
+
+/// Firmware and hardware info for field diagnostics. Hidden state, see [super::AppStateHolder::show_diagnostics]
+///
+/// Bus is not touched by draw: info is read by `diagnose` task, see [DiagnosticsState::refresh]
+pub struct DiagnosticsState {
+    state: Slot<AppSharedState>,
+}
+
+impl DiagnosticsState {
+    pub fn new() -> Self {
+        Self {
+            state: Slot::empty(),
+        }
+    }
+
+    /// Reads RTC temperature and scans bus for the screen. Returns false once screen is left, so task
+    /// stops repeating
+    ///
+    /// Does blocking I2C access, must be called from low priority task
+    pub fn refresh(rtc: &DS3231<I2c1Handle>, i2c: &Mutex<RefCell<I2c1Handle>>) -> bool {
+        if !SHOWN.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let temperature = rtc.temperature().map_err(|e| {
+            AppSharedState::report_i2c_error();
+            rtc_error(&e)
+        });
+
+        let mut found = [false; 128];
+        i2c::scan(i2c, &mut found);
+        let found = found
+            .iter()
+            .enumerate()
+            .filter(|(_, &f)| f)
+            .fold(0_u128, |bits, (addr, _)| bits | (1 << addr));
+
+        critical_section::with(|cs| {
+            RTC_TEMPERATURE.borrow(cs).set(Some(temperature));
+            FOUND_DEVICES.borrow(cs).set(Some(found));
+        });
+
+        true
+    }
+}
+
+impl Default for DiagnosticsState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppStateTrait for DiagnosticsState {
+    fn enter(&mut self, state: AppSharedState) {
+        self.state.put(state);
+
+        // Info of previous visit is stale
+        critical_section::with(|cs| {
+            RTC_TEMPERATURE.borrow(cs).set(None);
+            FOUND_DEVICES.borrow(cs).set(None);
+        });
+        SHOWN.store(true, Ordering::Relaxed);
+        crate::app::diagnose::spawn().ok();
+    }
+
+    fn exit(&mut self) -> AppSharedState {
+        SHOWN.store(false, Ordering::Relaxed);
+        self.state.take()
+    }
+
+    fn state(&self) -> &AppSharedState {
+        self.state.get().unwrap()
+    }
+
+    /// Uptime changes each second, refreshed info is shown by the same redraw
+    fn version(&self) -> u32 {
+        crate::uptime::uptime().num_seconds() as u32
+    }
+
+    /// Any click returns home
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
+        if j.clicked() && j.position().is_some() {
+            crate::app::return_home::spawn().ok();
+            return true;
+        }
+
+        false
+    }
+
+    /// Left and Right return home
+    fn switch_allowed(&self) -> bool {
+        false
+    }
+}
+
+impl Drawable for DiagnosticsState {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.draw_header(target, Str::Diagnostics.text())?;
+
+        let state = self.state();
+
+        let mut rows: [String<32>; 4] = Default::default();
+
+        write!(
+            &mut rows[0],
+            "{}: {}",
+            Str::Version.text(),
+            env!("CARGO_PKG_VERSION")
+        )
+        .unwrap();
+
+        let uptime = crate::uptime::uptime().num_seconds();
+        let day_seconds = uptime % SECONDS_PER_DAY;
+        write!(
+            &mut rows[1],
+            "{}: {}{} {:02}:{:02}:{:02}",
+            Str::Uptime.text(),
+            uptime / SECONDS_PER_DAY,
+            Str::Days.text(),
+            day_seconds / 3600,
+            day_seconds / 60 % 60,
+            day_seconds % 60
+        )
+        .unwrap();
+
+        let (temperature, found) = critical_section::with(|cs| {
+            (
+                RTC_TEMPERATURE.borrow(cs).get(),
+                FOUND_DEVICES.borrow(cs).get(),
+            )
+        });
+
+        let mut buf = [0_u8; 16];
+        let temperature = match temperature {
+            Some(Ok(celsius)) => format_temperature(&mut buf, celsius).unwrap_or("?"),
+            Some(Err(text)) => text.text(),
+            None => Str::NoValue.text(),
+        };
+        write!(
+            &mut rows[2],
+            "{}: {}",
+            Str::RtcTemperature.text(),
+            temperature
+        )
+        .unwrap();
+
+        // Devices answering on bus, so missing one is seen at once. Error is shown instead while bus fails
+        write!(&mut rows[3], "{}:", Str::I2cBus.text()).unwrap();
+        match found {
+            _ if state.i2c_error() => write!(&mut rows[3], " {}", Str::Error.text()).unwrap(),
+            Some(found) => {
+                let addresses = (0..128).filter(|addr| found & (1 << addr) != 0);
+                for addr in addresses {
+                    // Addresses not fitting the row are cut
+                    if write!(&mut rows[3], " {:02X}", addr).is_err() {
+                        break;
+                    }
+                }
+            }
+            None => write!(&mut rows[3], " {}", Str::NoValue.text()).unwrap(),
+        }
+
+        for (i, row) in rows.iter().enumerate() {
+            let position = Point::new(ROW_X, FIRST_ROW_Y + ROW_HEIGHT * i as i32);
+            Text::new(row, position, state.small_text_style).draw(target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Tells absent RTC from misbehaving one. Busy or faulty bus says nothing about RTC itself
+fn rtc_error(e: &ds3231::Error) -> Str {
+    match e {
+        ds3231::Error::Bus(e) if e.device_missing() => Str::NotFound,
+        ds3231::Error::Bus(e) if e.data_rejected() => Str::Error,
+        _ => Str::NoValue,
+    }
+}
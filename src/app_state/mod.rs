@@ -1,18 +1,33 @@
+use core::cell::Cell;
+use core::sync::atomic::{
+    AtomicBool, AtomicI16, AtomicI32, AtomicI8, AtomicU32, AtomicU8, Ordering,
+};
+
 use embedded_graphics::{
-    mono_font::{MonoTextStyle, MonoTextStyleBuilder},
+    mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     prelude::*,
     primitives::PrimitiveStyleBuilder,
     text::{Alignment, Text},
 };
 
-use crate::joystick::Joystick;
+use chrono::{FixedOffset, NaiveTime, Timelike};
+
+use crate::app::{CountdownTimer, StopwatchTimer};
+use crate::joystick::{ms_to_updates, Joystick, JoystickButton};
+use crate::layout;
+use crate::settings::{corrected_temperature, Settings, MAX_TEMPERATURE_OFFSET};
 
 pub mod prelude {
+    pub use super::alarm::AlarmState;
     pub use super::clock::ClockState;
+    pub use super::diagnostics::DiagnosticsState;
+    pub use super::settings::SettingsState;
     pub use super::stopwatch::StopwatchState;
     pub use super::timer::TimerState;
 
+    pub use super::draw_splash;
+    pub use super::take_pending_switch;
     pub use super::AppSharedState;
     pub use super::AppStateHolder;
     pub use super::AppStateTrait;
@@ -22,6 +37,10 @@ pub mod prelude {
 mod clock;
 use clock::ClockState;
 
+/// Alarm clock state
+mod alarm;
+use alarm::AlarmState;
+
 /// Stopwatch state
 mod stopwatch;
 use stopwatch::StopwatchState;
@@ -30,80 +49,297 @@ use stopwatch::StopwatchState;
 mod timer;
 use timer::TimerState;
 
+/// Settings menu state
+mod settings;
+use settings::SettingsState;
+
+/// Diagnostics info state
+mod diagnostics;
+use diagnostics::DiagnosticsState;
+
+/// Accelerating repeat of held buttons
+mod repeater;
+use repeater::ListRepeater;
+
+/// UI strings of compiled language
+mod strings;
+
 /// Basic primitives for drawing navigation hints
 mod navigation;
 use navigation::{NavigationDrawables, NavigationIcons};
 
-/// Macro for using in [AppStateHolder] to run state method
-macro_rules! run_state_func {
-    ($holder: expr, $function: ident) => {
-        match $holder.state {
-            AppState::Clock => $holder.clock_state.$function(),
-            AppState::Stopwatch => $holder.stopwatch_state.$function(),
-            AppState::Timer => $holder.timer_state.$function(),
+/// The only list of app states. New state is added here and in [AppStateHolder::new] call
+///
+/// Entry is `Variant = persisted id => holder field: type`. Entries are in switch order,
+/// first one is entered on boot. Persisted id is saved to EEPROM so it must never change
+///
+/// Entries after `;` are hidden: not in switch order and not restored on boot. They are entered by input combo
+///
+/// Passes the list to `$callback` after `$prefix` tokens
+macro_rules! with_app_states {
+    ($callback: ident ! ($($prefix: tt)*)) => {
+        $callback! {
+            $($prefix)*
+            Clock = 0 => clock_state: ClockState,
+            Alarm = 1 => alarm_state: AlarmState,
+            Stopwatch = 3 => stopwatch_state: StopwatchState<StopwatchTimer>,
+            Timer = 2 => timer_state: TimerState<CountdownTimer>,
+            Settings = 4 => settings_state: SettingsState;
+            Diagnostics = 5 => diagnostics_state: DiagnosticsState,
         }
     };
+}
 
-    ($holder: expr, $function: ident, $arg: expr) => {
+/// Declares [AppState], [SWITCH_ORDER] and [AppStateHolder] from state list
+macro_rules! declare_app_states {
+    (
+        $($variant: ident = $id: literal => $field: ident: $ty: ty),+;
+        $($hidden_variant: ident = $hidden_id: literal => $hidden_field: ident: $hidden_ty: ty),* $(,)?
+    ) => {
+        /// Current app states
+        #[repr(u8)]
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        enum AppState {
+            $($variant = $id,)+
+            $($hidden_variant = $hidden_id,)*
+        }
+
+        /// Order of states on switch. Rotation is defined by position here, not by enum value
+        const SWITCH_ORDER: &[AppState] = &[$(AppState::$variant),+];
+
+        impl AppState {
+            fn from_u8(value: u8) -> Option<Self> {
+                match value {
+                    $($id => Some(AppState::$variant),)+
+                    _ => None,
+                }
+            }
+
+            /// Name of variant, stable for logs
+            #[cfg(feature = "telemetry")]
+            fn name(self) -> &'static str {
+                match self {
+                    $(AppState::$variant => stringify!($variant),)+
+                    $(AppState::$hidden_variant => stringify!($hidden_variant),)*
+                }
+            }
+        }
+
+        pub struct AppStateHolder {
+            state: AppState,
+            $($field: $ty,)+
+            $($hidden_field: $hidden_ty,)*
+
+            /// Bumped on input and state switch. See [AppStateTrait::version]
+            input_version: AtomicU32,
+            /// Ticks without input. See [IDLE_TIMEOUT_TICKS]
+            idle_ticks: AtomicU32,
+            /// Ticks without input while display is on. See [DISPLAY_SLEEP_TICKS]
+            awake_ticks: AtomicU32,
+            /// Repeats state switch while Left or Right held
+            switch_repeater: ListRepeater,
+        }
+
+        impl AppStateHolder {
+            /// Takes states in list order, hidden ones last, and enters the first one
+            #[allow(clippy::too_many_arguments)]
+            pub fn new(
+                $($field: $ty,)+
+                $($hidden_field: $hidden_ty,)*
+                shared_state: AppSharedState,
+            ) -> Self {
+                let mut holder = Self {
+                    state: SWITCH_ORDER[0],
+                    $($field,)+
+                    $($hidden_field,)*
+
+                    input_version: AtomicU32::new(0),
+                    idle_ticks: AtomicU32::new(0),
+                    awake_ticks: AtomicU32::new(0),
+                    switch_repeater: Default::default(),
+                };
+
+                holder.enter(shared_state);
+                holder
+            }
+        }
+    };
+}
+
+with_app_states!(declare_app_states!());
+
+/// Ticks without input after which first state(clock) is shown again
+const IDLE_TIMEOUT_TICKS: u32 = 60;
+/// Ticks without input after which display panel is turned off
+const DISPLAY_SLEEP_TICKS: u32 = 600;
+/// Up and Down held together for this amount of joystick updates open diagnostics
+const DIAGNOSTICS_HOLD_TICKS: u32 = ms_to_updates(2000);
+
+/// Runs method of current state. `$args` is parenthesized argument list
+macro_rules! dispatch_state_func {
+    (
+        $holder: expr, $function: ident, $args: tt;
+        $($variant: ident = $id: literal => $field: ident: $ty: ty),+;
+        $($hidden_variant: ident = $hidden_id: literal => $hidden_field: ident: $hidden_ty: ty),* $(,)?
+    ) => {
         match $holder.state {
-            AppState::Clock => $holder.clock_state.$function($arg),
-            AppState::Stopwatch => $holder.stopwatch_state.$function($arg),
-            AppState::Timer => $holder.timer_state.$function($arg),
+            $(AppState::$variant => $holder.$field.$function $args,)+
+            $(AppState::$hidden_variant => $holder.$hidden_field.$function $args,)*
         }
     };
 }
 
-/// Current app states
-enum AppState {
-    Clock,
-    Timer,
-    Stopwatch,
+/// Macro for using in [AppStateHolder] to run state method
+macro_rules! run_state_func {
+    ($holder: expr, $function: ident) => {
+        with_app_states!(dispatch_state_func!($holder, $function, ();))
+    };
+
+    ($holder: expr, $function: ident, $arg: expr) => {
+        with_app_states!(dispatch_state_func!($holder, $function, ($arg);))
+    };
 }
 
-pub struct AppStateHolder {
-    state: AppState,
-    clock_state: ClockState,
-    timer_state: TimerState,
-    stopwatch_state: StopwatchState,
+impl AppState {
+    /// Next state in [SWITCH_ORDER]. Hidden state switches to first one
+    fn next(self) -> Self {
+        match self.position() {
+            Some(p) => SWITCH_ORDER[(p + 1) % SWITCH_ORDER.len()],
+            None => SWITCH_ORDER[0],
+        }
+    }
+
+    /// Previous state in [SWITCH_ORDER]. Hidden state switches to first one
+    fn prev(self) -> Self {
+        match self.position() {
+            Some(p) => SWITCH_ORDER[(p + SWITCH_ORDER.len() - 1) % SWITCH_ORDER.len()],
+            None => SWITCH_ORDER[0],
+        }
+    }
+
+    /// Position in [SWITCH_ORDER], None for hidden state
+    fn position(self) -> Option<usize> {
+        SWITCH_ORDER.iter().position(|s| *s == self)
+    }
 }
 
 impl AppStateHolder {
-    pub fn new(
-        mut clock: ClockState,
-        timer: TimerState,
-        stopwatch: StopwatchState,
-        shared_state: AppSharedState,
-    ) -> Self {
-        clock.enter(shared_state);
-
-        Self {
-            state: AppState::Clock,
-            clock_state: clock,
-            timer_state: timer,
-            stopwatch_state: stopwatch,
+    /// Moves `steps` states forward in switch order, backward if negative
+    pub fn switch_by(&mut self, steps: i32) {
+        let mut state = self.state;
+        for _ in 0..steps.unsigned_abs() {
+            state = if steps > 0 {
+                state.next()
+            } else {
+                state.prev()
+            };
         }
+
+        self.switch_to(state);
     }
 
-    /// Switch to next state
-    pub fn next(&mut self) {
+    /// Hands shared state over from current state to `state`
+    fn switch_to(&mut self, state: AppState) {
+        if state == self.state {
+            return;
+        }
+
         let shared_state = self.exit();
-        self.state = match self.state {
-            AppState::Clock => AppState::Stopwatch,
-            AppState::Stopwatch => AppState::Timer,
-            AppState::Timer => AppState::Clock,
-        };
+        self.state = state;
         self.enter(shared_state);
+        self.input_version.fetch_add(1, Ordering::Relaxed);
+        self.idle_ticks.store(0, Ordering::Relaxed);
     }
 
-    /// Switch to previous state
-    pub fn prev(&mut self) {
-        let shared_state = self.exit();
-        self.state = match self.state {
-            AppState::Clock => AppState::Timer,
-            AppState::Stopwatch => AppState::Clock,
-            AppState::Timer => AppState::Stopwatch,
-        };
-        self.enter(shared_state);
+    /// Alarm rings or finished countdown sounds: user must see it and silence it with first press
+    fn sounding(&self) -> bool {
+        self.alarm_state.ringing() || self.timer_state.alarm_sounding()
+    }
+
+    /// Switch to first state. Only screen is changed: running stopwatch and countdown continue
+    pub fn return_home(&mut self) {
+        self.switch_to(SWITCH_ORDER[0]);
+    }
+
+    /// Switch to diagnostics info. It is hidden state, any click returns home from it
+    pub fn show_diagnostics(&mut self) {
+        self.switch_to(AppState::Diagnostics);
+    }
+
+    /// Name of current state, e.g. "Clock". Used by telemetry
+    #[cfg(feature = "telemetry")]
+    pub fn state_name(&self) -> &'static str {
+        self.state.name()
+    }
+
+    /// Corrects displayed time from RTC. Does blocking I2C read, must be called from low priority task
+    pub fn sync_rtc(&self) {
+        if self.state == AppState::Clock {
+            self.clock_state.sync_rtc();
+        }
+    }
+
+    /// Checks alarm against RTC. Does blocking I2C read, must be called from low priority task
+    pub fn check_alarm(&self) {
+        self.alarm_state.check_alarm();
+    }
+
+    /// Collects settings to persist
+    pub fn settings(&self) -> Settings {
+        let (alarm_time, alarm_enabled) = self.alarm_state.alarm();
+
+        Settings {
+            app_state: self.state as u8,
+            alarm_hour: alarm_time.hour() as u8,
+            alarm_minute: alarm_time.minute() as u8,
+            alarm_enabled,
+            brightness: self.state().brightness(),
+            volume: self.state().volume(),
+            hour_format_12: self.state().hour_format_12(),
+            utc_offset: self.state().utc_offset(),
+            key_click: self.state().key_click(),
+            world_offset: self.state().world_offset(),
+            alarm_escalation: self.state().alarm_escalation(),
+            night_start: self.state().night_start(),
+            night_end: self.state().night_end(),
+            night_brightness: self.state().night_brightness(),
+            temperature_offset: self.state().temperature_offset(),
+        }
+    }
+
+    /// Returns all settings to their initial values
+    pub fn reset_to_defaults(&mut self) {
+        self.alarm_state.reset();
+        self.timer_state.reset_presets();
+        self.settings_state.reset();
+        self.state().reset();
+        self.input_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Applies settings loaded on boot
+    pub fn restore(&mut self, settings: &Settings) {
+        if let Some(time) =
+            NaiveTime::from_hms_opt(settings.alarm_hour as u32, settings.alarm_minute as u32, 0)
+        {
+            self.alarm_state.set_alarm(time, settings.alarm_enabled);
+        }
+
+        self.state().set_brightness(settings.brightness);
+        self.state().set_volume(settings.volume);
+        self.state().set_hour_format_12(settings.hour_format_12);
+        self.state().set_utc_offset(settings.utc_offset);
+        self.state().set_key_click(settings.key_click);
+        self.state().set_world_offset(settings.world_offset);
+        self.state().set_alarm_escalation(settings.alarm_escalation);
+        self.state().set_night_start(settings.night_start);
+        self.state().set_night_end(settings.night_end);
+        self.state().set_night_brightness(settings.night_brightness);
+        self.state()
+            .set_temperature_offset(settings.temperature_offset);
+
+        if let Some(state) = AppState::from_u8(settings.app_state) {
+            self.switch_to(state);
+        }
     }
 }
 
@@ -135,27 +371,535 @@ impl AppStateTrait for AppStateHolder {
     }
 
     fn tick(&self) {
+        // Alarm must be checked in any state. RTC read is too slow for tick priority
+        if self.alarm_state.tick_alarm() {
+            crate::app::check_alarm::spawn().ok();
+        }
+        // Sounding alarm must be visible. Running countdown keeps display awake, so it doesn't end on dark screen
+        if self.sounding() {
+            AppSharedState::display_on();
+            self.awake_ticks.store(0, Ordering::Relaxed);
+        } else if self.timer_state.counting() {
+            self.awake_ticks.store(0, Ordering::Relaxed);
+        } else if !AppSharedState::display_asleep()
+            && self.awake_ticks.fetch_add(1, Ordering::Relaxed) + 1 >= DISPLAY_SLEEP_TICKS
+        {
+            AppSharedState::sleep_display();
+            self.awake_ticks.store(0, Ordering::Relaxed);
+        }
+
+        // Spawned once: counter is reset by switch or input
+        if self.state != SWITCH_ORDER[0]
+            && self.idle_ticks.fetch_add(1, Ordering::Relaxed) + 1 == IDLE_TIMEOUT_TICKS
+        {
+            crate::app::return_home::spawn().ok();
+        }
+
+        // Error icon hides itself if errors stopped
+        I2C_ERROR_TICKS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| t.checked_sub(1))
+            .ok();
+
         run_state_func!(self, tick)
     }
 
-    fn handle_input<J: Joystick>(&self, joystick: &J) {
-        run_state_func!(self, handle_input, joystick)
+    fn handle_input<J: Joystick>(&self, joystick: &J) -> bool {
+        // Any input may change screen: pressed or held button acts, release changes hints
+        if joystick.position().is_some() || joystick.just_unpressed() {
+            self.input_version.fetch_add(1, Ordering::Relaxed);
+            self.idle_ticks.store(0, Ordering::Relaxed);
+            self.awake_ticks.store(0, Ordering::Relaxed);
+        }
+
+        // Asleep display is woken by click, which is swallowed so waking doesn't start anything.
+        // Later updates of the same hold pass, so held button acts as if pressed after wake.
+        // Sounding alarm wakes display itself and click passes to silence it at once
+        if AppSharedState::display_asleep() {
+            if self.sounding() {
+                AppSharedState::display_on();
+            } else {
+                if joystick.clicked() {
+                    AppSharedState::display_on();
+                }
+                return false;
+            }
+        }
+
+        // Ringing alarm takes all input until snoozed or dismissed. Buzzer is busy, so no click
+        if self.alarm_state.ringing() {
+            self.alarm_state.handle_input_ringing(joystick);
+            return false;
+        }
+
+        // Combo is checked once when threshold is crossed. Position reports Up while both pressed
+        if joystick.position() == &Some(JoystickButton::Up)
+            && joystick.pressed(JoystickButton::Down)
+            && joystick.hold_time() == DIAGNOSTICS_HOLD_TICKS
+            && self.state != AppState::Diagnostics
+        {
+            crate::app::show_diagnostics::spawn().ok();
+        }
+
+        let mut consumed = run_state_func!(self, handle_input, joystick);
+
+        // Click is handled by state itself, held Left or Right keeps switching
+        if self.switch_allowed() {
+            let switched = Cell::new(false);
+            self.switch_repeater.update(joystick, |pos| {
+                let next = match pos {
+                    JoystickButton::Left => false,
+                    JoystickButton::Right => true,
+                    _ => return,
+                };
+                request_switch(next);
+                switched.set(true);
+            });
+            consumed |= switched.get();
+        }
+
+        if consumed && self.state().key_click() {
+            crate::app::key_click::spawn(true).ok();
+        }
+
+        consumed
     }
+
+    fn switch_allowed(&self) -> bool {
+        run_state_func!(self, switch_allowed)
+    }
+
+    fn version(&self) -> u32 {
+        let state_version = run_state_func!(self, version);
+        let alarm_version = self.alarm_state.version();
+        let indicators_version =
+            crate::battery::battery_low() as u32 | (self.state().i2c_error() as u32) << 1;
+        let header_version = if HEADER_SCROLLING.load(Ordering::Relaxed) {
+            crate::uptime::uptime_seconds()
+        } else {
+            0
+        };
+
+        mix_version(
+            mix_version(self.input_version.load(Ordering::Relaxed), state_version),
+            mix_version(
+                mix_version(alarm_version, indicators_version),
+                header_version,
+            ),
+        )
+    }
+}
+
+/// Header of current state scrolls, so frame changes each second. See [AppStateTrait::draw_header]
+static HEADER_SCROLLING: AtomicBool = AtomicBool::new(false);
+/// Visible part of scrolling header: 21 characters of small font, 2 bytes each for Cyrillic
+const HEADER_BUF_SIZE: usize = 48;
+
+/// Combines versions so change of any part changes result
+#[inline]
+fn mix_version(a: u32, b: u32) -> u32 {
+    a.wrapping_mul(0x9E37_79B1) ^ b
+}
+
+const DEFAULT_BRIGHTNESS: u8 = 100;
+const DEFAULT_VOLUME: u8 = 100;
+/// Night hours are `[start, end)` in local time
+const DEFAULT_NIGHT_START: u8 = 22;
+const DEFAULT_NIGHT_END: u8 = 7;
+const DEFAULT_NIGHT_BRIGHTNESS: u8 = 10;
+/// Stored in place of night brightness while auto dim is off
+const NIGHT_DIM_OFF: u8 = u8::MAX;
+
+/// Display brightness in percents. See [AppSharedState] for access rules
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(DEFAULT_BRIGHTNESS);
+/// Buzzer volume in percents. See [AppSharedState] for access rules
+static VOLUME: AtomicU8 = AtomicU8::new(DEFAULT_VOLUME);
+
+/// UTC offset of displayed time in [UTC_OFFSET_STEP_MINUTES] units. See [AppSharedState] for access rules
+static UTC_OFFSET: AtomicI8 = AtomicI8::new(0);
+/// Hours in 12-hour format. See [AppSharedState] for access rules
+static HOUR_FORMAT_12: AtomicBool = AtomicBool::new(false);
+/// Click on accepted input. See [AppSharedState] for access rules
+static KEY_CLICK: AtomicBool = AtomicBool::new(true);
+/// World clock UTC offset, [WORLD_CLOCK_OFF] if it is off. See [AppSharedState] for access rules
+static WORLD_OFFSET: AtomicI8 = AtomicI8::new(WORLD_CLOCK_OFF);
+/// Temperature correction in half degrees. See [AppSharedState] for access rules
+static TEMPERATURE_OFFSET: AtomicI8 = AtomicI8::new(0);
+
+/// Display brightness in night hours, [NIGHT_DIM_OFF] if display is not dimmed. See [AppSharedState] for access rules
+static NIGHT_BRIGHTNESS: AtomicU8 = AtomicU8::new(DEFAULT_NIGHT_BRIGHTNESS);
+/// Local hour night starts at. See [AppSharedState] for access rules
+static NIGHT_START: AtomicU8 = AtomicU8::new(DEFAULT_NIGHT_START);
+/// Local hour night ends at. See [AppSharedState] for access rules
+static NIGHT_END: AtomicU8 = AtomicU8::new(DEFAULT_NIGHT_END);
+/// Night hours now. Updated by `auto_dim` from RTC time
+static NIGHT: AtomicBool = AtomicBool::new(false);
+/// Brightness changed by user in night hours: dimming waits for next night, so it doesn't fight the change
+static NIGHT_DIM_SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Display panel is off after [DISPLAY_SLEEP_TICKS] without input. First click only wakes it,
+/// see [AppStateHolder::handle_input]
+static DISPLAY_ASLEEP: AtomicBool = AtomicBool::new(false);
+
+/// Countdown alarm starts quiet and grows louder. See [AppSharedState] for access rules
+static ALARM_ESCALATION: AtomicBool = AtomicBool::new(true);
+
+/// Last RTC sensor temperature in quarters of degree, [NO_TEMPERATURE] until first read. See [AppSharedState::set_temperature]
+static TEMPERATURE_QUARTERS: AtomicI16 = AtomicI16::new(NO_TEMPERATURE);
+/// Stored in place of temperature while it is unknown
+const NO_TEMPERATURE: i16 = i16::MIN;
+
+/// Ticks left to show I2C error icon. See [AppSharedState::report_i2c_error]
+static I2C_ERROR_TICKS: AtomicU32 = AtomicU32::new(0);
+/// How many ticks I2C error icon stays after last error
+const I2C_ERROR_SHOW_TICKS: u32 = 5;
+
+/// State switches requested but not yet made by `change_state`: positive forward, negative backward
+static PENDING_SWITCH_STEPS: AtomicI32 = AtomicI32::new(0);
+
+/// Granularity of UTC offset. Some timezones are shifted by 30 or 45 minutes
+pub const UTC_OFFSET_STEP_MINUTES: i32 = 15;
+/// UTC-12:00
+pub const MIN_UTC_OFFSET: i8 = -12 * 4;
+/// UTC+14:00
+pub const MAX_UTC_OFFSET: i8 = 14 * 4;
+
+/// Stored in place of second timezone offset while world clock is off
+const WORLD_CLOCK_OFF: i8 = i8::MIN;
+
+/// Requests switch to next or previous state. Switch itself is made later by `change_state` task
+///
+/// Requests are counted, so none is lost if several come before `change_state` runs
+pub fn request_switch(next: bool) {
+    PENDING_SWITCH_STEPS.fetch_add(if next { 1 } else { -1 }, Ordering::Relaxed);
+    // Fails only if `change_state` is already queued. Queued one takes this request too
+    crate::app::change_state::spawn().ok();
+}
+
+/// Requests stopwatch backup. Must follow each stopwatch start, pause and stop, and each RTC write:
+/// running stopwatch is kept as its start time by RTC
+pub fn request_stopwatch_backup() {
+    // Fails only if backup is already queued. Queued one saves latest state too
+    #[cfg(feature = "stopwatch-backup")]
+    crate::app::backup_stopwatch::spawn().ok();
+}
+
+/// Takes requested switches, see [request_switch]. Returns steps for [AppStateHolder::switch_by]
+pub fn take_pending_switch() -> i32 {
+    PENDING_SWITCH_STEPS.swap(0, Ordering::Relaxed)
+}
+
+/// Timezone of displayed time. RTC itself always keeps UTC
+pub fn local_timezone() -> FixedOffset {
+    offset_timezone(UTC_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Timezone of offset in [UTC_OFFSET_STEP_MINUTES] units
+fn offset_timezone(offset: i8) -> FixedOffset {
+    FixedOffset::east_opt(offset as i32 * UTC_OFFSET_STEP_MINUTES * 60).unwrap()
+}
+
+/// Writes offset in [UTC_OFFSET_STEP_MINUTES] units as `UTC+hh:mm`
+pub fn write_utc_offset<W: core::fmt::Write>(w: &mut W, offset: i8) -> core::fmt::Result {
+    let minutes = offset as i32 * UTC_OFFSET_STEP_MINUTES;
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.abs();
+    write!(w, "UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
 }
 
 /// Shared between all states
+///
+/// Settings are `'static` atomics instead of owned values: the state itself is moved between app states on
+/// switch and is unreachable while `change_state` holds the write lock, but `draw`(priority 1) and buzzer
+/// users up to TIM interrupts(priority 5) must read them at any moment. State created in place of lost one,
+/// see [AppStateTrait::exit], refers to the same values, so settings survive it.
+/// Values are written by `handle_input`(priority 3) on edits, `auto_dim`(priority 2) with night flag,
+/// `factory_reset`(priority 1) with defaults and [AppStateHolder::restore] on boot. Single byte stores
+/// can't tear and nothing else is published with them, so `Relaxed` ordering is enough.
 pub struct AppSharedState {
     header_style: MonoTextStyle<'static, BinaryColor>,
     content_style: MonoTextStyle<'static, BinaryColor>,
     small_text_style: MonoTextStyle<'static, BinaryColor>,
 
     navigation_icons: NavigationDrawables,
+
+    /// Display brightness in percents
+    brightness: &'static AtomicU8,
+    /// Buzzer volume in percents
+    volume: &'static AtomicU8,
+    /// Display hours in 12-hour format with AM/PM suffix
+    hour_format_12: &'static AtomicBool,
+    /// UTC offset in [UTC_OFFSET_STEP_MINUTES] units
+    utc_offset: &'static AtomicI8,
+    /// Click buzzer on each accepted input
+    key_click: &'static AtomicBool,
+    /// UTC offset of world clock in [UTC_OFFSET_STEP_MINUTES] units, [WORLD_CLOCK_OFF] if it is off
+    world_offset: &'static AtomicI8,
+    /// Countdown alarm grows from short quiet beeps to full volume
+    alarm_escalation: &'static AtomicBool,
+    /// Local hour night starts at
+    night_start: &'static AtomicU8,
+    /// Local hour night ends at
+    night_end: &'static AtomicU8,
+    /// Display brightness in percents in night hours, [NIGHT_DIM_OFF] if display is not dimmed
+    night_brightness: &'static AtomicU8,
+    /// Correction added to measured temperature in half degrees
+    temperature_offset: &'static AtomicI8,
 }
 
-impl Default for AppSharedState {
-    fn default() -> Self {
-        use embedded_graphics::mono_font::iso_8859_5::{FONT_6X10, FONT_9X15_BOLD};
+impl AppSharedState {
+    /// Brightness to apply to display, readable without access to app state. Night brightness is used
+    /// in night hours unless user changed brightness during this night
+    #[inline]
+    pub fn display_brightness() -> u8 {
+        let night_brightness = NIGHT_BRIGHTNESS.load(Ordering::Relaxed);
+        if NIGHT.load(Ordering::Relaxed)
+            && !NIGHT_DIM_SUSPENDED.load(Ordering::Relaxed)
+            && night_brightness != NIGHT_DIM_OFF
+        {
+            night_brightness
+        } else {
+            BRIGHTNESS.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Display panel must be off. Readable without access to app state
+    #[inline]
+    pub fn display_asleep() -> bool {
+        DISPLAY_ASLEEP.load(Ordering::Relaxed)
+    }
+
+    /// Turns display panel off until next click or alarm
+    pub fn sleep_display() {
+        DISPLAY_ASLEEP.store(true, Ordering::Relaxed);
+    }
+
+    /// Turns display panel on
+    pub fn display_on() {
+        DISPLAY_ASLEEP.store(false, Ordering::Relaxed);
+    }
+
+    /// Volume readable without access to app state
+    #[inline]
+    pub fn volume_source() -> &'static AtomicU8 {
+        &VOLUME
+    }
+
+    /// Alarm escalation readable without access to app state
+    #[inline]
+    pub fn alarm_escalation_source() -> &'static AtomicBool {
+        &ALARM_ESCALATION
+    }
+
+    /// Shows error icon in header for [I2C_ERROR_SHOW_TICKS]. Called from failed bus operations,
+    /// including tasks without access to app state
+    #[inline]
+    pub fn report_i2c_error() {
+        I2C_ERROR_TICKS.store(I2C_ERROR_SHOW_TICKS, Ordering::Relaxed);
+    }
+
+    /// Stores temperature read by `read_temperature`, None if read failed
+    #[inline]
+    pub fn set_temperature(celsius: Option<f32>) {
+        let quarters = celsius.map_or(NO_TEMPERATURE, |c| (c * 4.0) as i16);
+        TEMPERATURE_QUARTERS.store(quarters, Ordering::Relaxed);
+    }
+
+    /// Last read temperature in Celsius, corrected by [AppSharedState::temperature_offset]
+    #[inline]
+    pub fn temperature(&self) -> Option<f32> {
+        match TEMPERATURE_QUARTERS.load(Ordering::Relaxed) {
+            NO_TEMPERATURE => None,
+            quarters => Some(corrected_temperature(
+                quarters as f32 / 4.0,
+                self.temperature_offset(),
+            )),
+        }
+    }
+
+    /// Correction of measured temperature in half degrees. Sensor near the board reads high due self-heating
+    #[inline]
+    pub fn temperature_offset(&self) -> i8 {
+        self.temperature_offset.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_temperature_offset(&self, half_degrees: i8) {
+        let half_degrees = half_degrees.clamp(-MAX_TEMPERATURE_OFFSET, MAX_TEMPERATURE_OFFSET);
+        self.temperature_offset
+            .store(half_degrees, Ordering::Relaxed);
+    }
+
+    /// Some I2C operation failed recently
+    #[inline]
+    pub fn i2c_error(&self) -> bool {
+        I2C_ERROR_TICKS.load(Ordering::Relaxed) != 0
+    }
+
+    #[inline]
+    pub fn brightness(&self) -> u8 {
+        self.brightness.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_brightness(&self, percent: u8) {
+        self.brightness.store(percent.min(100), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn volume(&self) -> u8 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_volume(&self, percent: u8) {
+        self.volume.store(percent.min(100), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn hour_format_12(&self) -> bool {
+        self.hour_format_12.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_hour_format_12(&self, enabled: bool) {
+        self.hour_format_12.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn utc_offset(&self) -> i8 {
+        self.utc_offset.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_utc_offset(&self, offset: i8) {
+        self.utc_offset.store(
+            offset.clamp(MIN_UTC_OFFSET, MAX_UTC_OFFSET),
+            Ordering::Relaxed,
+        );
+    }
+
+    #[inline]
+    pub fn key_click(&self) -> bool {
+        self.key_click.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_key_click(&self, enabled: bool) {
+        self.key_click.store(enabled, Ordering::Relaxed);
+    }
+
+    /// UTC offset of world clock, None if it is off
+    #[inline]
+    pub fn world_offset(&self) -> Option<i8> {
+        match self.world_offset.load(Ordering::Relaxed) {
+            WORLD_CLOCK_OFF => None,
+            offset => Some(offset),
+        }
+    }
+
+    #[inline]
+    pub fn set_world_offset(&self, offset: Option<i8>) {
+        let offset = offset.map_or(WORLD_CLOCK_OFF, |o| o.clamp(MIN_UTC_OFFSET, MAX_UTC_OFFSET));
+        self.world_offset.store(offset, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn alarm_escalation(&self) -> bool {
+        self.alarm_escalation.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_alarm_escalation(&self, enabled: bool) {
+        self.alarm_escalation.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn night_start(&self) -> u8 {
+        self.night_start.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_night_start(&self, hour: u8) {
+        self.night_start.store(hour % 24, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn night_end(&self) -> u8 {
+        self.night_end.load(Ordering::Relaxed)
+    }
 
+    #[inline]
+    pub fn set_night_end(&self, hour: u8) {
+        self.night_end.store(hour % 24, Ordering::Relaxed);
+    }
+
+    /// Display brightness in night hours, None if display is not dimmed
+    #[inline]
+    pub fn night_brightness(&self) -> Option<u8> {
+        match self.night_brightness.load(Ordering::Relaxed) {
+            NIGHT_DIM_OFF => None,
+            percent => Some(percent),
+        }
+    }
+
+    #[inline]
+    pub fn set_night_brightness(&self, percent: Option<u8>) {
+        let percent = percent.map_or(NIGHT_DIM_OFF, |p| p.min(100));
+        self.night_brightness.store(percent, Ordering::Relaxed);
+    }
+
+    /// Is local `hour` in night hours. Night may cross midnight, equal start and end mean no night
+    pub fn is_night_hour(&self, hour: u8) -> bool {
+        let (start, end) = (self.night_start(), self.night_end());
+        if start <= end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+
+    /// Switches between day and night brightness. Manual change made in previous night is forgotten
+    pub fn set_night(&self, night: bool) {
+        if NIGHT.swap(night, Ordering::Relaxed) != night {
+            NIGHT_DIM_SUSPENDED.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Keeps day brightness until next night. Called on manual brightness change
+    pub fn suspend_night_dim(&self) {
+        if NIGHT.load(Ordering::Relaxed) {
+            NIGHT_DIM_SUSPENDED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Timezone of displayed time
+    #[inline]
+    pub fn timezone(&self) -> FixedOffset {
+        local_timezone()
+    }
+
+    /// Returns user settings to initial values. Styles are not settings
+    pub fn reset(&self) {
+        self.set_brightness(DEFAULT_BRIGHTNESS);
+        self.set_volume(DEFAULT_VOLUME);
+        self.set_hour_format_12(false);
+        self.set_utc_offset(0);
+        self.set_key_click(true);
+        self.set_world_offset(None);
+        self.set_alarm_escalation(true);
+        self.set_night_start(DEFAULT_NIGHT_START);
+        self.set_night_end(DEFAULT_NIGHT_END);
+        self.set_night_brightness(Some(DEFAULT_NIGHT_BRIGHTNESS));
+        self.set_temperature_offset(0);
+    }
+
+    /// Creates state drawing UI with given fonts. Font must cover characters of UI strings, and layout
+    /// assumes fonts about the size of default ones: 9x15 for header and content, 6x10 for small text
+    pub fn new(
+        header_font: &'static MonoFont<'static>,
+        content_font: &'static MonoFont<'static>,
+        small_font: &'static MonoFont<'static>,
+    ) -> Self {
         let primitive_style = PrimitiveStyleBuilder::new()
             .stroke_width(1)
             .stroke_color(BinaryColor::On)
@@ -164,27 +908,75 @@ impl Default for AppSharedState {
 
         Self {
             header_style: MonoTextStyleBuilder::new()
-                .font(&FONT_9X15_BOLD)
+                .font(header_font)
                 .text_color(BinaryColor::On)
                 .build(),
             content_style: MonoTextStyleBuilder::new()
-                .font(&FONT_9X15_BOLD)
+                .font(content_font)
                 .text_color(BinaryColor::On)
                 .build(),
             small_text_style: MonoTextStyleBuilder::new()
-                .font(&FONT_6X10)
+                .font(small_font)
                 .text_color(BinaryColor::On)
                 .build(),
 
             navigation_icons: NavigationDrawables::new(&primitive_style),
+
+            brightness: &BRIGHTNESS,
+            volume: &VOLUME,
+            hour_format_12: &HOUR_FORMAT_12,
+            utc_offset: &UTC_OFFSET,
+            key_click: &KEY_CLICK,
+            world_offset: &WORLD_OFFSET,
+            alarm_escalation: &ALARM_ESCALATION,
+            night_start: &NIGHT_START,
+            night_end: &NIGHT_END,
+            night_brightness: &NIGHT_BRIGHTNESS,
+            temperature_offset: &TEMPERATURE_OFFSET,
         }
     }
 }
 
+impl Default for AppSharedState {
+    /// Cyrillic fonts of ISO-8859-5 set
+    fn default() -> Self {
+        use embedded_graphics::mono_font::iso_8859_5::{FONT_6X10, FONT_9X15_BOLD};
+
+        Self::new(&FONT_9X15_BOLD, &FONT_9X15_BOLD, &FONT_6X10)
+    }
+}
+
+/// Draws boot screen with firmware name and version
+pub fn draw_splash<D: DrawTarget<Color = BinaryColor>>(target: &mut D) -> Result<(), D::Error> {
+    use embedded_graphics::mono_font::iso_8859_5::{FONT_6X10, FONT_9X15_BOLD};
+
+    Text::with_alignment(
+        "DP-RUST",
+        Point { x: 64, y: 30 },
+        MonoTextStyle::new(&FONT_9X15_BOLD, BinaryColor::On),
+        Alignment::Center,
+    )
+    .draw(target)?;
+
+    Text::with_alignment(
+        concat!("v", env!("CARGO_PKG_VERSION")),
+        Point { x: 64, y: 46 },
+        MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+        Alignment::Center,
+    )
+    .draw(target)?;
+
+    Ok(())
+}
+
 pub trait AppStateTrait: Drawable<Color = BinaryColor, Output = ()> {
     /// enters in application state with specified shared state
+    ///
+    /// Redundant enter is ignored, state keeps shared state it already holds. Must not panic: it would halt firmware
     fn enter(&mut self, state: AppSharedState);
     /// exit from state and return shared state. Will block if some task in progress so should be in low priority task
+    ///
+    /// Exit without enter returns default shared state instead of panic
     fn exit(&mut self) -> AppSharedState;
 
     /// Shared state getter
@@ -194,18 +986,78 @@ pub trait AppStateTrait: Drawable<Color = BinaryColor, Output = ()> {
     /// This is high priority function
     fn tick(&self) {}
 
-    fn handle_input<J: Joystick>(&self, joystick: &J);
+    /// Handles joystick. Returns true if input caused an action, holder confirms it with key click
+    fn handle_input<J: Joystick>(&self, joystick: &J) -> bool;
+
+    /// Left and Right switch states now. Edit modes use them for own navigation, so held ones don't switch there
+    fn switch_allowed(&self) -> bool {
+        true
+    }
+
+    /// Version of drawn content. Draw is skipped if version is the same as in previous frame
+    ///
+    /// Changes caused by input are tracked by [AppStateHolder], so state reports only what changes
+    /// by itself: time, running timers, alarm
+    fn version(&self) -> u32;
 
     /// Draw header at top of display
     fn draw_header<D: DrawTarget<Color = BinaryColor>>(
         &self,
         target: &mut D,
         header: &str,
+    ) -> Result<(), D::Error> {
+        let state = self.state();
+
+        // Long header is drawn with small font, and scrolls if it overflows even then
+        let style = if layout::text_width(header, state.header_style.font) <= layout::SCREEN_WIDTH {
+            state.header_style
+        } else {
+            state.small_text_style
+        };
+
+        let visible = layout::chars_fitting(layout::SCREEN_WIDTH, style.font);
+        let len = header.chars().count();
+        HEADER_SCROLLING.store(len > visible, Ordering::Relaxed);
+
+        let mut buf: heapless::String<HEADER_BUF_SIZE> = Default::default();
+        let text = if len > visible {
+            // One character per second, with gap between end and start of next round
+            let offset = crate::uptime::uptime_seconds() as usize;
+            layout::scroll_window(header, visible, offset)
+                .try_for_each(|c| buf.push(c))
+                .ok();
+            buf.as_str()
+        } else {
+            header
+        };
+
+        Text::with_alignment(text, layout::HEADER, style, Alignment::Center).draw(target)?;
+
+        if crate::battery::battery_low() {
+            self.state()
+                .navigation_icons
+                .draw_battery(target, Point::new(116, 1))?;
+        }
+
+        if self.state().i2c_error() {
+            self.state()
+                .navigation_icons
+                .draw_warning(target, Point::new(1, 1))?;
+        }
+
+        Ok(())
+    }
+
+    /// Draw main value of state centered in [layout::CONTENT]
+    fn draw_content<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        text: &str,
     ) -> Result<(), D::Error> {
         Text::with_alignment(
-            header,
-            Point { x: 64, y: 10 },
-            self.state().header_style,
+            text,
+            layout::CONTENT,
+            self.state().content_style,
             Alignment::Center,
         )
         .draw(target)?;
@@ -213,6 +1065,23 @@ pub trait AppStateTrait: Drawable<Color = BinaryColor, Output = ()> {
         Ok(())
     }
 
+    /// Draw button icon with its action at hint row, e.g. [layout::HINT_LOWER]
+    fn draw_hint<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        icon: NavigationIcons,
+        position: Point,
+        text: &str,
+    ) -> Result<(), D::Error> {
+        let state = self.state();
+        state.navigation_icons.draw_icon_and_text(
+            target,
+            icon,
+            position,
+            Text::new(text, Default::default(), state.small_text_style),
+        )
+    }
+
     /// Draw 2 triangles to indicate mode switch posibility
     fn draw_navigation<D: DrawTarget<Color = BinaryColor>>(
         &self,
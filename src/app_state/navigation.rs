@@ -4,6 +4,8 @@ use embedded_graphics::prelude::*;
 use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, Styled, Triangle};
 use embedded_graphics::text::Text;
 
+use super::layout;
+
 pub type StyledTriangle = Styled<Triangle, PrimitiveStyle<BinaryColor>>;
 pub type StyledRectangle = Styled<Rectangle, PrimitiveStyle<BinaryColor>>;
 pub type StyledText<'s, 't> = Text<'t, MonoTextStyle<'s, BinaryColor>>;
@@ -23,6 +25,15 @@ pub struct NavigationDrawables {
     left: StyledTriangle,
     right: StyledTriangle,
     center: StyledRectangle,
+
+    battery_body: StyledRectangle,
+    battery_tip: StyledRectangle,
+
+    warning_body: StyledTriangle,
+    warning_mark: StyledRectangle,
+
+    /// Style of filled part of progress bar
+    progress_style: PrimitiveStyle<BinaryColor>,
 }
 
 impl NavigationDrawables {
@@ -37,9 +48,63 @@ impl NavigationDrawables {
             right: Triangle::new(Point::new(3, 0), Point::new(-3, 3), Point::new(-3, -3))
                 .into_styled(*style),
             center: Rectangle::new(Point::new(-3, -3), Size::new(6, 6)).into_styled(*style),
+
+            battery_body: Rectangle::new(Point::new(0, 0), Size::new(10, 6)).into_styled(*style),
+            battery_tip: Rectangle::new(Point::new(10, 2), Size::new(1, 2)).into_styled(*style),
+
+            warning_body: Triangle::new(Point::new(3, 0), Point::new(6, 6), Point::new(0, 6))
+                .into_styled(*style),
+            warning_mark: Rectangle::new(Point::new(3, 2), Size::new(1, 3))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On)),
+
+            progress_style: PrimitiveStyle::with_fill(BinaryColor::On),
         }
     }
 
+    /// Draws bar in `area` filled from left proportionally to `value / max`. Nothing is drawn if `max` is 0
+    pub fn draw_progress<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        area: Rectangle,
+        value: u32,
+        max: u32,
+    ) -> Result<(), D::Error> {
+        if max == 0 {
+            return Ok(());
+        }
+
+        // u64: width * value overflows u32 for long countdowns
+        let width = area.size.width as u64 * value.min(max) as u64 / max as u64;
+
+        Rectangle::new(area.top_left, Size::new(width as u32, area.size.height))
+            .into_styled(self.progress_style)
+            .draw(target)
+    }
+
+    /// Draws empty battery icon with top left corner at `position`. Icon is 11 x 6
+    pub fn draw_battery<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        position: Point,
+    ) -> Result<(), D::Error> {
+        self.battery_body.translate(position).draw(target)?;
+        self.battery_tip.translate(position).draw(target)?;
+
+        Ok(())
+    }
+
+    /// Draws warning sign with top left corner at `position`. Icon is 7 x 7
+    pub fn draw_warning<D: DrawTarget<Color = BinaryColor>>(
+        &self,
+        target: &mut D,
+        position: Point,
+    ) -> Result<(), D::Error> {
+        self.warning_body.translate(position).draw(target)?;
+        self.warning_mark.translate(position).draw(target)?;
+
+        Ok(())
+    }
+
     pub fn draw_icon<D: DrawTarget<Color = BinaryColor>>(
         &self,
         target: &mut D,
@@ -72,11 +137,8 @@ impl NavigationDrawables {
             Center => self.center.translate(position).draw(target),
         }?;
 
-        // Assume all icons 6 x 6 so draw text in position + 6 pixels
-        text.position = Point {
-            x: position.x + 6,
-            y: position.y + 3, // Center text
-        };
+        // Assume all icons 6 x 6 so draw text right of icon, centered on it
+        text.position = position + layout::HINT_TEXT_OFFSET;
         text.draw(target)?;
 
         Ok(())
@@ -0,0 +1,46 @@
+use crate::joystick::{ms_to_updates, Joystick, JoystickButton};
+use crate::speedchanger::SpeedChanger;
+
+/// Update intervals between repeats at start of hold
+const SPEED_STEPS: u32 = ms_to_updates(400);
+/// Repeats interval shrinks by one update each this amount of updates
+const ACCELERAION_TICKS: u32 = ms_to_updates(500);
+
+/// Hold before repeat of edited value. Shared by all states so held buttons feel the same everywhere:
+/// 500 ms is 10 updates at 50 ms input interval
+pub const EDIT_HOLD_TICKS: u32 = ms_to_updates(500);
+/// Hold before repeat of list scroll. List is short and each item must be visible, so never faster than edit
+pub const LIST_HOLD_TICKS: u32 = ms_to_updates(500);
+
+/// Repeater of value edit in any state
+pub type EditRepeater = HeldRepeater<EDIT_HOLD_TICKS>;
+/// Repeater of list scroll in any state
+pub type ListRepeater = HeldRepeater<LIST_HOLD_TICKS>;
+
+/// Repeats action while button is held, faster the longer it is held
+///
+/// `HOLD_TICKS` is amount of update intervals before first repeat, see [crate::joystick::ms_to_updates]
+#[derive(Default)]
+pub struct HeldRepeater<const HOLD_TICKS: u32> {
+    speed: SpeedChanger<SPEED_STEPS>,
+    acceleration: SpeedChanger<ACCELERAION_TICKS>,
+}
+
+impl<const HOLD_TICKS: u32> HeldRepeater<HOLD_TICKS> {
+    /// Should be called on each joystick update. Runs `action` with held button when repeat is due
+    pub fn update<J: Joystick, F: Fn(&JoystickButton)>(&self, j: &J, action: F) {
+        match j.position() {
+            Some(pos) if j.hold_time() > HOLD_TICKS => {
+                self.speed.execute(|| action(pos));
+
+                self.acceleration.execute(|| {
+                    self.speed.decrement_max_div();
+                });
+            }
+            _ => {
+                self.speed.reset();
+                self.acceleration.reset();
+            }
+        }
+    }
+}
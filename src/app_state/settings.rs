@@ -0,0 +1,391 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, text::Text};
+use heapless::String;
+
+use crate::format::format_temperature;
+use crate::joystick::Joystick;
+use crate::slot::Slot;
+
+use super::{
+    layout, navigation::NavigationIcons, strings::Str, write_utc_offset, AppSharedState,
+    AppStateTrait, MIN_UTC_OFFSET,
+};
+
+/// How many items fit between header and hints
+const VISIBLE_ROWS: usize = 3;
+/// Baseline of first item row
+const FIRST_ROW_Y: i32 = 26;
+const ROW_HEIGHT: i32 = 10;
+/// Change of percent values per click
+const PERCENT_STEP: u8 = 10;
+const MAX_PERCENT: u8 = 100;
+
+#[derive(Clone, Copy)]
+enum SettingsItem {
+    Brightness,
+    Volume,
+    HourFormat,
+    UtcOffset,
+    /// Second timezone on clock screen. Off below minimal offset
+    WorldClock,
+    KeyClick,
+    /// Countdown alarm grows louder over time
+    AlarmEscalation,
+    /// Display brightness in night hours. Off below 0%
+    NightBrightness,
+    NightStart,
+    NightEnd,
+    /// Correction of displayed temperature, half degree per click
+    TemperatureOffset,
+    /// Factory reset. Edit mode is confirmation, Up confirms
+    Reset,
+}
+
+const ITEMS: [SettingsItem; 12] = [
+    SettingsItem::Brightness,
+    SettingsItem::Volume,
+    SettingsItem::HourFormat,
+    SettingsItem::UtcOffset,
+    SettingsItem::WorldClock,
+    SettingsItem::KeyClick,
+    SettingsItem::AlarmEscalation,
+    SettingsItem::NightBrightness,
+    SettingsItem::NightStart,
+    SettingsItem::NightEnd,
+    SettingsItem::TemperatureOffset,
+    SettingsItem::Reset,
+];
+
+impl SettingsItem {
+    fn label(&self) -> &'static str {
+        let label = match self {
+            SettingsItem::Brightness => Str::Brightness,
+            SettingsItem::Volume => Str::Volume,
+            SettingsItem::HourFormat => Str::HourFormat,
+            SettingsItem::UtcOffset => Str::UtcOffset,
+            SettingsItem::WorldClock => Str::WorldClock,
+            SettingsItem::KeyClick => Str::KeyClick,
+            SettingsItem::AlarmEscalation => Str::AlarmEscalation,
+            SettingsItem::NightBrightness => Str::NightBrightness,
+            SettingsItem::NightStart => Str::NightStart,
+            SettingsItem::NightEnd => Str::NightEnd,
+            SettingsItem::TemperatureOffset => Str::TemperatureOffset,
+            SettingsItem::Reset => Str::Reset,
+        };
+
+        label.text()
+    }
+
+    fn write_value<W: Write>(&self, state: &AppSharedState, w: &mut W) -> core::fmt::Result {
+        match self {
+            SettingsItem::Brightness => write!(w, "{}%", state.brightness()),
+            SettingsItem::Volume => write!(w, "{}%", state.volume()),
+            SettingsItem::HourFormat => {
+                let hours = if state.hour_format_12() { 12 } else { 24 };
+                write!(w, "{}{}", hours, Str::Hours.text())
+            }
+            SettingsItem::UtcOffset => write_utc_offset(w, state.utc_offset()),
+            SettingsItem::WorldClock => match state.world_offset() {
+                Some(offset) => write_utc_offset(w, offset),
+                None => w.write_str(Str::Off.text()),
+            },
+            SettingsItem::KeyClick => w.write_str(Str::on_off(state.key_click()).text()),
+            SettingsItem::AlarmEscalation => {
+                w.write_str(Str::on_off(state.alarm_escalation()).text())
+            }
+            SettingsItem::NightBrightness => match state.night_brightness() {
+                Some(percent) => write!(w, "{}%", percent),
+                None => w.write_str(Str::Off.text()),
+            },
+            SettingsItem::NightStart => {
+                write!(w, "{:02}{}", state.night_start(), Str::Hours.text())
+            }
+            SettingsItem::NightEnd => write!(w, "{:02}{}", state.night_end(), Str::Hours.text()),
+            SettingsItem::TemperatureOffset => {
+                let offset = state.temperature_offset();
+                // Zero and negative values get sign from formatting
+                if offset > 0 {
+                    w.write_char('+')?;
+                }
+                let mut buf = [0_u8; 16];
+                let celsius = format_temperature(&mut buf, offset as f32 / 2.0)
+                    .map_err(|_| core::fmt::Error)?;
+                w.write_str(celsius)
+            }
+            SettingsItem::Reset => w.write_str(Str::All.text()),
+        }
+    }
+
+    /// Increase(or decrease) value of item
+    fn change(&self, state: &AppSharedState, increase: bool) {
+        let step = |v: u8| {
+            if increase {
+                v.saturating_add(PERCENT_STEP).min(MAX_PERCENT)
+            } else {
+                v.saturating_sub(PERCENT_STEP)
+            }
+        };
+        // Hours wrap around midnight
+        let step_hour = |h: u8| {
+            if increase {
+                (h + 1) % 24
+            } else {
+                (h + 23) % 24
+            }
+        };
+
+        match self {
+            SettingsItem::Brightness => {
+                state.set_brightness(step(state.brightness()));
+                // Change must be visible at once, also at night
+                state.suspend_night_dim();
+            }
+            SettingsItem::Volume => state.set_volume(step(state.volume())),
+            SettingsItem::HourFormat => state.set_hour_format_12(!state.hour_format_12()),
+            SettingsItem::KeyClick => state.set_key_click(!state.key_click()),
+            SettingsItem::AlarmEscalation => state.set_alarm_escalation(!state.alarm_escalation()),
+            SettingsItem::UtcOffset => {
+                let offset = state.utc_offset();
+                if increase {
+                    state.set_utc_offset(offset.saturating_add(1))
+                } else {
+                    state.set_utc_offset(offset.saturating_sub(1))
+                }
+            }
+            SettingsItem::WorldClock => {
+                let offset = match state.world_offset() {
+                    // Most likely zone is near own one
+                    None => Some(state.utc_offset()),
+                    Some(offset) if !increase && offset == MIN_UTC_OFFSET => None,
+                    Some(offset) if increase => Some(offset.saturating_add(1)),
+                    Some(offset) => Some(offset - 1),
+                };
+                state.set_world_offset(offset);
+            }
+            SettingsItem::NightBrightness => {
+                let percent = match state.night_brightness() {
+                    None if increase => Some(0),
+                    None => None,
+                    Some(0) if !increase => None,
+                    Some(percent) => Some(step(percent)),
+                };
+                state.set_night_brightness(percent);
+            }
+            SettingsItem::NightStart => state.set_night_start(step_hour(state.night_start())),
+            SettingsItem::NightEnd => state.set_night_end(step_hour(state.night_end())),
+            SettingsItem::TemperatureOffset => {
+                let offset = state.temperature_offset();
+                if increase {
+                    state.set_temperature_offset(offset.saturating_add(1))
+                } else {
+                    state.set_temperature_offset(offset.saturating_sub(1))
+                }
+            }
+            // Applied by holder, see [SettingsState::handle_input_edit_mode]
+            SettingsItem::Reset => {}
+        }
+    }
+}
+
+pub struct SettingsState {
+    state: Slot<AppSharedState>,
+
+    selected: AtomicUsize,
+    edit_mode: AtomicBool,
+}
+
+impl SettingsState {
+    pub fn new() -> Self {
+        Self {
+            state: Slot::empty(),
+
+            selected: AtomicUsize::new(0),
+            edit_mode: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns to first item
+    pub fn reset(&self) {
+        self.selected.store(0, Ordering::Relaxed);
+        self.edit_mode.store(false, Ordering::Release);
+    }
+
+    fn selected_item(&self) -> SettingsItem {
+        ITEMS[self.selected.load(Ordering::Relaxed)]
+    }
+
+    /// In list mode allow navigation and mode switch
+    fn handle_input_list_mode<J: Joystick>(&self, j: &J) -> bool {
+        if j.clicked() && j.position().is_some() {
+            let pos = j.position().as_ref().unwrap();
+
+            use crate::joystick::JoystickButton::*;
+
+            let selected = self.selected.load(Ordering::Relaxed);
+
+            match pos {
+                Left => {
+                    super::request_switch(false);
+                }
+                Right => {
+                    super::request_switch(true);
+                }
+                Up => self.selected.store(
+                    (selected + ITEMS.len() - 1) % ITEMS.len(),
+                    Ordering::Relaxed,
+                ),
+                Down => self
+                    .selected
+                    .store((selected + 1) % ITEMS.len(), Ordering::Relaxed),
+                Center => self.edit_mode.store(true, Ordering::Release),
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    /// In edit mode Up and Down change value of selected item
+    fn handle_input_edit_mode<J: Joystick>(&self, j: &J) -> bool {
+        if j.clicked() && j.position().is_some() {
+            let pos = j.position().as_ref().unwrap();
+
+            use crate::joystick::JoystickButton::*;
+
+            let state = self.state();
+
+            if let SettingsItem::Reset = self.selected_item() {
+                // Only Up confirms so accidental click can't wipe settings
+                if let Up = pos {
+                    crate::app::factory_reset::spawn().ok();
+                }
+                self.edit_mode.store(false, Ordering::Release);
+                return true;
+            }
+
+            match pos {
+                Up => self.selected_item().change(state, true),
+                Down => self.selected_item().change(state, false),
+                Center => self.edit_mode.store(false, Ordering::Release),
+
+                _ => return false,
+            }
+
+            return true;
+        }
+
+        false
+    }
+}
+
+impl Default for SettingsState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppStateTrait for SettingsState {
+    fn enter(&mut self, state: AppSharedState) {
+        self.state.put(state);
+    }
+
+    fn exit(&mut self) -> AppSharedState {
+        self.edit_mode.store(false, Ordering::Release);
+        self.state.take()
+    }
+
+    fn state(&self) -> &AppSharedState {
+        self.state.get().unwrap()
+    }
+
+    /// Settings change only by input
+    fn version(&self) -> u32 {
+        0
+    }
+
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
+        if self.edit_mode.load(Ordering::Acquire) {
+            self.handle_input_edit_mode(j)
+        } else {
+            self.handle_input_list_mode(j)
+        }
+    }
+
+    fn switch_allowed(&self) -> bool {
+        !self.edit_mode.load(Ordering::Relaxed)
+    }
+}
+
+impl Drawable for SettingsState {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.draw_header(target, Str::Settings.text())?;
+
+        let is_edit = self.edit_mode.load(Ordering::Relaxed);
+        if !is_edit {
+            self.draw_navigation(target)?;
+        }
+
+        let state = self.state();
+        let selected = self.selected.load(Ordering::Relaxed);
+        // Scroll so selected item is always visible
+        let first_visible = selected.saturating_sub(VISIBLE_ROWS - 1);
+
+        for (row, (index, item)) in ITEMS
+            .iter()
+            .enumerate()
+            .skip(first_visible)
+            .take(VISIBLE_ROWS)
+            .enumerate()
+        {
+            let y = FIRST_ROW_Y + ROW_HEIGHT * row as i32;
+
+            let mut buf: String<32> = Default::default();
+            if is_edit && index == selected && matches!(item, SettingsItem::Reset) {
+                write!(&mut buf, "{}", Str::ResetConfirm.text()).unwrap();
+            } else {
+                write!(&mut buf, "{}: ", item.label()).unwrap();
+                item.write_value(state, &mut buf).unwrap();
+            }
+
+            Text::new(&buf, Point::new(18, y), state.small_text_style).draw(target)?;
+
+            if index == selected {
+                let marker = if is_edit {
+                    NavigationIcons::Up
+                } else {
+                    NavigationIcons::Right
+                };
+
+                state
+                    .navigation_icons
+                    .draw_icon(target, marker, Point::new(12, y - 3))?;
+            }
+        }
+
+        let center_button_hint = if is_edit && matches!(self.selected_item(), SettingsItem::Reset) {
+            Str::Cancel
+        } else if is_edit {
+            Str::Apply
+        } else {
+            Str::Edit
+        };
+
+        self.draw_hint(
+            target,
+            NavigationIcons::Center,
+            layout::HINT_LOWER,
+            center_button_hint.text(),
+        )?;
+
+        Ok(())
+    }
+}
@@ -1,48 +1,75 @@
-use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use chrono::Duration;
-use embedded_graphics::{
-    pixelcolor::BinaryColor,
-    prelude::*,
-    text::{Alignment, Text},
-};
-use heapless::String;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
 
-use crate::app::StopwatchTimer;
+use crate::format::{format_duration, Precision};
 use crate::joystick::Joystick;
+use crate::slot::Slot;
+use crate::stopwatchtimer::{Stopwatch, StopwatchEvent};
 
-use super::{navigation::NavigationIcons, AppSharedState, AppStateTrait};
+use super::{layout, navigation::NavigationIcons, strings::Str, AppSharedState, AppStateTrait};
 
-pub struct StopwatchState {
-    state: Option<AppSharedState>,
+/// Half period of elapsed time blinking while paused
+const PAUSE_BLINK_MS: i64 = 500;
 
-    stopwatch: &'static StopwatchTimer,
+pub struct StopwatchState<S: Stopwatch + 'static> {
+    state: Slot<AppSharedState>,
+
+    stopwatch: &'static S,
+    /// Stopwatch paused itself at its maximum. Start is refused until reset
+    limit_reached: AtomicBool,
 }
 
-impl StopwatchState {
-    pub fn new(timer_ref: &'static StopwatchTimer) -> Self {
+impl<S: Stopwatch> StopwatchState<S> {
+    pub fn new(timer_ref: &'static S) -> Self {
         Self {
-            state: None,
+            state: Slot::empty(),
             stopwatch: timer_ref,
+            limit_reached: AtomicBool::new(false),
+        }
+    }
+
+    /// Stopped with elapsed time kept, unlike reset one showing zero
+    fn paused(&self) -> bool {
+        !self.stopwatch.started() && self.stopwatch.elapsed() > 0
+    }
+
+    /// Elapsed time is hidden in this half of blink period while paused
+    fn blink_hidden(&self) -> bool {
+        self.paused() && (crate::uptime::uptime().num_milliseconds() / PAUSE_BLINK_MS) % 2 == 1
+    }
+
+    /// Handles stopwatch transition made since last call, including ones made by interrupt
+    fn handle_event(&self) {
+        match self.stopwatch.take_event() {
+            Some(StopwatchEvent::LimitReached) => self.limit_reached.store(true, Ordering::Relaxed),
+            Some(StopwatchEvent::Stopped) => self.limit_reached.store(false, Ordering::Relaxed),
+            _ => {}
         }
     }
 }
 
-impl AppStateTrait for StopwatchState {
+impl<S: Stopwatch> AppStateTrait for StopwatchState<S> {
     fn enter(&mut self, state: AppSharedState) {
-        assert!(self.state.is_none());
-        self.state = Some(state);
+        self.state.put(state);
     }
 
     fn exit(&mut self) -> AppSharedState {
-        self.state.take().expect("exit called without enter")
+        self.state.take()
     }
 
     fn state(&self) -> &AppSharedState {
-        self.state.as_ref().unwrap()
+        self.state.get().unwrap()
+    }
+
+    /// Centiseconds are displayed, blink phase while paused
+    fn version(&self) -> u32 {
+        (self.stopwatch.elapsed() / 10) ^ ((self.blink_hidden() as u32) << 31)
     }
 
-    fn handle_input<J: Joystick>(&self, j: &J) {
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
+        self.handle_event();
+
         if j.clicked() && j.position().is_some() {
             let pos = j.position().as_ref().unwrap();
 
@@ -52,15 +79,15 @@ impl AppStateTrait for StopwatchState {
                 Left => {
                     // Request from app mode switch
                     // It will run after exit from this function due low priority
-                    crate::app::change_state::spawn(false).ok();
+                    super::request_switch(false);
                 }
                 Right => {
-                    crate::app::change_state::spawn(true).ok();
+                    super::request_switch(true);
                 }
                 Center => {
                     if self.stopwatch.started() {
                         self.stopwatch.pause();
-                    } else {
+                    } else if !self.limit_reached.load(Ordering::Relaxed) {
                         self.stopwatch.start();
                     }
                 }
@@ -68,13 +95,21 @@ impl AppStateTrait for StopwatchState {
                     self.stopwatch.stop();
                 }
 
-                _ => {}
+                _ => return false,
             }
+
+            if let Center | Down = pos {
+                super::request_stopwatch_backup();
+            }
+
+            return true;
         }
+
+        false
     }
 }
 
-impl Drawable for StopwatchState {
+impl<S: Stopwatch> Drawable for StopwatchState<S> {
     type Color = BinaryColor;
     type Output = ();
 
@@ -82,63 +117,44 @@ impl Drawable for StopwatchState {
     where
         D: DrawTarget<Color = Self::Color>,
     {
-        self.draw_header(target, "СЕКУНДОМЕР")?;
+        self.draw_header(target, Str::Stopwatch.text())?;
         self.draw_navigation(target)?;
 
         // Draw UI help
         let center_button_hint = if self.stopwatch.started() {
-            "Пауза"
+            Str::Pause
+        } else if self.limit_reached.load(Ordering::Relaxed) {
+            Str::Limit
+        } else if self.paused() {
+            Str::Resume
         } else {
-            "Старт"
+            Str::Start
         };
 
-        let state = self.state();
-        state.navigation_icons.draw_icon_and_text(
+        self.draw_hint(
             target,
             NavigationIcons::Center,
-            Point::new(20, 46),
-            Text::new(
-                center_button_hint,
-                Default::default(),
-                state.small_text_style,
-            ),
+            layout::HINT_UPPER,
+            center_button_hint.text(),
         )?;
 
-        state.navigation_icons.draw_icon_and_text(
+        self.draw_hint(
             target,
             NavigationIcons::Down,
-            Point::new(20, 56),
-            Text::new("Стоп и сброс", Default::default(), state.small_text_style),
+            layout::HINT_LOWER,
+            Str::StopAndReset.text(),
         )?;
 
-        // Draw elapsed time
-        let mut buf: String<32> = Default::default();
-        let elapsed = Duration::milliseconds(self.stopwatch.elapsed() as i64);
-        let hours = elapsed.num_hours();
-        let minutes = elapsed.num_minutes() - 60 * hours;
-        let seconds = elapsed.num_seconds() - 60 * minutes - 60 * 60 * hours;
-
-        // ms / 100 - to display only we supported
-        let hecto_ms = (elapsed.num_milliseconds()
-            - 1000 * seconds
-            - 60 * 1000 * minutes
-            - 60 * 60 * 1000 * hours)
-            / 100;
-
-        write!(
-            &mut buf,
-            "{:}:{:02}:{:02}.{:01}",
-            hours, minutes, seconds, hecto_ms
-        )
-        .unwrap();
-
-        Text::with_alignment(
-            &buf,
-            Point { x: 64, y: 32 },
-            self.state().content_style,
-            Alignment::Center,
-        )
-        .draw(target)?;
+        // Draw elapsed time, blinking while paused so it differs from reset zero
+        if self.blink_hidden() {
+            return Ok(());
+        }
+
+        let mut buf = [0_u8; 32];
+        let elapsed =
+            format_duration(&mut buf, self.stopwatch.elapsed(), Precision::Hundredths).unwrap();
+
+        self.draw_content(target, elapsed)?;
 
         Ok(())
     }
@@ -0,0 +1,227 @@
+//! UI strings. Language is chosen at compile time: Russian by default, English with `english` feature
+//!
+//! Both sets must fit the same layout: headers up to 14 characters of header font, hints up to 17 of small one
+
+use chrono::Weekday;
+
+/// Key of UI string. See [Str::text]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Str {
+    // Headers
+    Clock,
+    Alarm,
+    Stopwatch,
+    Timer,
+    Settings,
+    Diagnostics,
+
+    // Button hints
+    Edit,
+    Apply,
+    Cancel,
+    Set,
+    HoldToStart,
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    StopAndReset,
+    /// Stopwatch can't count further
+    Limit,
+    AddMinute,
+    Enable,
+    Disable,
+    /// Silence ringing alarm
+    Dismiss,
+    Preset,
+    Repeat,
+
+    // Values
+    On,
+    Off,
+    /// Unit suffix of days, after number
+    Days,
+    /// Unit suffix of hours, after number
+    Hours,
+    /// Short weekday shown with date
+    Weekday(Weekday),
+    /// Shown in place of date while RTC fails
+    RtcError,
+
+    // Settings items
+    Brightness,
+    Volume,
+    HourFormat,
+    UtcOffset,
+    WorldClock,
+    KeyClick,
+    AlarmEscalation,
+    NightBrightness,
+    NightStart,
+    NightEnd,
+    TemperatureOffset,
+    Reset,
+    /// Value of reset item
+    All,
+    ResetConfirm,
+
+    // Diagnostics rows
+    Version,
+    Uptime,
+    RtcTemperature,
+    I2cBus,
+    /// Value is not available
+    NoValue,
+    /// Device doesn't answer its address
+    NotFound,
+    Error,
+}
+
+impl Str {
+    /// Text of string in compiled language
+    #[cfg(not(feature = "english"))]
+    pub fn text(self) -> &'static str {
+        match self {
+            Str::Clock => "ЧАСЫ",
+            Str::Alarm => "БУДИЛЬНИК",
+            Str::Stopwatch => "СЕКУНДОМЕР",
+            Str::Timer => "ТАЙМЕР",
+            Str::Settings => "НАСТРОЙКИ",
+            Str::Diagnostics => "ИНФО",
+
+            Str::Edit => "Изменить",
+            Str::Apply => "Применить",
+            Str::Cancel => "Отмена",
+            Str::Set => "Задать",
+            Str::HoldToStart => "Держать: запуск",
+            Str::Start => "Старт",
+            Str::Stop => "Стоп",
+            Str::Pause => "Пауза",
+            Str::Resume => "Продолжить",
+            Str::StopAndReset => "Стоп и сброс",
+            Str::Limit => "Предел",
+            Str::AddMinute => "+1 мин",
+            Str::Enable => "Включить",
+            Str::Disable => "Выключить",
+            Str::Dismiss => "Отключить",
+            Str::Preset => "Пресет",
+            Str::Repeat => "Повтор",
+
+            Str::On => "Вкл",
+            Str::Off => "Выкл",
+            Str::Days => "д",
+            Str::Hours => "ч",
+            Str::Weekday(day) => match day {
+                Weekday::Mon => "ПН",
+                Weekday::Tue => "ВТ",
+                Weekday::Wed => "СР",
+                Weekday::Thu => "ЧТ",
+                Weekday::Fri => "ПТ",
+                Weekday::Sat => "СБ",
+                Weekday::Sun => "ВС",
+            },
+            Str::RtcError => "ОШИБКА RTC",
+
+            Str::Brightness => "Яркость",
+            Str::Volume => "Громкость",
+            Str::HourFormat => "Формат",
+            Str::UtcOffset => "Пояс",
+            Str::WorldClock => "Пояс 2",
+            Str::KeyClick => "Звук кнопок",
+            Str::AlarmEscalation => "Нарастание",
+            Str::NightBrightness => "Ночью",
+            Str::NightStart => "Ночь с",
+            Str::NightEnd => "Ночь до",
+            Str::TemperatureOffset => "Поправка t",
+            Str::Reset => "Сброс",
+            Str::All => "все",
+            Str::ResetConfirm => "Сбросить все?",
+
+            Str::Version => "Версия",
+            Str::Uptime => "Работа",
+            Str::RtcTemperature => "Темп. RTC",
+            Str::I2cBus => "Шина I2C",
+            Str::NoValue => "нет",
+            Str::NotFound => "не найден",
+            Str::Error => "ошибка",
+        }
+    }
+
+    /// Text of string in compiled language
+    #[cfg(feature = "english")]
+    pub fn text(self) -> &'static str {
+        match self {
+            Str::Clock => "CLOCK",
+            Str::Alarm => "ALARM",
+            Str::Stopwatch => "STOPWATCH",
+            Str::Timer => "TIMER",
+            Str::Settings => "SETTINGS",
+            Str::Diagnostics => "INFO",
+
+            Str::Edit => "Edit",
+            Str::Apply => "Apply",
+            Str::Cancel => "Cancel",
+            Str::Set => "Set",
+            Str::HoldToStart => "Hold: start",
+            Str::Start => "Start",
+            Str::Stop => "Stop",
+            Str::Pause => "Pause",
+            Str::Resume => "Resume",
+            Str::StopAndReset => "Stop and reset",
+            Str::Limit => "Limit",
+            Str::AddMinute => "+1 min",
+            Str::Enable => "Enable",
+            Str::Disable => "Disable",
+            Str::Dismiss => "Dismiss",
+            Str::Preset => "Preset",
+            Str::Repeat => "Repeat",
+
+            Str::On => "On",
+            Str::Off => "Off",
+            Str::Days => "d",
+            Str::Hours => "h",
+            Str::Weekday(day) => match day {
+                Weekday::Mon => "MO",
+                Weekday::Tue => "TU",
+                Weekday::Wed => "WE",
+                Weekday::Thu => "TH",
+                Weekday::Fri => "FR",
+                Weekday::Sat => "SA",
+                Weekday::Sun => "SU",
+            },
+            Str::RtcError => "RTC ERROR",
+
+            Str::Brightness => "Brightness",
+            Str::Volume => "Volume",
+            Str::HourFormat => "Format",
+            Str::UtcOffset => "Zone",
+            Str::WorldClock => "Zone 2",
+            Str::KeyClick => "Key click",
+            Str::AlarmEscalation => "Ramp up",
+            Str::NightBrightness => "At night",
+            Str::NightStart => "Night from",
+            Str::NightEnd => "Night to",
+            Str::TemperatureOffset => "Temp. adj.",
+            Str::Reset => "Reset",
+            Str::All => "all",
+            Str::ResetConfirm => "Reset all?",
+
+            Str::Version => "Version",
+            Str::Uptime => "Uptime",
+            Str::RtcTemperature => "RTC temp.",
+            Str::I2cBus => "I2C bus",
+            Str::NoValue => "none",
+            Str::NotFound => "not found",
+            Str::Error => "error",
+        }
+    }
+
+    /// [Str::On] or [Str::Off]
+    pub fn on_off(on: bool) -> Self {
+        if on {
+            Str::On
+        } else {
+            Str::Off
+        }
+    }
+}
@@ -1,22 +1,40 @@
 use core::fmt::Write;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
 use atomic_enum::atomic_enum;
 use chrono::Duration;
-use embedded_graphics::text::{Alignment, Text};
-use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
-use heapless::String;
-
-use crate::app::CountdownTimer;
-use crate::joystick::Joystick;
-use crate::speedchanger::SpeedChanger;
+use embedded_graphics::text::Text;
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+};
+use heapless::{String, Vec};
+
+use crate::countdowntimer::{Countdown, MAX_COUNTDOWN};
+use crate::format::{format_duration, Precision};
+use crate::joystick::{ms_to_updates, Joystick, JoystickButton};
+use crate::slot::Slot;
 
 use super::navigation::NavigationIcons;
-use super::{AppSharedState, AppStateTrait};
-
-const SPEED_STEPS: u32 = 8;
-const ACCELERAION_TICKS: u32 = 10;
-const MAX_TIMER_COUNTDOWN: u32 = 60 * 60 * 99 + 60 * 59 + 59; // 99 hours, 59 mins, 59 secs
+use super::repeater::{EditRepeater, ListRepeater};
+use super::{layout, strings::Str, AppSharedState, AppStateTrait};
+
+/// Update intervals Center must be held to start countdown from edit
+const START_HOLD_TICK: u32 = ms_to_updates(1000);
+/// How long edited field is framed after value was clamped at limit
+const CLAMP_CUE_MS: u32 = 300;
+/// Height of remaining time bar at bottom of screen
+const PROGRESS_HEIGHT: u32 = 2;
+/// Added to running countdown by Up
+const QUICK_ADD_SECONDS: u32 = 60;
+/// Characters of shown countdown "HH:MM:SS". Edited fields are placed by it
+const TIME_CHARS: u32 = 8;
+
+/// Maximum count of stored presets
+const MAX_PRESETS: usize = 8;
+/// Presets loaded on creation, in seconds
+const DEFAULT_PRESETS: [u32; 4] = [60, 3 * 60, 5 * 60, 10 * 60];
 
 #[atomic_enum]
 #[derive(PartialEq)]
@@ -30,10 +48,13 @@ enum TimerInternalState {
 }
 
 #[atomic_enum]
+#[derive(PartialEq)]
 enum EditField {
     Hours,
     Minutes,
     Seconds,
+    /// Repeat mode toggle
+    Repeat,
 }
 
 impl AtomicEditField {
@@ -41,7 +62,8 @@ impl AtomicEditField {
         let new_field = match self.load(Ordering::Acquire) {
             EditField::Hours => EditField::Minutes,
             EditField::Minutes => EditField::Seconds,
-            EditField::Seconds => EditField::Hours,
+            EditField::Seconds => EditField::Repeat,
+            EditField::Repeat => EditField::Hours,
         };
 
         self.store(new_field, Ordering::Release);
@@ -49,7 +71,8 @@ impl AtomicEditField {
 
     fn prev(&self) {
         let new_field = match self.load(Ordering::Acquire) {
-            EditField::Hours => EditField::Seconds,
+            EditField::Hours => EditField::Repeat,
+            EditField::Repeat => EditField::Seconds,
             EditField::Minutes => EditField::Hours,
             EditField::Seconds => EditField::Minutes,
         };
@@ -62,71 +85,173 @@ impl AtomicEditField {
             EditField::Hours => 60 * 60,
             EditField::Minutes => 60,
             EditField::Seconds => 1,
+            EditField::Repeat => 0,
         }
     }
 
-    fn countdown_add(&self, c: &AtomicU32) {
+    /// Adds edited field amount up to `max`. Returns true if value was clamped
+    fn countdown_add(&self, c: &AtomicU32, max: u32) -> bool {
         let to_add = self.edit_amount();
 
         let mut counter = c.load(Ordering::Acquire);
-        let diff = MAX_TIMER_COUNTDOWN - counter;
-        if diff > to_add {
-            counter += to_add;
+        let diff = max.saturating_sub(counter);
+        let clamped = diff < to_add;
+        if clamped {
+            counter = max;
         } else {
-            counter = MAX_TIMER_COUNTDOWN;
+            counter += to_add;
         }
         c.store(counter, Ordering::Release);
+
+        clamped
     }
 
-    fn countdown_sub(&self, c: &AtomicU32) {
+    /// Subtracts edited field amount down to zero. Returns true if value was clamped
+    fn countdown_sub(&self, c: &AtomicU32) -> bool {
         let to_sub = self.edit_amount();
 
         let mut counter = c.load(Ordering::Acquire);
-        if counter > to_sub {
-            counter -= to_sub;
-        } else {
+        let clamped = counter < to_sub;
+        if clamped {
             counter = 0;
+        } else {
+            counter -= to_sub;
         }
         c.store(counter, Ordering::Release);
+
+        clamped
     }
 }
 
-pub struct TimerState {
-    state: Option<AppSharedState>,
-    timer: &'static CountdownTimer,
+#[inline]
+fn uptime_ms() -> u32 {
+    crate::uptime::uptime().num_milliseconds() as u32
+}
+
+pub struct TimerState<C: Countdown + 'static> {
+    state: Slot<AppSharedState>,
+    timer: &'static C,
     internal_state: AtomicTimerInternalState,
 
     countdown_selected: AtomicU32,
+    presets: Vec<u32, MAX_PRESETS>,
+    preset_selected: AtomicUsize,
     edit_field: AtomicEditField,
-    edit_speed: SpeedChanger<SPEED_STEPS>,
-    edit_acceleration: SpeedChanger<ACCELERAION_TICKS>,
+    /// Center pressed in edit mode, its hold starts countdown. Hold continued from entering edit is ignored
+    start_armed: AtomicBool,
+    /// Longest countdown which can be set in edit, seconds
+    max_countdown: u32,
+    /// Uptime in ms until which clamp cue is shown, see [CLAMP_CUE_MS]
+    clamp_cue_until_ms: AtomicU32,
+    /// Clamp cue shown during current hold, repeats at limit don't show it again
+    clamp_cued: AtomicBool,
+    edit_repeater: EditRepeater,
+    preset_repeater: ListRepeater,
 }
 
-impl TimerState {
-    pub fn new(timer: &'static CountdownTimer) -> Self {
-        let mut start_int_state = TimerInternalState::TimerEnd;
-        if timer.started() {
-            start_int_state = TimerInternalState::TimerStarted;
-        }
+impl<C: Countdown> TimerState<C> {
+    /// UI state is derived from `timer` on [AppStateTrait::enter]
+    ///
+    /// `max_countdown` is limit of edited countdown in seconds, up to [MAX_COUNTDOWN]
+    pub fn new(timer: &'static C, max_countdown: u32) -> Self {
+        assert!(
+            max_countdown <= MAX_COUNTDOWN,
+            "Countdown maximum doesn't fit display"
+        );
 
         Self {
-            state: None,
+            state: Slot::empty(),
 
             timer,
 
-            internal_state: AtomicTimerInternalState::new(start_int_state),
+            internal_state: AtomicTimerInternalState::new(TimerInternalState::TimerEnd),
             countdown_selected: AtomicU32::new(0),
+            presets: Vec::from_slice(&DEFAULT_PRESETS).unwrap(),
+            preset_selected: AtomicUsize::new(0),
             edit_field: AtomicEditField::new(EditField::Seconds),
-            edit_speed: Default::default(),
-            edit_acceleration: Default::default(),
+            start_armed: AtomicBool::new(false),
+            max_countdown,
+            clamp_cue_until_ms: AtomicU32::new(0),
+            clamp_cued: AtomicBool::new(false),
+            edit_repeater: Default::default(),
+            preset_repeater: Default::default(),
         }
     }
 
-    pub fn handle_input_end<J: Joystick>(&self, j: &J) {
-        if j.position().is_none() {
+    /// Countdown reached zero and buzzer sounds until it is stopped
+    pub fn alarm_sounding(&self) -> bool {
+        self.timer.started() && self.timer.countdown() == 0
+    }
+
+    /// Countdown runs towards zero, so its end is near
+    pub fn counting(&self) -> bool {
+        self.timer.started() && !self.timer.paused() && self.timer.countdown() > 0
+    }
+
+    /// Restores default presets
+    pub fn reset_presets(&mut self) {
+        self.presets = Vec::from_slice(&DEFAULT_PRESETS).unwrap();
+        self.preset_selected.store(0, Ordering::Relaxed);
+    }
+
+    /// Selects next(or previous) preset and loads it into edit countdown
+    fn cycle_preset(&self, forward: bool) {
+        let count = self.presets.len();
+        if count == 0 {
             return;
         }
 
+        let cur = self.preset_selected.load(Ordering::Relaxed);
+        let new = if forward {
+            (cur + 1) % count
+        } else {
+            (cur + count - 1) % count
+        };
+
+        self.preset_selected.store(new, Ordering::Relaxed);
+        self.countdown_selected
+            .store(self.presets[new], Ordering::Relaxed);
+    }
+
+    /// Changes edited field up or down. Clamp at limit is shown once per hold, see [CLAMP_CUE_MS]
+    fn edit_countdown(&self, increase: bool) {
+        let clamped = if increase {
+            self.edit_field
+                .countdown_add(&self.countdown_selected, self.max_countdown)
+        } else {
+            self.edit_field.countdown_sub(&self.countdown_selected)
+        };
+
+        if !clamped {
+            self.clamp_cued.store(false, Ordering::Relaxed);
+        } else if !self.clamp_cued.swap(true, Ordering::Relaxed) {
+            self.clamp_cue_until_ms
+                .store(uptime_ms() + CLAMP_CUE_MS, Ordering::Relaxed);
+        }
+    }
+
+    fn clamp_cue_active(&self) -> bool {
+        uptime_ms() < self.clamp_cue_until_ms.load(Ordering::Relaxed)
+    }
+
+    /// Timer keeps running while other state is shown and may be started or stopped there,
+    /// so UI state left on exit may be stale. Edit is kept: it is unrelated to running countdown
+    fn sync_internal_state(&self) {
+        let current = self.internal_state.load(Ordering::Relaxed);
+        let synced = match (self.timer.started(), current) {
+            (true, _) => TimerInternalState::TimerStarted,
+            (false, TimerInternalState::TimerStarted) => TimerInternalState::TimerEnd,
+            (false, other) => other,
+        };
+
+        self.internal_state.store(synced, Ordering::Relaxed);
+    }
+
+    pub fn handle_input_end<J: Joystick>(&self, j: &J) -> bool {
+        if j.position().is_none() {
+            return false;
+        }
+
         if j.clicked() {
             let pos = j.position().as_ref().unwrap();
 
@@ -134,25 +259,39 @@ impl TimerState {
 
             match pos {
                 Left => {
-                    crate::app::change_state::spawn(false).ok();
+                    super::request_switch(false);
                 }
                 Right => {
-                    crate::app::change_state::spawn(true).ok();
+                    super::request_switch(true);
+                }
+                Up => self.cycle_preset(true),
+                Down => self.cycle_preset(false),
+                Center => {
+                    self.start_armed.store(false, Ordering::Relaxed);
+                    self.internal_state
+                        .store(TimerInternalState::Edit, Ordering::Relaxed);
                 }
-                Center => self
-                    .internal_state
-                    .store(TimerInternalState::Edit, Ordering::Relaxed),
+            }
+        }
 
+        self.preset_repeater.update(j, |pos| {
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                // Up held
+                Up => self.cycle_preset(true),
+                // Down held
+                Down => self.cycle_preset(false),
                 _ => {}
             }
-        }
-    }
+        });
 
-    pub fn handle_input_edit<J: Joystick>(&self, j: &J) {
-        const HOLD_DURATION_TICK: u32 = 2;
+        j.clicked()
+    }
 
+    pub fn handle_input_edit<J: Joystick>(&self, j: &J) -> bool {
         if j.position().is_none() {
-            return;
+            return false;
         }
 
         if j.clicked() {
@@ -160,51 +299,60 @@ impl TimerState {
 
             use crate::joystick::JoystickButton::*;
 
+            let is_repeat_field = self.edit_field.load(Ordering::Relaxed) == EditField::Repeat;
+
             match pos {
-                // Up pressed
-                Up => self.edit_field.countdown_add(&self.countdown_selected),
-                // Down pressed
-                Down => self.edit_field.countdown_sub(&self.countdown_selected),
+                // Up or Down pressed on repeat field
+                Up | Down if is_repeat_field => self.timer.set_repeat(!self.timer.repeat()),
+                // Up or Down pressed. New press shows clamp again
+                Up | Down => {
+                    self.clamp_cued.store(false, Ordering::Relaxed);
+                    self.edit_countdown(*pos == Up);
+                }
                 // Left pressed
                 Left => self.edit_field.prev(),
                 // Right pressed
                 Right => self.edit_field.next(),
+                // Started by hold, see below
                 Center => {
-                    self.timer
-                        .start(self.countdown_selected.load(Ordering::Relaxed));
-
-                    self.internal_state
-                        .store(TimerInternalState::TimerStarted, Ordering::Relaxed);
+                    self.start_armed.store(true, Ordering::Relaxed);
+                    return false;
                 }
             }
         }
 
-        if j.hold_time() > HOLD_DURATION_TICK {
-            let pos = j.position().as_ref().unwrap();
+        // Stray click while editing must not launch countdown, so start needs deliberate hold
+        if j.position() == &Some(JoystickButton::Center)
+            && j.hold_time() == START_HOLD_TICK
+            && self.start_armed.swap(false, Ordering::Relaxed)
+        {
+            self.timer
+                .start(self.countdown_selected.load(Ordering::Relaxed));
 
-            use crate::joystick::JoystickButton::*;
-            self.edit_speed.execute(|| {
-                match pos {
-                    // Up pressed
-                    Up => self.edit_field.countdown_add(&self.countdown_selected),
-                    // Down pressed
-                    Down => self.edit_field.countdown_sub(&self.countdown_selected),
-                    _ => {}
-                }
-            });
+            self.internal_state
+                .store(TimerInternalState::TimerStarted, Ordering::Relaxed);
 
-            self.edit_acceleration.execute(|| {
-                self.edit_speed.decrement_max_div();
-            });
-        } else {
-            self.edit_speed.reset();
-            self.edit_acceleration.reset();
+            return true;
         }
+
+        self.edit_repeater.update(j, |pos| {
+            use crate::joystick::JoystickButton::*;
+
+            match pos {
+                // Up held
+                Up => self.edit_countdown(true),
+                // Down held
+                Down => self.edit_countdown(false),
+                _ => {}
+            }
+        });
+
+        j.clicked()
     }
 
-    pub fn handle_input_started<J: Joystick>(&self, j: &J) {
+    pub fn handle_input_started<J: Joystick>(&self, j: &J) -> bool {
         if j.position().is_none() {
-            return;
+            return false;
         }
 
         if j.clicked() {
@@ -214,47 +362,65 @@ impl TimerState {
 
             match pos {
                 Left => {
-                    crate::app::change_state::spawn(false).ok();
+                    super::request_switch(false);
                 }
                 Right => {
-                    crate::app::change_state::spawn(true).ok();
+                    super::request_switch(true);
                 }
                 Center => {
                     self.timer.stop();
                     self.internal_state
                         .store(TimerInternalState::TimerEnd, Ordering::Relaxed);
                 }
-
-                _ => {}
+                Up => self.timer.add_time(QUICK_ADD_SECONDS),
+                Down => {
+                    if self.timer.paused() {
+                        self.timer.resume();
+                    } else {
+                        self.timer.pause();
+                    }
+                }
             }
         }
+
+        j.clicked()
     }
 }
 
-impl AppStateTrait for TimerState {
+impl<C: Countdown> AppStateTrait for TimerState<C> {
     fn enter(&mut self, state: AppSharedState) {
-        assert!(self.state.is_none());
-        self.state = Some(state);
+        self.state.put(state);
+
+        self.sync_internal_state();
     }
 
     fn exit(&mut self) -> AppSharedState {
-        self.state.take().expect("exit called without enter")
+        self.state.take()
     }
 
     fn state(&self) -> &AppSharedState {
-        self.state.as_ref().unwrap()
+        self.state.get().unwrap()
+    }
+
+    /// Running countdown changes each second, clamp cue hides by time
+    fn version(&self) -> u32 {
+        self.timer.countdown() ^ (self.clamp_cue_active() as u32) << 31
     }
 
-    fn handle_input<J: Joystick>(&self, j: &J) {
+    fn handle_input<J: Joystick>(&self, j: &J) -> bool {
         match self.internal_state.load(Ordering::Relaxed) {
             TimerInternalState::TimerEnd => self.handle_input_end(j),
             TimerInternalState::Edit => self.handle_input_edit(j),
             TimerInternalState::TimerStarted => self.handle_input_started(j),
         }
     }
+
+    fn switch_allowed(&self) -> bool {
+        self.internal_state.load(Ordering::Relaxed) != TimerInternalState::Edit
+    }
 }
 
-impl Drawable for TimerState {
+impl<C: Countdown> Drawable for TimerState<C> {
     type Color = BinaryColor;
     type Output = ();
 
@@ -262,85 +428,162 @@ impl Drawable for TimerState {
     where
         D: DrawTarget<Color = Self::Color>,
     {
-        self.draw_header(target, "ТАЙМЕР")?;
+        self.draw_header(target, Str::Timer.text())?;
 
         let int_state = self.internal_state.load(Ordering::Relaxed);
 
         // Draw UI hints
         let center_button_hint = match int_state {
-            TimerInternalState::TimerEnd => "Задать",
-            TimerInternalState::Edit => "Запуск",
-            TimerInternalState::TimerStarted => "Стоп",
+            TimerInternalState::TimerEnd => Str::Set,
+            TimerInternalState::Edit => Str::HoldToStart,
+            TimerInternalState::TimerStarted => Str::Stop,
         };
 
         let state = self.state();
-        state.navigation_icons.draw_icon_and_text(
+        self.draw_hint(
             target,
             NavigationIcons::Center,
-            Point::new(20, 56),
-            Text::new(
-                center_button_hint,
-                Default::default(),
-                state.small_text_style,
-            ),
+            layout::HINT_LOWER,
+            center_button_hint.text(),
         )?;
 
+        if int_state == TimerInternalState::TimerStarted {
+            let pause_button_hint = if self.timer.paused() {
+                Str::Resume
+            } else {
+                Str::Pause
+            };
+
+            self.draw_hint(
+                target,
+                NavigationIcons::Down,
+                layout::HINT_UPPER,
+                pause_button_hint.text(),
+            )?;
+
+            self.draw_hint(
+                target,
+                NavigationIcons::Up,
+                layout::HINT_LOWER_RIGHT,
+                Str::AddMinute.text(),
+            )?;
+        }
+
+        if int_state == TimerInternalState::TimerEnd && !self.presets.is_empty() {
+            let mut preset_hint: String<24> = Default::default();
+            write!(
+                &mut preset_hint,
+                "{} {}/{}",
+                Str::Preset.text(),
+                self.preset_selected.load(Ordering::Relaxed) + 1,
+                self.presets.len()
+            )
+            .unwrap();
+
+            self.draw_hint(
+                target,
+                NavigationIcons::Up,
+                layout::HINT_UPPER,
+                &preset_hint,
+            )?;
+        }
+
         if int_state != TimerInternalState::Edit {
             self.draw_navigation(target)?;
         }
 
+        if int_state == TimerInternalState::TimerStarted {
+            // Remaining time bar below hints. Quick-add may push countdown above initial, bar stays full then
+            let screen = target.bounding_box();
+            let area = Rectangle::new(
+                Point::new(
+                    screen.top_left.x,
+                    screen.top_left.y + screen.size.height as i32 - PROGRESS_HEIGHT as i32,
+                ),
+                Size::new(screen.size.width, PROGRESS_HEIGHT),
+            );
+
+            state.navigation_icons.draw_progress(
+                target,
+                area,
+                self.timer.countdown(),
+                self.timer.initial(),
+            )?;
+        }
+
         // Draw current countdown
         let countdown_to_draw = match int_state {
-            TimerInternalState::TimerEnd => 0,
-            TimerInternalState::Edit => self.countdown_selected.load(Ordering::Relaxed),
-            TimerInternalState::TimerStarted => self.timer.countdown(),
+            TimerInternalState::TimerEnd | TimerInternalState::Edit => {
+                Duration::seconds(self.countdown_selected.load(Ordering::Relaxed) as i64)
+            }
+            TimerInternalState::TimerStarted => self.timer.remaining(),
         };
 
-        let mut buf: String<32> = Default::default();
-        let elapsed = Duration::seconds(countdown_to_draw as i64);
-        let hours = elapsed.num_hours();
-        let minutes = elapsed.num_minutes() - 60 * hours;
-        let seconds = elapsed.num_seconds() - 60 * minutes - 60 * 60 * hours;
-
-        write!(&mut buf, "{:02}:{:02}:{:02}", hours, minutes, seconds).unwrap();
-
-        Text::with_alignment(
-            &buf,
-            Point { x: 64, y: 34 },
-            self.state().content_style,
-            Alignment::Center,
+        let mut buf = [0_u8; 32];
+        let countdown_text = format_duration(
+            &mut buf,
+            countdown_to_draw.num_milliseconds() as u32,
+            Precision::Seconds,
         )
-        .draw(target)?;
+        .unwrap();
+
+        self.draw_content(target, countdown_text)?;
 
         // Draw selector
         if int_state == TimerInternalState::Edit {
-            let y_above = 19;
-            let y_below = 40;
-
             let field = self.edit_field.load(Ordering::Relaxed);
+
+            let mut repeat_hint: String<24> = Default::default();
+            write!(
+                &mut repeat_hint,
+                "{}: {}",
+                Str::Repeat.text(),
+                Str::on_off(self.timer.repeat()).text()
+            )
+            .unwrap();
+
             let x_pos = match field {
-                EditField::Hours => 36,
-                EditField::Minutes => 64,
-                EditField::Seconds => 92,
+                EditField::Hours | EditField::Minutes | EditField::Seconds => {
+                    layout::field_x(TIME_CHARS, field as u32)
+                }
+                // No digit to point at: repeat line is shown as hint instead
+                EditField::Repeat => {
+                    self.draw_hint(
+                        target,
+                        NavigationIcons::Up,
+                        layout::HINT_UPPER,
+                        &repeat_hint,
+                    )?;
+
+                    return Ok(());
+                }
             };
 
+            Text::new(
+                &repeat_hint,
+                layout::HINT_UPPER + layout::HINT_TEXT_OFFSET,
+                state.small_text_style,
+            )
+            .draw(target)?;
+
             self.state().navigation_icons.draw_icon(
                 target,
                 NavigationIcons::Up,
-                Point {
-                    x: x_pos,
-                    y: y_above,
-                },
+                Point::new(x_pos, layout::FIELD_ABOVE),
             )?;
 
             self.state().navigation_icons.draw_icon(
                 target,
                 NavigationIcons::Down,
-                Point {
-                    x: x_pos,
-                    y: y_below,
-                },
+                Point::new(x_pos, layout::FIELD_BELOW),
             )?;
+
+            // Field framed when value is clamped at limit
+            if self.clamp_cue_active() {
+                layout::field_frame(x_pos)
+                    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                    .draw(target)?;
+            }
         }
 
         Ok(())
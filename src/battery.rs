@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use hal::adc::config::{AdcConfig, SampleTime};
+use hal::adc::Adc;
+use hal::gpio::{Analog, PA0};
+use hal::pac::ADC1;
+
+/// Battery connected via resistor divider: VBAT -> R1 -> PA0 -> R2 -> GND
+/// Ratio is (R1 + R2) / R2
+const DIVIDER_NUMERATOR: u32 = 2;
+const DIVIDER_DENOMINATOR: u32 = 1;
+/// Below this voltage battery icon drawn in header
+const LOW_BATTERY_MV: u32 = 3400;
+
+/// Last measured voltage. 0 if not measured yet
+static BATTERY_MV: AtomicU32 = AtomicU32::new(0);
+
+pub struct BatteryMonitor {
+    adc: Adc<ADC1>,
+    pin: PA0<Analog>,
+}
+
+impl BatteryMonitor {
+    pub fn new(adc: ADC1, pin: PA0<Analog>) -> Self {
+        let mut adc = Adc::adc1(adc, true, AdcConfig::default());
+        // Measures VDDA via VREFINT, so conversions are correct on discharging battery
+        adc.calibrate();
+
+        Self { adc, pin }
+    }
+
+    /// Measures battery voltage and stores it for [battery_millivolts]
+    pub fn update(&mut self) -> u32 {
+        // VDDA changes with battery, recalibrate before each measurement
+        self.adc.calibrate();
+
+        let sample = self.adc.convert(&self.pin, SampleTime::Cycles_480);
+        let pin_mv = self.adc.sample_to_millivolts(sample) as u32;
+        let mv = pin_mv * DIVIDER_NUMERATOR / DIVIDER_DENOMINATOR;
+
+        BATTERY_MV.store(mv, Ordering::Relaxed);
+
+        mv
+    }
+}
+
+/// Last measured battery voltage. 0 if not measured yet
+#[inline]
+pub fn battery_millivolts() -> u32 {
+    BATTERY_MV.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn battery_low() -> bool {
+    let mv = battery_millivolts();
+    mv != 0 && mv < LOW_BATTERY_MV
+}
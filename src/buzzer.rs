@@ -1,4 +1,7 @@
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use critical_section::Mutex;
 
 use hal::gpio::PA7;
 use hal::pac::TIM3;
@@ -7,27 +10,113 @@ use hal::timer::PwmExt;
 use stm32f4xx_hal::rcc::Clocks;
 use stm32f4xx_hal::timer::PwmChannel;
 
+const MAX_VOLUME: u8 = 100;
+
+/// Buzzer shared by countdown, alarm and key click
+///
+/// Used from tasks of different priorities and from TIM interrupt, so channel is accessed only in critical section
 pub struct Buzzer {
-    ch: RefCell<PwmChannel<TIM3, 1>>,
+    ch: Mutex<RefCell<PwmChannel<TIM3, 1>>>,
+    /// Volume in percents applied on each enable
+    volume: &'static AtomicU8,
+    /// Sound enabled by [Buzzer::enable]
+    enabled: AtomicBool,
+    /// Click in progress, see [Buzzer::click_start]
+    clicking: AtomicBool,
 }
 
 impl Buzzer {
-    pub fn new(timer: TIM3, pin: PA7, clocks: &Clocks) -> Self {
+    pub fn new(timer: TIM3, pin: PA7, volume: &'static AtomicU8, clocks: &Clocks) -> Self {
         let pwm = timer.pwm_hz(pin.into_alternate(), 1.Hz(), clocks);
-        let mut ch = pwm.split();
-        let max_duty = ch.get_max_duty();
-        ch.set_duty(max_duty / 2);
+        let ch = pwm.split();
 
-        Self {
-            ch: RefCell::new(ch),
-        }
+        let buzzer = Self {
+            ch: Mutex::new(RefCell::new(ch)),
+            volume,
+            enabled: AtomicBool::new(false),
+            clicking: AtomicBool::new(false),
+        };
+        buzzer.set_volume(volume.load(Ordering::Relaxed));
+
+        buzzer
     }
 
+    /// Starts sound with current volume
     pub fn enable(&self) {
-        self.ch.borrow_mut().enable();
+        self.enable_scaled(MAX_VOLUME);
+    }
+
+    /// Starts sound with `scale` percents of current volume. Buzzer PWM is 1 Hz, so lower duty is also shorter beep
+    pub fn enable_scaled(&self, scale: u8) {
+        let percent = (self.volume.load(Ordering::Relaxed) as u32 * scale.min(MAX_VOLUME) as u32
+            / MAX_VOLUME as u32) as u8;
+
+        critical_section::with(|cs| {
+            // Takes over running click
+            self.clicking.store(false, Ordering::Relaxed);
+            self.enabled.store(true, Ordering::Relaxed);
+
+            let mut ch = self.ch.borrow(cs).borrow_mut();
+            set_duty_percent(&mut ch, percent);
+            ch.enable();
+        });
     }
 
     pub fn disable(&self) {
-        self.ch.borrow_mut().disable();
+        critical_section::with(|cs| {
+            self.clicking.store(false, Ordering::Relaxed);
+            self.enabled.store(false, Ordering::Relaxed);
+
+            self.ch.borrow(cs).borrow_mut().disable();
+        });
     }
+
+    /// Starts short key click. Ignored while buzzer sounds or muted. Must be ended with [Buzzer::click_end]
+    ///
+    /// Click uses full duty: with slow PWM, duty cycle of short pulse would depend on its phase
+    pub fn click_start(&self) -> bool {
+        if self.volume.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        critical_section::with(|cs| {
+            if self.enabled.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            self.clicking.store(true, Ordering::Relaxed);
+
+            let mut ch = self.ch.borrow(cs).borrow_mut();
+            let max_duty = ch.get_max_duty();
+            ch.set_duty(max_duty);
+            ch.enable();
+
+            true
+        })
+    }
+
+    /// Stops click unless buzzer was enabled for other sound meanwhile
+    pub fn click_end(&self) {
+        critical_section::with(|cs| {
+            if self.clicking.swap(false, Ordering::Relaxed) {
+                self.ch.borrow(cs).borrow_mut().disable();
+            }
+        });
+    }
+
+    /// Sets volume in percents. 0% mutes buzzer, values above 100% are clamped.
+    pub fn set_volume(&self, percent: u8) {
+        critical_section::with(|cs| {
+            set_duty_percent(&mut self.ch.borrow(cs).borrow_mut(), percent)
+        });
+    }
+}
+
+/// PWM is 1 Hz, so duty sets how long buzzer sounds each second, not how loud. 100% volume is mapped to half
+/// of max duty: half second beep and equal pause, so beeps stay apart
+fn set_duty_percent(ch: &mut PwmChannel<TIM3, 1>, percent: u8) {
+    let percent = percent.min(MAX_VOLUME) as u32;
+
+    let longest_duty = ch.get_max_duty() as u32 / 2;
+    ch.set_duty((longest_duty * percent / MAX_VOLUME as u32) as u16);
 }
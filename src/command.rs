@@ -0,0 +1,218 @@
+use chrono::NaiveTime;
+use heapless::Vec;
+
+/// Longest accepted command line without line end
+pub const MAX_LINE_LEN: usize = 32;
+
+/// Command received over serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// `SET HH:MM:SS`: sets local time of RTC, date is kept
+    Set(NaiveTime),
+    /// `GET`: reports local date and time
+    Get,
+    /// `TEMP`: reports last read temperature
+    Temp,
+}
+
+/// Error of command line parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// First word is not a known command
+    Unknown,
+    /// Command is known, but its arguments are missing or malformed
+    Syntax,
+    /// Line is longer than [MAX_LINE_LEN], it is dropped whole
+    TooLong,
+    /// Line is not valid UTF-8
+    Encoding,
+}
+
+impl Error {
+    /// Text of error response
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Error::Unknown => "ERR UNKNOWN",
+            Error::Syntax => "ERR SYNTAX",
+            Error::TooLong => "ERR TOO LONG",
+            Error::Encoding => "ERR ENCODING",
+        }
+    }
+}
+
+/// Parses command line. Commands are case insensitive, extra spaces are allowed
+pub fn parse(line: &str) -> Result<Command, Error> {
+    let mut words = line.split_ascii_whitespace();
+    let name = words.next().ok_or(Error::Unknown)?;
+
+    let command = if name.eq_ignore_ascii_case("SET") {
+        let time = words.next().ok_or(Error::Syntax)?;
+        Command::Set(parse_time(time).ok_or(Error::Syntax)?)
+    } else if name.eq_ignore_ascii_case("GET") {
+        Command::Get
+    } else if name.eq_ignore_ascii_case("TEMP") {
+        Command::Temp
+    } else {
+        return Err(Error::Unknown);
+    };
+
+    if words.next().is_some() {
+        return Err(Error::Syntax);
+    }
+
+    Ok(command)
+}
+
+/// Parses `HH:MM:SS`
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    let mut parts = s.split(':').map(|p| p.parse::<u32>().ok());
+
+    let hour = parts.next()??;
+    let min = parts.next()??;
+    let sec = parts.next()??;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    NaiveTime::from_hms_opt(hour, min, sec)
+}
+
+/// Collects received bytes into lines ended by `\n`. `\r` before it is dropped
+///
+/// Buffer is bounded: too long line is dropped and reported once at its end
+pub struct LineBuffer {
+    /// One more byte for `\r`
+    buf: Vec<u8, { MAX_LINE_LEN + 1 }>,
+    /// Some bytes of current line didn't fit
+    overflow: bool,
+    /// Buffer holds line returned by last push. It is cleared by next one
+    complete: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            overflow: false,
+            complete: false,
+        }
+    }
+
+    /// Adds byte. Returns line at its end, None otherwise
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, Error>> {
+        if core::mem::replace(&mut self.complete, false) {
+            self.buf.clear();
+        }
+
+        if byte != b'\n' {
+            if self.buf.push(byte).is_err() {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        self.complete = true;
+        let line = self.buf.strip_suffix(b"\r").unwrap_or(&self.buf);
+        // Byte kept for `\r` may hold one more character of line without it
+        if core::mem::replace(&mut self.overflow, false) || line.len() > MAX_LINE_LEN {
+            return Some(Err(Error::TooLong));
+        }
+
+        Some(core::str::from_utf8(line).map_err(|_| Error::Encoding))
+    }
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, min: u32, sec: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, sec).unwrap()
+    }
+
+    /// Lines completed while pushing `bytes`
+    fn lines(buffer: &mut LineBuffer, bytes: &[u8]) -> std::vec::Vec<Result<String, Error>> {
+        bytes
+            .iter()
+            .filter_map(|&byte| buffer.push(byte).map(|line| line.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_case_insensitive() {
+        assert_eq!(parse("SET 07:05:09"), Ok(Command::Set(time(7, 5, 9))));
+        assert_eq!(
+            parse("  set   23:59:59 "),
+            Ok(Command::Set(time(23, 59, 59)))
+        );
+        assert_eq!(parse("get"), Ok(Command::Get));
+        assert_eq!(parse("Temp"), Ok(Command::Temp));
+    }
+
+    #[test]
+    fn unknown_and_empty_lines_are_rejected() {
+        assert_eq!(parse(""), Err(Error::Unknown));
+        assert_eq!(parse("   "), Err(Error::Unknown));
+        assert_eq!(parse("RESET"), Err(Error::Unknown));
+    }
+
+    #[test]
+    fn malformed_arguments_are_syntax_errors() {
+        for line in [
+            "SET",
+            "SET 24:00:00",
+            "SET 12:60:00",
+            "SET 12:00",
+            "SET 12:00:00:00",
+            "SET 12:0a:00",
+            "SET 12:00:00 now",
+            "GET now",
+        ] {
+            assert_eq!(parse(line), Err(Error::Syntax), "{}", line);
+        }
+    }
+
+    #[test]
+    fn line_end_completes_line() {
+        let mut buffer = LineBuffer::new();
+        assert_eq!(
+            lines(&mut buffer, b"GET\r\nTEMP\n\n"),
+            [Ok("GET".into()), Ok("TEMP".into()), Ok("".into())]
+        );
+    }
+
+    #[test]
+    fn too_long_line_is_reported_once_and_dropped() {
+        for len in [MAX_LINE_LEN + 1, MAX_LINE_LEN + 2, 100] {
+            let mut buffer = LineBuffer::new();
+            let mut bytes = std::vec![b'A'; len];
+            bytes.push(b'\n');
+
+            assert_eq!(lines(&mut buffer, &bytes), [Err(Error::TooLong)], "{}", len);
+            assert_eq!(lines(&mut buffer, b"GET\n"), [Ok("GET".into())]);
+        }
+    }
+
+    #[test]
+    fn longest_line_fits_with_carriage_return() {
+        let mut buffer = LineBuffer::new();
+        let mut bytes = [b'A'; MAX_LINE_LEN + 2];
+        bytes[MAX_LINE_LEN] = b'\r';
+        bytes[MAX_LINE_LEN + 1] = b'\n';
+
+        let expected = core::str::from_utf8(&bytes[..MAX_LINE_LEN]).unwrap();
+        assert_eq!(lines(&mut buffer, &bytes), [Ok(expected.into())]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_encoding_error() {
+        let mut buffer = LineBuffer::new();
+        assert_eq!(lines(&mut buffer, b"GET\xFF\n"), [Err(Error::Encoding)]);
+    }
+}
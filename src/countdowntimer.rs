@@ -1,6 +1,7 @@
 use core::cell::RefCell;
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+use chrono::Duration;
 use cortex_m::peripheral::NVIC;
 use hal::pac::Interrupt;
 use hal::prelude::*;
@@ -10,23 +11,78 @@ use hal::timer::{Counter, Instance};
 
 use crate::buzzer::Buzzer;
 
-const TIMER_TARGET_FREQ: u32 = 2000;
+/// Countdown is counted in seconds, so interrupt period is fixed
 const TIMER_MS_STEP: u32 = 1000;
+/// How many timer ticks buzzer sounds after countdown end before auto silence
+const ALARM_DURATION_TICKS: u32 = 30;
+/// Maximum countdown in seconds: 99 hours, 59 mins, 59 secs
+pub const MAX_COUNTDOWN: u32 = 60 * 60 * 99 + 60 * 59 + 59;
+/// How many timer ticks buzzer beeps on each cycle in repeat mode
+const REPEAT_BEEP_TICKS: u32 = 1;
+/// Alarm loudness while nobody stops it: from which tick after countdown end, percents of volume.
+/// Used only if escalation is enabled, otherwise alarm sounds at full volume from start
+const ALARM_ESCALATION: [(u32, u8); 3] = [(0, 25), (10, 50), (20, 100)];
 
-pub struct CountdownTimer<TIM: Instance> {
-    timer: RefCell<Counter<TIM, TIMER_TARGET_FREQ>>,
-    buzzer: Buzzer,
+/// Countdown used by app states. Hides timer peripheral behind it
+pub trait Countdown {
+    /// Starts countdown of `countdown_seconds` from the beginning
+    fn start(&self, countdown_seconds: u32);
+    /// Stops countdown and its alarm
+    fn stop(&self);
+    /// Freezes countdown
+    fn pause(&self);
+    /// Continues countdown after [Countdown::pause]
+    fn resume(&self);
+    /// Adds time to running countdown
+    fn add_time(&self, seconds: u32);
+    /// Enables or disables restarting countdown each time it reaches zero
+    fn set_repeat(&self, repeat: bool);
+    fn repeat(&self) -> bool;
+    /// Seconds left
+    fn countdown(&self) -> u32;
+    /// Time left
+    fn remaining(&self) -> Duration {
+        Duration::seconds(self.countdown() as i64)
+    }
+    /// Countdown passed to last `start`
+    fn initial(&self) -> u32;
+    fn started(&self) -> bool;
+    fn paused(&self) -> bool;
+}
+
+/// `FREQ` is counter frequency in Hz. Counter must fit [TIMER_MS_STEP] at it: up to 65 kHz for 16-bit timers
+pub struct CountdownTimer<TIM: Instance, const FREQ: u32> {
+    timer: RefCell<Counter<TIM, FREQ>>,
+    buzzer: &'static Buzzer,
     it: Interrupt,
 
     countdown: AtomicU32,
     started: AtomicBool,
+    paused: AtomicBool,
+    /// Ticks passed since countdown reached zero
+    alarm_ticks: AtomicU32,
+
+    /// Restart countdown from `initial` when it reaches zero
+    repeat: AtomicBool,
+    /// Countdown passed to last `start`, used to reload in repeat mode
+    initial: AtomicU32,
+    /// Ticks left until cycle beep is silenced
+    beep_ticks: AtomicU32,
+    /// Alarm grows louder by [ALARM_ESCALATION]. Setting owned by app state
+    escalation: &'static AtomicBool,
 }
 
-impl<TIM: Instance> CountdownTimer<TIM> {
-    pub fn new(timer: TIM, tim_interrupt: Interrupt, buzzer: Buzzer, clocks: &Clocks) -> Self {
+impl<TIM: Instance, const FREQ: u32> CountdownTimer<TIM, FREQ> {
+    pub fn new(
+        timer: TIM,
+        tim_interrupt: Interrupt,
+        buzzer: &'static Buzzer,
+        escalation: &'static AtomicBool,
+        clocks: &Clocks,
+    ) -> Self {
         let mut tim = timer.counter(clocks);
         tim.start(TIMER_MS_STEP.millis())
-            .expect("Failed to start timer");
+            .expect("Countdown timer frequency out of timer range");
         tim.listen(Event::Update);
         NVIC::mask(tim_interrupt);
 
@@ -37,12 +93,72 @@ impl<TIM: Instance> CountdownTimer<TIM> {
 
             countdown: AtomicU32::new(0),
             started: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            alarm_ticks: AtomicU32::new(0),
+
+            repeat: AtomicBool::new(false),
+            initial: AtomicU32::new(0),
+            beep_ticks: AtomicU32::new(0),
+            escalation,
+        }
+    }
+
+    #[inline]
+    pub fn handle_it(&self) {
+        self.timer.borrow_mut().clear_interrupt(Event::Update);
+        if self.started() && !self.paused() {
+            // Silence cycle beep after its duration
+            let beep = self.beep_ticks.load(Ordering::Relaxed);
+            if beep > 0 {
+                self.beep_ticks.store(beep - 1, Ordering::Relaxed);
+                if beep == 1 {
+                    self.buzzer.disable();
+                }
+            }
+
+            let c = self.countdown.load(Ordering::Acquire);
+            let initial = self.initial.load(Ordering::Relaxed);
+            if c == 1 && self.repeat() && initial > 0 {
+                // Cycle finished: reload and beep shortly instead of continuous alarm
+                self.countdown.store(initial, Ordering::Release);
+                self.beep_ticks.store(REPEAT_BEEP_TICKS, Ordering::Relaxed);
+                self.buzzer.enable();
+            } else if c > 0 {
+                self.countdown.fetch_sub(1, Ordering::Release);
+            } else {
+                let alarm_ticks = self.alarm_ticks.fetch_add(1, Ordering::Relaxed);
+                if alarm_ticks < ALARM_DURATION_TICKS {
+                    self.buzzer.enable_scaled(self.alarm_scale(alarm_ticks));
+                } else {
+                    // Nobody stopped the alarm, silence it but keep timer finished
+                    self.buzzer.disable();
+                }
+            }
         }
     }
 
+    /// Percents of volume for alarm `alarm_ticks` after countdown end
+    fn alarm_scale(&self, alarm_ticks: u32) -> u8 {
+        if !self.escalation.load(Ordering::Relaxed) {
+            return 100;
+        }
+
+        ALARM_ESCALATION
+            .iter()
+            .rev()
+            .find(|(from_tick, _)| alarm_ticks >= *from_tick)
+            .map_or(100, |(_, scale)| *scale)
+    }
+}
+
+impl<TIM: Instance, const FREQ: u32> Countdown for CountdownTimer<TIM, FREQ> {
     #[inline]
-    pub fn start(&self, countdown_seconds: u32) {
+    fn start(&self, countdown_seconds: u32) {
         self.countdown.store(countdown_seconds, Ordering::Relaxed);
+        self.initial.store(countdown_seconds, Ordering::Relaxed);
+        self.alarm_ticks.store(0, Ordering::Relaxed);
+        self.beep_ticks.store(0, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
         self.started.store(true, Ordering::Relaxed);
 
         // Restart timer
@@ -58,8 +174,11 @@ impl<TIM: Instance> CountdownTimer<TIM> {
     }
 
     #[inline]
-    pub fn stop(&self) {
+    fn stop(&self) {
         self.countdown.store(0, Ordering::Relaxed);
+        self.alarm_ticks.store(0, Ordering::Relaxed);
+        self.beep_ticks.store(0, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
         self.started.store(false, Ordering::Relaxed);
 
         self.buzzer.disable();
@@ -67,29 +186,92 @@ impl<TIM: Instance> CountdownTimer<TIM> {
         NVIC::mask(self.it);
     }
 
+    /// Freezes countdown. Alarm is silenced while paused and continues after resume
     #[inline]
-    pub fn handle_it(&self) {
-        self.timer.borrow_mut().clear_interrupt(Event::Update);
-        if self.started() {
-            let c = self.countdown.load(Ordering::Acquire);
-            if c > 0 {
-                self.countdown.fetch_sub(1, Ordering::Release);
-            } else {
-                self.buzzer.enable();
-            }
+    fn pause(&self) {
+        if !self.started() {
+            return;
         }
+
+        NVIC::mask(self.it);
+        self.paused.store(true, Ordering::Relaxed);
+
+        self.buzzer.disable();
     }
 
     #[inline]
-    pub fn countdown(&self) -> u32 {
+    fn resume(&self) {
+        if !self.paused.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        // Restart timer to count full second after resume
+        self.timer
+            .borrow_mut()
+            .start(TIMER_MS_STEP.millis())
+            .unwrap();
+
+        NVIC::unpend(self.it);
+        // Safe: TIM interrupts doesn't affect any critical-section locked resources
+        unsafe {
+            NVIC::unmask(self.it);
+        }
+    }
+
+    /// Adds time to running countdown without stopping it. Clamped to [MAX_COUNTDOWN]
+    ///
+    /// Finished countdown is restarted and its alarm silenced
+    fn add_time(&self, seconds: u32) {
+        if !self.started() {
+            return;
+        }
+
+        // Interrupt may decrement countdown at any moment, so update is done atomically
+        let previous = self
+            .countdown
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| {
+                Some(c.saturating_add(seconds).min(MAX_COUNTDOWN))
+            })
+            .unwrap();
+
+        if previous == 0 {
+            self.alarm_ticks.store(0, Ordering::Relaxed);
+            self.buzzer.disable();
+        }
+    }
+
+    /// Enables or disables restarting countdown each time it reaches zero
+    #[inline]
+    fn set_repeat(&self, repeat: bool) {
+        self.repeat.store(repeat, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn repeat(&self) -> bool {
+        self.repeat.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn countdown(&self) -> u32 {
         self.countdown.load(Ordering::Relaxed)
     }
 
+    /// Countdown passed to last `start`
+    #[inline]
+    fn initial(&self) -> u32 {
+        self.initial.load(Ordering::Relaxed)
+    }
+
     #[inline]
-    pub fn started(&self) -> bool {
+    fn started(&self) -> bool {
         self.started.load(Ordering::Relaxed)
     }
+
+    #[inline]
+    fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
 }
 
-unsafe impl<TIM: Instance> Sync for CountdownTimer<TIM> {}
-unsafe impl<TIM: Instance> Send for CountdownTimer<TIM> {}
+unsafe impl<TIM: Instance, const FREQ: u32> Sync for CountdownTimer<TIM, FREQ> {}
+unsafe impl<TIM: Instance, const FREQ: u32> Send for CountdownTimer<TIM, FREQ> {}
@@ -0,0 +1,100 @@
+#[cfg(target_os = "none")]
+use hal::pac::{CRC, RCC};
+
+/// CRC-32 polynomial of hardware unit, fixed
+const POLYNOMIAL: u32 = 0x04C1_1DB7;
+/// Value of hardware unit after reset
+const INITIAL: u32 = 0xFFFF_FFFF;
+
+/// CRC-32/MPEG-2: polynomial 0x04C11DB7, initial value 0xFFFFFFFF, no reflection, no final XOR.
+/// Check value of "123456789" is 0x0376E6E7
+pub trait Crc32 {
+    /// CRC of `data`
+    fn crc32(&mut self, data: &[u8]) -> u32;
+}
+
+/// [Crc32] by hardware CRC unit
+///
+/// Unit takes whole 32-bit words only. Words are taken big-endian, so result is the same as of byte-wise
+/// CRC; trailing bytes are added in software
+#[cfg(target_os = "none")]
+pub struct HardwareCrc32 {
+    crc: CRC,
+}
+
+#[cfg(target_os = "none")]
+impl HardwareCrc32 {
+    /// Enables CRC unit clock. Must be called in init: clock register is modified without lock
+    pub fn new(crc: CRC) -> Self {
+        // Safe: interrupts are not enabled yet, so nothing else modifies register
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.ahb1enr.modify(|_, w| w.crcen().set_bit());
+
+        Self { crc }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Crc32 for HardwareCrc32 {
+    fn crc32(&mut self, data: &[u8]) -> u32 {
+        self.crc.cr.write(|w| w.reset().set_bit());
+
+        let mut words = data.chunks_exact(4);
+        for word in &mut words {
+            let word = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            self.crc.dr.write(|w| unsafe { w.bits(word) });
+        }
+
+        update(self.crc.dr.read().bits(), words.remainder())
+    }
+}
+
+/// [Crc32] computed bit by bit. Needs no peripheral, so host tests use it
+pub struct SoftwareCrc32;
+
+impl Crc32 for SoftwareCrc32 {
+    fn crc32(&mut self, data: &[u8]) -> u32 {
+        update(INITIAL, data)
+    }
+}
+
+/// Continues `crc` with `data` bit by bit
+fn update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+    const CHECK_VALUE: u32 = 0x0376_E6E7;
+
+    #[test]
+    fn software_crc_matches_check_value() {
+        assert_eq!(SoftwareCrc32.crc32(CHECK_INPUT), CHECK_VALUE);
+    }
+
+    #[test]
+    fn empty_data_gives_initial_value() {
+        assert_eq!(SoftwareCrc32.crc32(&[]), INITIAL);
+    }
+
+    #[test]
+    fn tail_continues_word_wise_part() {
+        // Hardware unit takes 8 bytes of check input as 2 words, the last byte is added by `update`.
+        // Any split gives the same result
+        for split in 0..=CHECK_INPUT.len() {
+            let (head, tail) = CHECK_INPUT.split_at(split);
+            assert_eq!(update(update(INITIAL, head), tail), CHECK_VALUE);
+        }
+    }
+}
@@ -4,20 +4,85 @@ use chrono::prelude::*;
 
 use critical_section::Mutex;
 
-use crate::i2c::BlockingI2C;
+use crate::i2c::{BlockingI2C, BlockingI2CBus, BusError};
 
 const I2C_ADDRESS: u8 = 0b01101000;
 const REGISTER_COUNT: usize = 7;
 
-pub use hal::i2c::Error;
+/// Error of RTC operations
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Bus transaction failed
+    Bus(BusError),
+    /// Register holds value out of its range: RTC is corrupted or lost power without backup battery
+    InvalidData,
+    /// INT/SQW pin drives alarm interrupt, square wave would silently disable it
+    AlarmInterruptEnabled,
+}
+
+impl From<BusError> for Error {
+    fn from(e: BusError) -> Self {
+        Error::Bus(e)
+    }
+}
 
 #[repr(u8)]
 pub enum Register {
     Seconds = 0x00,
     Minutes = 0x01,
     Hours = 0x02,
+    Day = 0x03,
+    Date = 0x04,
+    Month = 0x05,
+    Year = 0x06,
 }
 
+/// Control register: oscillator, square wave and interrupt bits
+const CONTROL_REGISTER: u8 = 0x0E;
+/// Status register: flags and 32 kHz output enable
+const STATUS_REGISTER: u8 = 0x0F;
+
+#[repr(u8)]
+enum ControlMasks {
+    /// Square wave runs on backup battery too
+    Bbsqw = 0b01000000,
+    /// Square wave rate select, 2 bits
+    Rs = 0b00011000,
+    /// INT/SQW pin outputs alarm interrupt(True) or square wave(False)
+    Intcn = 0b00000100,
+    /// Alarm 2 and alarm 1 interrupt enable
+    AlarmIe = 0b00000011,
+}
+
+/// Shift of [ControlMasks::Rs]
+const RS_SHIFT: u8 = 3;
+
+/// Enables 32 kHz output in [STATUS_REGISTER]
+const STATUS_EN32KHZ_MASK: u8 = 0b00001000;
+
+/// Frequency of square wave on INT/SQW pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SqwFreq {
+    Hz1 = 0b00,
+    Hz1024 = 0b01,
+    Hz4096 = 0b10,
+    Hz8192 = 0b11,
+}
+
+/// Aging offset register, two's complement
+const AGING_OFFSET_REGISTER: u8 = 0x10;
+
+/// First of temperature registers: integer part, then quarters of degree in 2 high bits
+const TEMPERATURE_REGISTER: u8 = 0x11;
+/// Temperature registers hold quarters of degree
+const TEMPERATURE_STEP: f32 = 0.25;
+
+/// Years stored in RTC are counted from this one
+const BASE_YEAR: i32 = 2000;
+/// Century bit in month register
+const MONTH_CENTURY_MASK: u8 = 0b10000000;
+
 #[repr(u8)]
 enum HoursMasks {
     /// 12(True) or 24(False) hours format
@@ -36,55 +101,145 @@ impl<I2C: BlockingI2C> DS3231<I2C> {
         Self { i2c }
     }
 
+    /// Reads RTC as UTC time. See [DS3231::read_naive]
     pub fn update_time(&self) -> Result<DateTime<Utc>, Error> {
-        let data = nb::block!(self.read_registers())?;
+        Ok(DateTime::from_utc(self.read_naive()?, Utc))
+    }
+
+    /// Writes UTC time into RTC. See [DS3231::write_naive]
+    pub fn set_time(&self, time: DateTime<Utc>) -> Result<(), Error> {
+        self.write_naive(time.naive_utc())
+    }
 
-        let mut time: DateTime<Utc> = Default::default();
+    /// Reads registers as is. RTC knows nothing about timezones, app keeps UTC in it
+    pub fn read_naive(&self) -> Result<NaiveDateTime, Error> {
+        let data = self.read_registers()?;
 
-        let secs = bcd_to_decimal(data[Register::Seconds as usize]);
-        time = time.with_second(secs as u32).unwrap();
+        let year = BASE_YEAR + bcd_to_decimal(data[Register::Year as usize]) as i32;
+        let month = bcd_to_decimal(data[Register::Month as usize] & !MONTH_CENTURY_MASK);
+        let day = bcd_to_decimal(data[Register::Date as usize]);
 
-        let mins = bcd_to_decimal(data[Register::Minutes as usize]);
-        time = time.with_minute(mins as u32).unwrap();
+        // Date registers of never set RTC are invalid, keep default date then
+        let date = NaiveDate::from_ymd_opt(year, month as u32, day as u32).unwrap_or_default();
 
+        let secs = bcd_to_decimal(data[Register::Seconds as usize]);
+        let mins = bcd_to_decimal(data[Register::Minutes as usize]);
         let hours = hours_to_decimal(data[Register::Hours as usize]);
-        time = time.with_hour(hours as u32).unwrap();
+        // Time registers are valid even on never set RTC, so out of range value means corruption
+        let time = NaiveTime::from_hms_opt(hours as u32, mins as u32, secs as u32)
+            .ok_or(Error::InvalidData)?;
 
-        Ok(time)
+        Ok(date.and_time(time))
     }
 
-    pub fn set_time(&self, time: DateTime<Utc>) -> Result<(), Error> {
+    /// Writes registers as is. Hours are stored in 24H format
+    pub fn write_naive(&self, time: NaiveDateTime) -> Result<(), Error> {
         let mut data = [0_u8; REGISTER_COUNT];
         data[Register::Seconds as usize] = decimal_to_bcd(time.second() as u8);
         data[Register::Minutes as usize] = decimal_to_bcd(time.minute() as u8);
         // Store in 24H format
         data[Register::Hours as usize] = decimal_to_bcd(time.hour() as u8);
 
-        nb::block!(self.write_registers(&data))?;
+        data[Register::Day as usize] = time.weekday().number_from_monday() as u8;
+        data[Register::Date as usize] = decimal_to_bcd(time.day() as u8);
+        data[Register::Month as usize] = decimal_to_bcd(time.month() as u8);
+        let year = (time.year() - BASE_YEAR).clamp(0, 99);
+        data[Register::Year as usize] = decimal_to_bcd(year as u8);
+
+        self.write_registers(&data)?;
 
         Ok(())
     }
 
-    fn read_registers(&self) -> nb::Result<[u8; REGISTER_COUNT], Error> {
-        let mut buf = [0_u8; REGISTER_COUNT];
+    /// Reads temperature of internal sensor in Celsius. RTC updates it once per 64 seconds
+    pub fn temperature(&self) -> Result<f32, Error> {
+        let mut buf = [0_u8; 2];
+        self.i2c
+            .write_read_blocking(I2C_ADDRESS, &[TEMPERATURE_REGISTER], &mut buf)?;
+
+        Ok(buf[0] as i8 as f32 + (buf[1] >> 6) as f32 * TEMPERATURE_STEP)
+    }
+
+    /// Reads oscillator trim. See [DS3231::set_aging_offset]
+    pub fn aging_offset(&self) -> Result<i8, Error> {
+        let mut buf = [0_u8; 1];
+        self.i2c
+            .write_read_blocking(I2C_ADDRESS, &[AGING_OFFSET_REGISTER], &mut buf)?;
+
+        Ok(buf[0] as i8)
+    }
+
+    /// Trims oscillator to compensate drift. Positive values add capacitance and slow the clock, negative ones
+    /// speed it up, about 0.1 ppm per step at 25°C. Applied on next temperature conversion, at most 64 seconds later
+    pub fn set_aging_offset(&self, offset: i8) -> Result<(), Error> {
+        // Register holds plain two's complement, no BCD
+        Ok(self
+            .i2c
+            .write_blocking(I2C_ADDRESS, &[AGING_OFFSET_REGISTER, offset as u8])?)
+    }
+
+    /// Enables 32.768 kHz output on 32K pin. Bit is in status register, not control one
+    pub fn enable_32khz_output(&self, enable: bool) -> Result<(), Error> {
+        self.update_register(STATUS_REGISTER, |status| {
+            if enable {
+                Ok(status | STATUS_EN32KHZ_MASK)
+            } else {
+                Ok(status & !STATUS_EN32KHZ_MASK)
+            }
+        })
+    }
 
-        critical_section::with(|cs| {
-            let mut bus = self.i2c.borrow(cs).borrow_mut();
+    /// Outputs square wave of `freq` on INT/SQW pin, or returns pin to interrupt output if None.
+    /// `on_battery` keeps wave running without main power, draining backup battery
+    ///
+    /// The pin is shared with alarm interrupts: returns [Error::AlarmInterruptEnabled] instead of disabling them
+    pub fn set_square_wave(&self, freq: Option<SqwFreq>, on_battery: bool) -> Result<(), Error> {
+        self.update_register(CONTROL_REGISTER, |control| {
+            let freq = match freq {
+                Some(freq) => freq,
+                None => return Ok(control | ControlMasks::Intcn as u8),
+            };
 
-            bus.write_read(I2C_ADDRESS, &[0], &mut buf)?;
+            if control & ControlMasks::AlarmIe as u8 != 0 {
+                return Err(Error::AlarmInterruptEnabled);
+            }
 
-            Ok(buf)
+            let mut control = control
+                & !(ControlMasks::Rs as u8 | ControlMasks::Intcn as u8 | ControlMasks::Bbsqw as u8);
+            control |= (freq as u8) << RS_SHIFT;
+            if on_battery {
+                control |= ControlMasks::Bbsqw as u8;
+            }
+
+            Ok(control)
         })
     }
 
-    fn write_registers(&self, regs: &[u8; REGISTER_COUNT]) -> nb::Result<(), Error> {
+    /// Reads register, changes it by `f` and writes back. Nothing is written if `f` fails
+    fn update_register<F>(&self, register: u8, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(u8) -> Result<u8, Error>,
+    {
+        let mut buf = [0_u8; 1];
+        self.i2c
+            .write_read_blocking(I2C_ADDRESS, &[register], &mut buf)?;
+
+        let value = f(buf[0])?;
+        Ok(self.i2c.write_blocking(I2C_ADDRESS, &[register, value])?)
+    }
+
+    fn read_registers(&self) -> Result<[u8; REGISTER_COUNT], Error> {
+        let mut buf = [0_u8; REGISTER_COUNT];
+        self.i2c.write_read_blocking(I2C_ADDRESS, &[0], &mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn write_registers(&self, regs: &[u8; REGISTER_COUNT]) -> Result<(), Error> {
         let mut buf = [0_u8; REGISTER_COUNT + 1];
         buf[1..].copy_from_slice(regs);
 
-        critical_section::with(|cs| {
-            let mut bus = self.i2c.borrow(cs).borrow_mut();
-            bus.write(I2C_ADDRESS, &buf)
-        })
+        Ok(self.i2c.write_blocking(I2C_ADDRESS, &buf)?)
     }
 }
 
@@ -106,14 +261,218 @@ fn hours_to_decimal(bcd: u8) -> u8 {
     let is_ampm_format = (HoursMasks::H12_24 as u8) & bcd;
 
     if is_ampm_format != 0 {
+        // 12 AM is midnight and 12 PM is noon, so hour 12 is 0 before adding PM offset
+        let hour =
+            bcd_to_decimal(bcd & !((HoursMasks::AmPm as u8) | (HoursMasks::H12_24 as u8))) % 12;
+
         if (HoursMasks::AmPm as u8) & bcd != 0 {
             // If is PM
-            return 12
-                + bcd_to_decimal(bcd & !((HoursMasks::AmPm as u8) | (HoursMasks::H12_24 as u8)));
+            return 12 + hour;
         } else {
-            return bcd_to_decimal(bcd & !((HoursMasks::AmPm as u8) | (HoursMasks::H12_24 as u8)));
+            return hour;
         }
     }
 
     bcd_to_decimal(bcd & !(HoursMasks::H12_24 as u8))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i2c::{self, mock::MockI2c, NoAcknowledgeSource};
+
+    /// Registers of 2024-03-09 13:45:07, Saturday
+    const REGISTERS: [u8; REGISTER_COUNT] = [0x07, 0x45, 0x13, 0x06, 0x09, 0x03, 0x24];
+
+    fn time() -> DateTime<Utc> {
+        let naive = NaiveDate::from_ymd_opt(2024, 3, 9)
+            .unwrap()
+            .and_hms_opt(13, 45, 7)
+            .unwrap();
+        Utc.from_utc_datetime(&naive)
+    }
+
+    #[test]
+    fn bcd_round_trips() {
+        for d in 0..=99 {
+            assert_eq!(bcd_to_decimal(decimal_to_bcd(d)), d);
+        }
+        assert_eq!(decimal_to_bcd(59), 0x59);
+        assert_eq!(bcd_to_decimal(0x47), 47);
+    }
+
+    #[test]
+    fn hours_decode_24h_format() {
+        assert_eq!(hours_to_decimal(0x00), 0);
+        assert_eq!(hours_to_decimal(0x09), 9);
+        assert_eq!(hours_to_decimal(0x23), 23);
+    }
+
+    #[test]
+    fn hours_decode_12h_format() {
+        let h12 = HoursMasks::H12_24 as u8;
+        let pm = HoursMasks::AmPm as u8;
+
+        // 12 AM is midnight, 12 PM is noon
+        assert_eq!(hours_to_decimal(h12 | 0x12), 0);
+        assert_eq!(hours_to_decimal(h12 | pm | 0x12), 12);
+        assert_eq!(hours_to_decimal(h12 | 0x01), 1);
+        assert_eq!(hours_to_decimal(h12 | 0x11), 11);
+        assert_eq!(hours_to_decimal(h12 | pm | 0x01), 13);
+        assert_eq!(hours_to_decimal(h12 | pm | 0x11), 23);
+    }
+
+    #[test]
+    fn update_time_reads_all_registers_from_seconds() {
+        let bus = MockI2c::bus(&[&REGISTERS]);
+
+        assert_eq!(DS3231::new(bus).update_time(), Ok(time()));
+        assert_eq!(MockI2c::writes(bus), [(I2C_ADDRESS, vec![0x00])]);
+    }
+
+    #[test]
+    fn set_time_writes_registers_after_pointer() {
+        let bus = MockI2c::bus(&[]);
+
+        DS3231::new(bus).set_time(time()).unwrap();
+
+        let mut expected = vec![0x00];
+        expected.extend_from_slice(&REGISTERS);
+        assert_eq!(MockI2c::writes(bus), [(I2C_ADDRESS, expected)]);
+    }
+
+    #[test]
+    fn set_time_clamps_year_to_century() {
+        let bus = MockI2c::bus(&[]);
+        let naive = NaiveDate::from_ymd_opt(2100, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+
+        DS3231::new(bus)
+            .set_time(Utc.from_utc_datetime(&naive))
+            .unwrap();
+
+        let (_, bytes) = &MockI2c::writes(bus)[0];
+        assert_eq!(bytes[1 + Register::Year as usize], 0x99);
+    }
+
+    #[test]
+    fn update_time_reports_bus_error() {
+        let bus = MockI2c::bus(&[]);
+        let nack = i2c::Error::NoAcknowledge(NoAcknowledgeSource::Address);
+        MockI2c::fail(bus, nack);
+
+        assert_eq!(
+            DS3231::new(bus).update_time(),
+            Err(Error::Bus(BusError::I2C(nack)))
+        );
+    }
+
+    #[test]
+    fn out_of_range_time_is_invalid_data() {
+        for (register, value) in [
+            (Register::Seconds, 0x99),
+            (Register::Seconds, 0x60),
+            (Register::Minutes, 0x7A),
+            (Register::Hours, 0x24),
+        ] {
+            let mut registers = REGISTERS;
+            registers[register as usize] = value;
+            let bus = MockI2c::bus(&[&registers]);
+
+            assert_eq!(DS3231::new(bus).update_time(), Err(Error::InvalidData));
+        }
+    }
+
+    #[test]
+    fn invalid_date_keeps_time() {
+        // Never set RTC: date registers are zero
+        let mut registers = REGISTERS;
+        registers[Register::Date as usize] = 0x00;
+        registers[Register::Month as usize] = 0x00;
+        let bus = MockI2c::bus(&[&registers]);
+
+        let naive = DS3231::new(bus).read_naive().unwrap();
+        assert_eq!(naive.date(), NaiveDate::default());
+        assert_eq!(naive.time(), time().naive_utc().time());
+    }
+
+    #[test]
+    fn aging_offset_round_trips_as_twos_complement() {
+        for offset in [0_i8, 1, -1, 127, -128] {
+            let bus = MockI2c::bus(&[]);
+            DS3231::new(bus).set_aging_offset(offset).unwrap();
+
+            let written = MockI2c::writes(bus);
+            assert_eq!(
+                written,
+                [(I2C_ADDRESS, vec![AGING_OFFSET_REGISTER, offset as u8])]
+            );
+
+            let bus = MockI2c::bus(&[&written[0].1[1..]]);
+            assert_eq!(DS3231::new(bus).aging_offset(), Ok(offset));
+            assert_eq!(
+                MockI2c::writes(bus),
+                [(I2C_ADDRESS, vec![AGING_OFFSET_REGISTER])]
+            );
+        }
+    }
+
+    #[test]
+    fn enable_32khz_output_keeps_other_status_bits() {
+        let bus = MockI2c::bus(&[&[0b1000_0011]]);
+        DS3231::new(bus).enable_32khz_output(true).unwrap();
+        assert_eq!(
+            MockI2c::writes(bus)[1],
+            (I2C_ADDRESS, vec![STATUS_REGISTER, 0b1000_1011])
+        );
+
+        let bus = MockI2c::bus(&[&[0b1000_1011]]);
+        DS3231::new(bus).enable_32khz_output(false).unwrap();
+        assert_eq!(
+            MockI2c::writes(bus)[1],
+            (I2C_ADDRESS, vec![STATUS_REGISTER, 0b1000_0011])
+        );
+    }
+
+    #[test]
+    fn square_wave_sets_rate_and_clears_intcn() {
+        // Reset value: INTCN and RS bits set
+        let bus = MockI2c::bus(&[&[0b0001_1100]]);
+        DS3231::new(bus)
+            .set_square_wave(Some(SqwFreq::Hz1024), true)
+            .unwrap();
+
+        assert_eq!(
+            MockI2c::writes(bus)[1],
+            (I2C_ADDRESS, vec![CONTROL_REGISTER, 0b0100_1000])
+        );
+    }
+
+    #[test]
+    fn square_wave_off_returns_pin_to_interrupts() {
+        let bus = MockI2c::bus(&[&[0b0000_1000]]);
+        DS3231::new(bus).set_square_wave(None, false).unwrap();
+
+        assert_eq!(
+            MockI2c::writes(bus)[1],
+            (I2C_ADDRESS, vec![CONTROL_REGISTER, 0b0000_1100])
+        );
+    }
+
+    #[test]
+    fn square_wave_keeps_enabled_alarm_interrupts() {
+        let bus = MockI2c::bus(&[&[0b0000_0101]]);
+
+        assert_eq!(
+            DS3231::new(bus).set_square_wave(Some(SqwFreq::Hz1), false),
+            Err(Error::AlarmInterruptEnabled)
+        );
+        // Only register read, nothing written
+        assert_eq!(
+            MockI2c::writes(bus),
+            [(I2C_ADDRESS, vec![CONTROL_REGISTER])]
+        );
+    }
+}
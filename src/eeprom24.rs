@@ -0,0 +1,68 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use crate::i2c::{BlockingI2C, BlockingI2CBus};
+
+/// AT24C32 on DS3231 boards with all address pins pulled up
+const I2C_ADDRESS: u8 = 0x57;
+/// Write can't cross page boundary
+const PAGE_SIZE: usize = 32;
+/// How many times write is repeated while EEPROM busy with internal write cycle
+const WRITE_RETRIES: u32 = 100;
+
+pub use crate::i2c::BusError as Error;
+
+/// AT24Cxx EEPROM with 2 bytes addressing
+pub struct Eeprom24<I2C: BlockingI2C + 'static> {
+    i2c: &'static Mutex<RefCell<I2C>>,
+}
+
+impl<I2C: BlockingI2C> Eeprom24<I2C> {
+    pub fn new(i2c: &'static Mutex<RefCell<I2C>>) -> Self {
+        Self { i2c }
+    }
+
+    /// Reads `buffer.len()` bytes starting from `address`
+    pub fn read(&self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        self.i2c
+            .write_read_blocking(I2C_ADDRESS, &address.to_be_bytes(), buffer)
+    }
+
+    /// Writes `data` starting from `address` splitting it into pages
+    pub fn write(&self, address: u16, data: &[u8]) -> Result<(), Error> {
+        let mut address = address;
+        let mut data = data;
+
+        while !data.is_empty() {
+            let page_left = PAGE_SIZE - (address as usize % PAGE_SIZE);
+            let (chunk, rest) = data.split_at(page_left.min(data.len()));
+
+            self.write_page(address, chunk)?;
+
+            address += chunk.len() as u16;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Writes data into single page. EEPROM don't acknowledge while previous write in progress, so retry
+    fn write_page(&self, address: u16, data: &[u8]) -> Result<(), Error> {
+        debug_assert!(data.len() <= PAGE_SIZE);
+
+        let mut buf = [0_u8; PAGE_SIZE + 2];
+        buf[..2].copy_from_slice(&address.to_be_bytes());
+        buf[2..data.len() + 2].copy_from_slice(data);
+        let buf = &buf[..data.len() + 2];
+
+        let mut retries = 0;
+        loop {
+            match self.i2c.write_blocking(I2C_ADDRESS, buf) {
+                Ok(()) => return Ok(()),
+                Err(e) if retries >= WRITE_RETRIES => return Err(e),
+                Err(_) => retries += 1,
+            }
+        }
+    }
+}
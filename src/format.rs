@@ -0,0 +1,256 @@
+use core::fmt::{self, Write};
+
+use chrono::{Datelike, Timelike};
+
+/// Temperature unit. Degree sign is missing in ISO 8859-5 fonts
+const TEMPERATURE_SUFFIX: &str = "С";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Buffer is too small for formatted value
+    Truncated,
+}
+
+/// [Write] implementation over byte slice
+///
+/// On overflow as much text as fits is kept, cut on char boundary, and write returns error
+pub struct FormatBuffer<'b> {
+    buffer: &'b mut [u8],
+    /// Bytes actually written. Bytes after are never read
+    used: usize,
+    truncated: bool,
+}
+
+impl<'b> FormatBuffer<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            buffer,
+            used: 0,
+            truncated: false,
+        }
+    }
+
+    /// Is some text dropped due lack of space
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns written text. Truncated text is still valid str
+    pub fn as_str(self) -> &'b str {
+        let written = &self.buffer[..self.used];
+        // Only whole chars are copied in `write_str`
+        core::str::from_utf8(written).unwrap()
+    }
+}
+
+impl<'b> Write for FormatBuffer<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.buffer.len() - self.used;
+
+        let mut to_copy = s.len().min(free);
+        while !s.is_char_boundary(to_copy) {
+            to_copy -= 1;
+        }
+
+        self.buffer[self.used..self.used + to_copy].copy_from_slice(&s.as_bytes()[..to_copy]);
+        self.used += to_copy;
+
+        if to_copy < s.len() {
+            self.truncated = true;
+            return Err(fmt::Error);
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats temperature with one decimal place, e.g. "-0.5С" or "25.3С"
+pub fn format_temperature(buffer: &mut [u8], celsius: f32) -> Result<&str, Error> {
+    let mut w = FormatBuffer::new(buffer);
+    write_celsius(&mut w, celsius)
+        .and_then(|_| w.write_str(TEMPERATURE_SUFFIX))
+        .map_err(|_| Error::Truncated)?;
+
+    Ok(w.as_str())
+}
+
+/// Writes temperature with one decimal place and without unit, e.g. "-0.5" or "25.3"
+///
+/// `{:.1}` is not used: float formatting pulls a lot of code into `no_std` binary
+pub fn write_celsius<W: Write>(w: &mut W, celsius: f32) -> fmt::Result {
+    // Round half away from zero to tenths
+    let scaled = celsius * 10.0;
+    let tenths = if scaled >= 0.0 {
+        (scaled + 0.5) as i32
+    } else {
+        (scaled - 0.5) as i32
+    };
+
+    // Sign written separately: integer part of -0.5 is 0
+    let sign = if tenths < 0 { "-" } else { "" };
+    let tenths = tenths.unsigned_abs();
+
+    write!(w, "{}{}.{}", sign, tenths / 10, tenths % 10)
+}
+
+/// Writes date and time as "YYYY-MM-DD HH:MM:SS"
+pub fn write_date_time<W: Write, T: Datelike + Timelike>(w: &mut W, t: &T) -> fmt::Result {
+    write!(
+        w,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        t.year(),
+        t.month(),
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+/// Shown fraction of second in [format_duration]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// "HH:MM:SS"
+    Seconds,
+    /// "H:MM:SS.cc"
+    Hundredths,
+}
+
+/// Formats duration as hours, minutes, seconds and optional fraction of second.
+/// Fraction is truncated, not rounded, so shown value never runs ahead of real one
+pub fn format_duration(
+    buffer: &mut [u8],
+    total_ms: u32,
+    precision: Precision,
+) -> Result<&str, Error> {
+    let total_seconds = total_ms / 1000;
+    let hours = total_seconds / (60 * 60);
+    let minutes = total_seconds / 60 % 60;
+    let seconds = total_seconds % 60;
+    let ms = total_ms % 1000;
+
+    let mut w = FormatBuffer::new(buffer);
+    match precision {
+        Precision::Seconds => write!(&mut w, "{:02}:{:02}:{:02}", hours, minutes, seconds),
+        Precision::Hundredths => write!(
+            &mut w,
+            "{}:{:02}:{:02}.{:02}",
+            hours,
+            minutes,
+            seconds,
+            ms / 10
+        ),
+    }
+    .map_err(|_| Error::Truncated)?;
+
+    Ok(w.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_has_one_decimal_place() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(format_temperature(&mut buf, 25.3), Ok("25.3С"));
+        assert_eq!(format_temperature(&mut buf, 0.0), Ok("0.0С"));
+        assert_eq!(format_temperature(&mut buf, 21.25), Ok("21.3С"));
+    }
+
+    #[test]
+    fn negative_temperature_keeps_sign() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(format_temperature(&mut buf, -0.5), Ok("-0.5С"));
+        assert_eq!(format_temperature(&mut buf, -0.04), Ok("0.0С"));
+        assert_eq!(format_temperature(&mut buf, -12.75), Ok("-12.8С"));
+    }
+
+    #[test]
+    fn temperature_reports_short_buffer() {
+        // "25.3" fits, two bytes of "С" don't
+        let mut buf = [0_u8; 5];
+        assert_eq!(format_temperature(&mut buf, 25.3), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn duration_in_seconds_is_zero_padded() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(
+            format_duration(&mut buf, 0, Precision::Seconds),
+            Ok("00:00:00")
+        );
+        assert_eq!(
+            format_duration(&mut buf, 3_723_000, Precision::Seconds),
+            Ok("01:02:03")
+        );
+    }
+
+    #[test]
+    fn duration_fits_99_hours() {
+        let max_ms = ((99 * 60 + 59) * 60 + 59) * 1000 + 990;
+        let mut buf = [0_u8; 16];
+        assert_eq!(
+            format_duration(&mut buf, max_ms, Precision::Seconds),
+            Ok("99:59:59")
+        );
+        assert_eq!(
+            format_duration(&mut buf, max_ms, Precision::Hundredths),
+            Ok("99:59:59.99")
+        );
+    }
+
+    #[test]
+    fn duration_fraction_is_truncated() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(
+            format_duration(&mut buf, 59_999, Precision::Hundredths),
+            Ok("0:00:59.99")
+        );
+        assert_eq!(
+            format_duration(&mut buf, 1_009, Precision::Hundredths),
+            Ok("0:00:01.00")
+        );
+        assert_eq!(
+            format_duration(&mut buf, 999, Precision::Seconds),
+            Ok("00:00:00")
+        );
+    }
+
+    #[test]
+    fn exact_fit_is_not_truncated() {
+        let mut buf = [0_u8; 4];
+        let mut w = FormatBuffer::new(&mut buf);
+        assert!(w.write_str("abcd").is_ok());
+        assert!(!w.truncated());
+        assert_eq!(w.as_str(), "abcd");
+    }
+
+    #[test]
+    fn one_byte_over_keeps_what_fits() {
+        let mut buf = [0_u8; 4];
+        let mut w = FormatBuffer::new(&mut buf);
+        assert!(w.write_str("abcde").is_err());
+        assert!(w.truncated());
+        assert_eq!(w.as_str(), "abcd");
+    }
+
+    #[test]
+    fn truncation_cuts_on_char_boundary() {
+        // "С" takes 2 bytes, only 1 is free
+        let mut buf = [0xFF_u8; 4];
+        let mut w = FormatBuffer::new(&mut buf);
+        assert!(w.write_str("abcС").is_err());
+        assert_eq!(w.as_str(), "abc");
+    }
+
+    #[test]
+    fn full_buffer_rejects_further_writes() {
+        let mut buf = [0_u8; 2];
+        let mut w = FormatBuffer::new(&mut buf);
+        assert!(w.write_str("ab").is_ok());
+        assert!(w.write_str("").is_ok());
+        assert!(w.write_str("c").is_err());
+        assert_eq!(w.as_str(), "ab");
+    }
+}
@@ -0,0 +1,260 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use critical_section::Mutex;
+use heapless::Vec;
+
+/// NMEA 0183 limits sentence to 82 characters with `$` and line end
+const MAX_SENTENCE_LEN: usize = 82;
+
+/// Years in RMC date are counted from this one
+const RMC_BASE_YEAR: i32 = 2000;
+
+/// Accepted PPS period. Pulse outside of it means missed pulses or noise, pending time is dropped then
+const PPS_PERIOD_MIN_US: u32 = 900_000;
+const PPS_PERIOD_MAX_US: u32 = 1_100_000;
+
+/// Monotonic timestamp of last PPS edge, µs. Wraps, only differences are used
+static LAST_PPS_US: AtomicU32 = AtomicU32::new(0);
+static PPS_SEEN: AtomicBool = AtomicBool::new(false);
+/// UTC time of next PPS edge, taken from sentence received after previous one
+static PENDING_TIME: Mutex<Cell<Option<NaiveDateTime>>> = Mutex::new(Cell::new(None));
+
+/// Handles parsed sentence time received at `now_us`
+///
+/// Receivers send time of last PPS edge after it, so it is paired with the edge if it came within
+/// one period. Next edge is one second later
+pub fn on_sentence(time: NaiveDateTime, now_us: u32) {
+    let since_pps = now_us.wrapping_sub(LAST_PPS_US.load(Ordering::Relaxed));
+    if !PPS_SEEN.load(Ordering::Relaxed) || since_pps >= PPS_PERIOD_MIN_US {
+        return;
+    }
+
+    critical_section::with(|cs| {
+        PENDING_TIME
+            .borrow(cs)
+            .set(Some(time + Duration::seconds(1)))
+    });
+}
+
+/// Handles PPS edge latched at `now_us`. Returns UTC time of this edge if it is known
+pub fn on_pps(now_us: u32) -> Option<NaiveDateTime> {
+    let period = now_us.wrapping_sub(LAST_PPS_US.swap(now_us, Ordering::Relaxed));
+    let seen = PPS_SEEN.swap(true, Ordering::Relaxed);
+
+    // Pending time is for the edge one period after previous one
+    let pending = critical_section::with(|cs| PENDING_TIME.borrow(cs).take());
+    if !seen || !(PPS_PERIOD_MIN_US..=PPS_PERIOD_MAX_US).contains(&period) {
+        return None;
+    }
+
+    pending
+}
+
+/// Collects received bytes into sentences. `$` starts new sentence, line end completes it
+pub struct SentenceBuffer {
+    buf: Vec<u8, MAX_SENTENCE_LEN>,
+    /// Bytes of current sentence are collected. Cleared on overflow until next `$`
+    active: bool,
+}
+
+impl SentenceBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            active: false,
+        }
+    }
+
+    /// Adds byte. Returns sentence without line end when it is complete
+    pub fn push(&mut self, byte: u8) -> Option<&str> {
+        match byte {
+            b'$' => {
+                self.buf.clear();
+                self.active = true;
+            }
+            b'\r' | b'\n' if self.active => {
+                self.active = false;
+                return core::str::from_utf8(&self.buf).ok();
+            }
+            // Overflowed sentence is dropped until next `$`
+            _ if self.active => self.active = self.buf.push(byte).is_ok(),
+            _ => {}
+        }
+
+        None
+    }
+}
+
+impl Default for SentenceBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses UTC time from RMC or ZDA sentence of any talker, without leading `$`
+///
+/// Returns None for other sentences, bad checksum, and RMC without fix. ZDA has no fix flag:
+/// receivers leave its fields empty until time is known
+pub fn parse_sentence(sentence: &str) -> Option<NaiveDateTime> {
+    let (body, checksum) = sentence.split_once('*')?;
+    if u8::from_str_radix(checksum, 16).ok()? != body.bytes().fold(0, |acc, b| acc ^ b) {
+        return None;
+    }
+
+    let mut fields = body.split(',');
+    let id = fields.next()?;
+    // Talker is 2 letters: GP, GN, GL...
+    match id.get(2..)? {
+        "RMC" => {
+            let time = parse_time(fields.next()?)?;
+            if fields.next()? != "A" {
+                return None;
+            }
+            // Skip latitude, longitude, speed and course
+            let date = fields.nth(6)?;
+            let day = parse_number(date.get(0..2)?)?;
+            let month = parse_number(date.get(2..4)?)?;
+            let year = RMC_BASE_YEAR + parse_number(date.get(4..6)?)? as i32;
+
+            Some(NaiveDate::from_ymd_opt(year, month, day)?.and_time(time))
+        }
+        "ZDA" => {
+            let time = parse_time(fields.next()?)?;
+            let day = parse_number(fields.next()?)?;
+            let month = parse_number(fields.next()?)?;
+            let year = parse_number(fields.next()?)? as i32;
+
+            Some(NaiveDate::from_ymd_opt(year, month, day)?.and_time(time))
+        }
+        _ => None,
+    }
+}
+
+/// Parses `hhmmss` with optional fraction. Fraction is dropped: time of PPS edge is whole second
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    let hour = parse_number(s.get(0..2)?)?;
+    let min = parse_number(s.get(2..4)?)?;
+    let sec = parse_number(s.get(4..6)?)?;
+
+    NaiveTime::from_hms_opt(hour, min, sec)
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RMC: &str = "GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+    const ZDA: &str = "GPZDA,201530.00,04,07,2002,00,00*60";
+
+    fn date_time(ymd: (i32, u32, u32), hms: (u32, u32, u32)) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(ymd.0, ymd.1, ymd.2)
+            .unwrap()
+            .and_hms_opt(hms.0, hms.1, hms.2)
+            .unwrap()
+    }
+
+    #[test]
+    fn rmc_with_fix_gives_time() {
+        // Two-digit year is counted from [RMC_BASE_YEAR]
+        assert_eq!(
+            parse_sentence(RMC),
+            Some(date_time((2094, 3, 23), (12, 35, 19)))
+        );
+    }
+
+    #[test]
+    fn rmc_without_fix_is_ignored() {
+        let no_fix = "GPRMC,123519,V,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*7D";
+        assert_eq!(parse_sentence(no_fix), None);
+    }
+
+    #[test]
+    fn zda_fraction_is_dropped() {
+        assert_eq!(
+            parse_sentence(ZDA),
+            Some(date_time((2002, 7, 4), (20, 15, 30)))
+        );
+    }
+
+    #[test]
+    fn zda_without_time_is_ignored() {
+        assert_eq!(parse_sentence("GNZDA,,,,,,*56"), None);
+    }
+
+    #[test]
+    fn bad_checksum_and_other_sentences_are_ignored() {
+        let bad_checksum = RMC.replace("*6A", "*6B");
+        assert_eq!(parse_sentence(&bad_checksum), None);
+        assert_eq!(parse_sentence(RMC.split('*').next().unwrap()), None);
+        assert_eq!(
+            parse_sentence("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"),
+            None
+        );
+    }
+
+    #[test]
+    fn sentence_buffer_collects_from_dollar_to_line_end() {
+        let mut buffer = SentenceBuffer::new();
+        let mut sentences = std::vec::Vec::new();
+        let input = std::format!("noise${}\r\n${}\r\n", RMC, ZDA);
+        for byte in input.bytes() {
+            if let Some(sentence) = buffer.push(byte) {
+                sentences.push(String::from(sentence));
+            }
+        }
+
+        assert_eq!(sentences, [RMC, ZDA]);
+    }
+
+    #[test]
+    fn overflowed_sentence_is_dropped() {
+        let mut buffer = SentenceBuffer::new();
+        buffer.push(b'$');
+        for _ in 0..MAX_SENTENCE_LEN + 1 {
+            assert_eq!(buffer.push(b'A'), None);
+        }
+        assert_eq!(buffer.push(b'\n'), None);
+
+        // Next sentence is collected again
+        for byte in "$GPZDA\r".bytes() {
+            if let Some(sentence) = buffer.push(byte) {
+                assert_eq!(sentence, "GPZDA");
+                return;
+            }
+        }
+        panic!("Sentence after overflow is lost");
+    }
+
+    /// PPS state is global, so the whole sequence is in one test
+    #[test]
+    fn sentence_time_is_applied_to_next_pps() {
+        let time = date_time((2024, 3, 9), (13, 45, 7));
+        let second = 1_000_000;
+
+        // Sentence before any PPS edge is dropped
+        on_sentence(time, 0);
+        assert_eq!(on_pps(100), None);
+
+        // Sentence after edge gives time of next one
+        on_sentence(time, 100 + 300_000);
+        assert_eq!(on_pps(100 + second), Some(time + Duration::seconds(1)));
+
+        // Pending time is taken once
+        assert_eq!(on_pps(100 + 2 * second), None);
+
+        // Edge after missed pulses drops pending time
+        on_sentence(time, 100 + 2 * second + 200_000);
+        assert_eq!(on_pps(100 + 4 * second), None);
+
+        // Sentence late after edge belongs to other edge. Counter wraps
+        on_pps(u32::MAX - 1000);
+        on_sentence(time, (u32::MAX - 1000).wrapping_add(950_000));
+        assert_eq!(on_pps((u32::MAX - 1000).wrapping_add(second)), None);
+    }
+}
@@ -1,8 +1,16 @@
-use hal::dma::{Stream0, Stream1};
-use hal::gpio::{OpenDrain, AF4, PB8, PB9};
-use hal::i2c::{dma::I2CMasterDma, Error};
-use hal::pac::{DMA1, I2C1};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use critical_section::Mutex;
+#[cfg(target_os = "none")]
+use hal::{
+    dma::{Stream0, Stream1},
+    gpio::{OpenDrain, AF4, PB8, PB9},
+    i2c::dma::I2CMasterDma,
+    pac::{DMA1, I2C1},
+};
+
+#[cfg(target_os = "none")]
 pub type I2c1Handle = I2CMasterDma<
     I2C1,
     (PB8<AF4<OpenDrain>>, PB9<AF4<OpenDrain>>),
@@ -12,22 +20,459 @@ pub type I2c1Handle = I2CMasterDma<
     1,
 >;
 
+/// How many times busy bus is polled before giving up. With backoff it is about 100ms,
+/// several times longer than full display DMA transfer
+const MAX_BUSY_ATTEMPTS: u32 = 10_000;
+/// Delay between polls of busy bus, in core cycles
+#[cfg(target_os = "none")]
+const BUSY_BACKOFF_CYCLES: u32 = 1_000;
+
+/// Set while DMA transfer owns the bus. Blocking operations wait for its end without touching the bus
+static DMA_TRANSFER: AtomicBool = AtomicBool::new(false);
+
+/// DMA1 stream of [I2c1Handle] transmit
+#[cfg(target_os = "none")]
+const TX_STREAM: usize = 1;
+
+/// First not reserved 7-bit address
+const FIRST_ADDRESS: u8 = 0x08;
+/// Last not reserved 7-bit address
+const LAST_ADDRESS: u8 = 0x77;
+
+/// Error of I2C transaction. Own type instead of HAL one, so drivers over [BlockingI2C] don't depend on HAL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Device didn't acknowledge address or data byte
+    NoAcknowledge(NoAcknowledgeSource),
+    /// Other bus fault: arbitration loss, misplaced start or stop condition, overrun or timeout
+    Bus,
+    /// Error of foreign I2C implementation of unknown kind, see [EmbeddedHalI2C]
+    Other,
+}
+
+/// Which byte wasn't acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoAcknowledgeSource {
+    Address,
+    Data,
+    /// Peripheral doesn't tell
+    Unknown,
+}
+
+#[cfg(target_os = "none")]
+impl From<hal::i2c::Error> for Error {
+    fn from(e: hal::i2c::Error) -> Self {
+        use hal::i2c::{Error as HalError, NoAcknowledgeSource as HalSource};
+
+        match e {
+            HalError::NoAcknowledge(HalSource::Address) => {
+                Error::NoAcknowledge(NoAcknowledgeSource::Address)
+            }
+            HalError::NoAcknowledge(HalSource::Data) => {
+                Error::NoAcknowledge(NoAcknowledgeSource::Data)
+            }
+            HalError::NoAcknowledge(_) => Error::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            _ => Error::Bus,
+        }
+    }
+}
+
+impl From<embedded_hal::i2c::ErrorKind> for Error {
+    fn from(kind: embedded_hal::i2c::ErrorKind) -> Self {
+        use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource as HalSource};
+
+        match kind {
+            ErrorKind::NoAcknowledge(HalSource::Address) => {
+                Error::NoAcknowledge(NoAcknowledgeSource::Address)
+            }
+            ErrorKind::NoAcknowledge(HalSource::Data) => {
+                Error::NoAcknowledge(NoAcknowledgeSource::Data)
+            }
+            ErrorKind::NoAcknowledge(_) => Error::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            ErrorKind::Bus | ErrorKind::ArbitrationLoss | ErrorKind::Overrun => Error::Bus,
+            _ => Error::Other,
+        }
+    }
+}
+
 pub trait BlockingI2C {
     fn write(&mut self, addr: u8, bytes: &[u8]) -> nb::Result<(), Error>;
     fn read(&mut self, addr: u8, buffer: &mut [u8]) -> nb::Result<(), Error>;
     fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> nb::Result<(), Error>;
 }
 
+/// Error of [BlockingI2CBus] operations
+#[derive(Debug, PartialEq, Eq)]
+pub enum BusError {
+    /// Bus stayed busy for all attempts
+    Busy,
+    /// Transaction failed
+    I2C(Error),
+}
+
+impl BusError {
+    /// Device didn't acknowledge its address: it is absent, unpowered or has other address
+    pub fn device_missing(&self) -> bool {
+        matches!(
+            self,
+            BusError::I2C(Error::NoAcknowledge(NoAcknowledgeSource::Address))
+        )
+    }
+
+    /// Device acknowledged address but rejected data: it is present but misbehaves
+    pub fn data_rejected(&self) -> bool {
+        matches!(
+            self,
+            BusError::I2C(Error::NoAcknowledge(NoAcknowledgeSource::Data))
+        )
+    }
+}
+
+/// Abort of DMA transfer, missing in HAL
+pub trait AbortDma {
+    /// Stops DMA transfer and releases the bus. Must be called in critical section with transfer in progress,
+    /// completion callback is not called
+    fn abort_dma(&mut self);
+}
+
+/// Wrappers over [BlockingI2C] that retry while bus busy with bounded attempt count
+///
+/// Implemented for shared bus instead of [BlockingI2C] itself: bus is locked only for single attempt,
+/// so DMA transfer holding bus can be finished by its interrupt between attempts
+pub trait BlockingI2CBus {
+    fn write_blocking(&self, addr: u8, bytes: &[u8]) -> Result<(), BusError>;
+    fn read_blocking(&self, addr: u8, buffer: &mut [u8]) -> Result<(), BusError>;
+    fn write_read_blocking(
+        &self,
+        addr: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), BusError>;
+}
+
+impl<I2C: BlockingI2C> BlockingI2CBus for Mutex<RefCell<I2C>> {
+    fn write_blocking(&self, addr: u8, bytes: &[u8]) -> Result<(), BusError> {
+        retry_busy(|| with_idle_bus(self, |i2c| i2c.write(addr, bytes)))
+    }
+
+    fn read_blocking(&self, addr: u8, buffer: &mut [u8]) -> Result<(), BusError> {
+        retry_busy(|| with_idle_bus(self, |i2c| i2c.read(addr, buffer)))
+    }
+
+    fn write_read_blocking(
+        &self,
+        addr: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), BusError> {
+        retry_busy(|| with_idle_bus(self, |i2c| i2c.write_read(addr, bytes, buffer)))
+    }
+}
+
+/// Marks start(or end) of DMA transfer. Must be set in the same critical section the transfer started in
+#[inline]
+pub fn set_dma_transfer(active: bool) {
+    DMA_TRANSFER.store(active, Ordering::Release);
+}
+
+/// Is DMA transfer in progress
+#[inline]
+pub fn dma_transfer() -> bool {
+    DMA_TRANSFER.load(Ordering::Acquire)
+}
+
+/// Runs `f` on locked bus, or returns [nb::Error::WouldBlock] if DMA transfer in progress
+///
+/// Flag checked under lock: transfer can't start between check and `f`
+fn with_idle_bus<I2C, T, F>(bus: &Mutex<RefCell<I2C>>, f: F) -> nb::Result<T, Error>
+where
+    F: FnOnce(&mut I2C) -> nb::Result<T, Error>,
+{
+    critical_section::with(|cs| {
+        if dma_transfer() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        f(&mut bus.borrow(cs).borrow_mut())
+    })
+}
+
+/// Repeats `f` while it returns [nb::Error::WouldBlock]
+fn retry_busy<T, F: FnMut() -> nb::Result<T, Error>>(mut f: F) -> Result<T, BusError> {
+    for _ in 0..MAX_BUSY_ATTEMPTS {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(nb::Error::Other(e)) => return Err(BusError::I2C(e)),
+            Err(nb::Error::WouldBlock) => backoff(),
+        }
+    }
+
+    Err(BusError::Busy)
+}
+
+/// Waits before next poll of busy bus
+#[inline]
+fn backoff() {
+    #[cfg(target_os = "none")]
+    cortex_m::asm::delay(BUSY_BACKOFF_CYCLES);
+    #[cfg(not(target_os = "none"))]
+    core::hint::spin_loop();
+}
+
+#[cfg(target_os = "none")]
 impl BlockingI2C for I2c1Handle {
     fn write(&mut self, addr: u8, bytes: &[u8]) -> nb::Result<(), Error> {
-        I2c1Handle::write(self, addr, bytes)
+        I2c1Handle::write(self, addr, bytes).map_err(|e| e.map(Error::from))
+    }
+
+    fn read(&mut self, addr: u8, buffer: &mut [u8]) -> nb::Result<(), Error> {
+        I2c1Handle::read(self, addr, buffer).map_err(|e| e.map(Error::from))
+    }
+
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> nb::Result<(), Error> {
+        I2c1Handle::write_read(self, addr, bytes, buffer).map_err(|e| e.map(Error::from))
+    }
+}
+
+#[cfg(target_os = "none")]
+impl AbortDma for I2c1Handle {
+    fn abort_dma(&mut self) {
+        // Safe: registers belong to this handle, which is borrowed mutably
+        let (dma, i2c) = unsafe { (&*DMA1::ptr(), &*I2C1::ptr()) };
+
+        let stream = &dma.st[TX_STREAM];
+        stream.cr.modify(|_, w| w.en().clear_bit());
+        // Stream stops after current beat
+        while stream.cr.read().en().bit_is_set() {}
+
+        // Completion may be already pending: handler must find no flags and skip callback
+        dma.lifcr.write(|w| {
+            w.ctcif1()
+                .set_bit()
+                .chtif1()
+                .set_bit()
+                .cteif1()
+                .set_bit()
+                .cdmeif1()
+                .set_bit()
+                .cfeif1()
+                .set_bit()
+        });
+
+        i2c.cr2.modify(|_, w| w.dmaen().clear_bit());
+        i2c.cr1.modify(|_, w| w.stop().set_bit());
+    }
+}
+
+/// Adapter to use any `embedded-hal` 1.0 I2C with drivers depending on [BlockingI2C]
+///
+/// Blocking implementations wait for bus themselves, so [nb::Error::WouldBlock] is never returned.
+/// Errors are taken by their kind, so missing device is still told from misbehaving one
+pub struct EmbeddedHalI2C<I>(pub I);
+
+impl<I: embedded_hal::i2c::I2c> BlockingI2C for EmbeddedHalI2C<I> {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> nb::Result<(), Error> {
+        self.0.write(addr, bytes).map_err(hal_error)
     }
 
     fn read(&mut self, addr: u8, buffer: &mut [u8]) -> nb::Result<(), Error> {
-        I2c1Handle::read(self, addr, buffer)
+        self.0.read(addr, buffer).map_err(hal_error)
     }
 
     fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> nb::Result<(), Error> {
-        I2c1Handle::write_read(self, addr, bytes, buffer)
+        self.0.write_read(addr, bytes, buffer).map_err(hal_error)
+    }
+}
+
+fn hal_error<E: embedded_hal::i2c::Error>(e: E) -> nb::Error<Error> {
+    nb::Error::Other(e.kind().into())
+}
+
+/// Probes each not reserved address with zero-length write and marks ones that acknowledged.
+/// Useful for bring-up: DS3231 is at 0x68, SSD1306 at 0x3C
+///
+/// Bus is locked for each probe only, so DMA transfer in progress can finish between them
+pub fn scan<I2C: BlockingI2C>(i2c: &Mutex<RefCell<I2C>>, found: &mut [bool; 128]) {
+    found.fill(false);
+
+    for addr in FIRST_ADDRESS..=LAST_ADDRESS {
+        found[addr as usize] = i2c.write_blocking(addr, &[]).is_ok();
+    }
+}
+
+/// Bus for host tests of drivers
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Records written bytes and answers reads with queued responses
+    #[derive(Debug, Default)]
+    pub struct MockI2c {
+        /// Address and bytes of each write, including write part of write-read
+        pub writes: Vec<(u8, Vec<u8>)>,
+        /// Responses of following reads. Read with nothing queued fails
+        pub reads: VecDeque<Vec<u8>>,
+        /// Returned by all operations instead of success if set
+        pub error: Option<Error>,
+    }
+
+    impl MockI2c {
+        /// Leaks bus with `reads` queued: drivers keep `'static` reference to bus
+        pub fn bus(reads: &[&[u8]]) -> &'static Mutex<RefCell<Self>> {
+            let mock = Self {
+                reads: reads.iter().map(|r| r.to_vec()).collect(),
+                ..Default::default()
+            };
+
+            Box::leak(Box::new(Mutex::new(RefCell::new(mock))))
+        }
+
+        /// Writes made on `bus` so far
+        pub fn writes(bus: &Mutex<RefCell<Self>>) -> Vec<(u8, Vec<u8>)> {
+            critical_section::with(|cs| bus.borrow(cs).borrow().writes.clone())
+        }
+
+        /// Makes all following operations on `bus` fail with `error`
+        pub fn fail(bus: &Mutex<RefCell<Self>>, error: Error) {
+            critical_section::with(|cs| bus.borrow(cs).borrow_mut().error = Some(error));
+        }
+
+        fn respond(&mut self, buffer: &mut [u8]) -> nb::Result<(), Error> {
+            let response = self.reads.pop_front().ok_or(nb::Error::Other(Error::Bus))?;
+            assert_eq!(response.len(), buffer.len(), "Read of unexpected length");
+            buffer.copy_from_slice(&response);
+
+            Ok(())
+        }
+    }
+
+    impl BlockingI2C for MockI2c {
+        fn write(&mut self, addr: u8, bytes: &[u8]) -> nb::Result<(), Error> {
+            if let Some(e) = self.error {
+                return Err(nb::Error::Other(e));
+            }
+
+            self.writes.push((addr, bytes.to_vec()));
+            Ok(())
+        }
+
+        fn read(&mut self, _addr: u8, buffer: &mut [u8]) -> nb::Result<(), Error> {
+            if let Some(e) = self.error {
+                return Err(nb::Error::Other(e));
+            }
+
+            self.respond(buffer)
+        }
+
+        fn write_read(
+            &mut self,
+            addr: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> nb::Result<(), Error> {
+            self.write(addr, bytes)?;
+            self.respond(buffer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::{
+        ErrorKind, ErrorType, I2c, NoAcknowledgeSource as HalSource, Operation,
+    };
+
+    use super::*;
+
+    /// `embedded-hal` I2C recording writes and answering reads with fixed byte
+    #[derive(Default)]
+    struct FakeHalI2c {
+        writes: std::vec::Vec<(u8, std::vec::Vec<u8>)>,
+        /// Returned by all transactions instead of success if set
+        error: Option<ErrorKind>,
+    }
+
+    impl ErrorType for FakeHalI2c {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeHalI2c {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if let Some(e) = self.error {
+                return Err(e);
+            }
+
+            for op in operations {
+                match op {
+                    Operation::Write(bytes) => self.writes.push((address, bytes.to_vec())),
+                    Operation::Read(buffer) => buffer.fill(0xA5),
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn adapter_passes_transactions() {
+        let mut i2c = EmbeddedHalI2C(FakeHalI2c::default());
+        let mut buffer = [0_u8; 2];
+
+        i2c.write(0x68, &[1, 2]).unwrap();
+        i2c.write_read(0x68, &[3], &mut buffer).unwrap();
+        assert_eq!(buffer, [0xA5; 2]);
+
+        assert_eq!(i2c.0.writes, [(0x68, vec![1, 2]), (0x68, vec![3])]);
+    }
+
+    #[test]
+    fn adapter_maps_error_kinds() {
+        for (kind, expected) in [
+            (
+                ErrorKind::NoAcknowledge(HalSource::Address),
+                Error::NoAcknowledge(NoAcknowledgeSource::Address),
+            ),
+            (
+                ErrorKind::NoAcknowledge(HalSource::Data),
+                Error::NoAcknowledge(NoAcknowledgeSource::Data),
+            ),
+            (
+                ErrorKind::NoAcknowledge(HalSource::Unknown),
+                Error::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            ),
+            (ErrorKind::Bus, Error::Bus),
+            (ErrorKind::ArbitrationLoss, Error::Bus),
+            (ErrorKind::Overrun, Error::Bus),
+            (ErrorKind::Other, Error::Other),
+        ] {
+            let mut i2c = EmbeddedHalI2C(FakeHalI2c {
+                error: Some(kind),
+                ..Default::default()
+            });
+            let mut buffer = [0_u8; 1];
+
+            assert_eq!(i2c.write(0x3C, &[0]), Err(nb::Error::Other(expected)));
+            assert_eq!(i2c.read(0x3C, &mut buffer), Err(nb::Error::Other(expected)));
+            assert_eq!(
+                i2c.write_read(0x3C, &[0], &mut buffer),
+                Err(nb::Error::Other(expected))
+            );
+        }
+    }
+
+    #[test]
+    fn missing_device_is_seen_through_adapter() {
+        let bus = Mutex::new(RefCell::new(EmbeddedHalI2C(FakeHalI2c {
+            error: Some(ErrorKind::NoAcknowledge(HalSource::Address)),
+            ..Default::default()
+        })));
+
+        assert!(bus.write_blocking(0x68, &[0]).unwrap_err().device_missing());
     }
 }
@@ -1,13 +1,24 @@
+#[cfg(target_os = "none")]
 use hal::gpio::{Input, Pin};
 
+/// Period of [Joystick::update] calls. [Joystick::hold_time] is counted in these intervals
+pub const UPDATE_INTERVAL_MS: u32 = 50;
+
+/// Converts duration to amount of joystick updates, so hold thresholds don't depend on update rate
+pub const fn ms_to_updates(ms: u32) -> u32 {
+    ms / UPDATE_INTERVAL_MS
+}
+
 pub trait Button {
     fn pressed(&self) -> bool;
 }
 
+#[cfg(target_os = "none")]
 pub struct ButtonPullUp<PIN> {
     pin: PIN,
 }
 
+#[cfg(target_os = "none")]
 impl<const P: char, const N: u8> ButtonPullUp<Pin<P, N, Input>> {
     pub fn new(pin: Pin<P, N, Input>) -> Self {
         let p = pin.internal_pull_up(true);
@@ -15,6 +26,7 @@ impl<const P: char, const N: u8> ButtonPullUp<Pin<P, N, Input>> {
     }
 }
 
+#[cfg(target_os = "none")]
 impl<const P: char, const N: u8> Button for ButtonPullUp<Pin<P, N, Input>> {
     fn pressed(&self) -> bool {
         self.pin.is_low()
@@ -22,7 +34,7 @@ impl<const P: char, const N: u8> Button for ButtonPullUp<Pin<P, N, Input>> {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JoystickButton {
     Up = 0,
     Down,
@@ -31,6 +43,57 @@ pub enum JoystickButton {
     Center,
 }
 
+/// Buttons ordered by value. Also priority of [Joystick::position] when several are pressed
+const BUTTONS: [JoystickButton; 5] = [
+    JoystickButton::Up,
+    JoystickButton::Down,
+    JoystickButton::Left,
+    JoystickButton::Right,
+    JoystickButton::Center,
+];
+
+/// Logical direction reported for each physical button, so shield wiring or mounting can differ without rewiring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonMapping([JoystickButton; 5]);
+
+impl ButtonMapping {
+    /// Each button reports its own direction
+    pub const IDENTITY: Self = Self(BUTTONS);
+
+    /// For upside-down mounting: directions are opposite, center is kept
+    pub const ROTATED_180: Self = Self::new([
+        JoystickButton::Down,
+        JoystickButton::Up,
+        JoystickButton::Right,
+        JoystickButton::Left,
+        JoystickButton::Center,
+    ]);
+
+    /// `directions[i]` is direction reported by physical button with value `i`
+    ///
+    /// Panics if some direction is assigned twice, at compile time when used in const
+    pub const fn new(directions: [JoystickButton; 5]) -> Self {
+        let mut assigned = 0_u8;
+        let mut i = 0;
+        while i < directions.len() {
+            assigned |= 1 << directions[i] as u8;
+            i += 1;
+        }
+        assert!(
+            assigned == 0b11111,
+            "Each direction must be assigned to one button"
+        );
+
+        Self(directions)
+    }
+}
+
+impl Default for ButtonMapping {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
 pub trait Joystick {
     /// Current joystick position
     fn position(&self) -> &Option<JoystickButton>;
@@ -41,7 +104,13 @@ pub trait Joystick {
     /// Is joystick just unpressed
     fn just_unpressed(&self) -> bool;
 
-    /// How many update intervals passed from pressing
+    /// Is `button` pressed since last update. Unlike [Joystick::clicked] works while other button held
+    fn just_pressed(&self, button: JoystickButton) -> bool;
+
+    /// Is `button` pressed now. Unlike [Joystick::position] reports every pressed button
+    fn pressed(&self, button: JoystickButton) -> bool;
+
+    /// How many update intervals passed from pressing. See [UPDATE_INTERVAL_MS]
     fn hold_time(&self) -> u32;
 
     /// Update joystick status
@@ -61,10 +130,15 @@ where
     left: L,
     right: R,
     center: C,
+    mapping: ButtonMapping,
 
     prev_position: Option<JoystickButton>,
     position: Option<JoystickButton>,
     time_wo_change: u32,
+
+    /// Bit per pressed button, indexed by [JoystickButton] value
+    prev_pressed: u8,
+    pressed: u8,
 }
 
 impl<U, D, L, R, C> AccessoryShieldJoystick<U, D, L, R, C>
@@ -75,18 +149,35 @@ where
     R: Button,
     C: Button,
 {
+    /// Creates joystick with [ButtonMapping::IDENTITY]
     pub fn new(up: U, down: D, left: L, right: R, center: C) -> Self {
+        Self::with_mapping(up, down, left, right, center, ButtonMapping::IDENTITY)
+    }
+
+    /// Creates joystick reporting directions by `mapping`. Buttons are passed by wiring of shield
+    pub fn with_mapping(
+        up: U,
+        down: D,
+        left: L,
+        right: R,
+        center: C,
+        mapping: ButtonMapping,
+    ) -> Self {
         AccessoryShieldJoystick {
             up,
             down,
             left,
             right,
             center,
+            mapping,
 
             prev_position: None,
             position: None,
 
             time_wo_change: 0,
+
+            prev_pressed: 0,
+            pressed: 0,
         }
     }
 }
@@ -111,6 +202,15 @@ where
         self.prev_position.is_some() && self.position.is_none()
     }
 
+    fn just_pressed(&self, button: JoystickButton) -> bool {
+        let mask = 1 << button as u8;
+        self.pressed & mask != 0 && self.prev_pressed & mask == 0
+    }
+
+    fn pressed(&self, button: JoystickButton) -> bool {
+        self.pressed & (1 << button as u8) != 0
+    }
+
     fn hold_time(&self) -> u32 {
         self.time_wo_change
     }
@@ -118,21 +218,22 @@ where
     fn update(&mut self) {
         self.prev_position = self.position.take();
 
-        use JoystickButton::*;
-
-        if self.up.pressed() {
-            self.position = Some(Up);
-        } else if self.down.pressed() {
-            self.position = Some(Down);
-        } else if self.left.pressed() {
-            self.position = Some(Left);
-        } else if self.right.pressed() {
-            self.position = Some(Right);
-        } else if self.center.pressed() {
-            self.position = Some(Center);
-        } else {
-            self.position = None;
-        }
+        // Position reports only one button, so each one is tracked separately too
+        self.prev_pressed = self.pressed;
+        self.pressed = [
+            self.up.pressed(),
+            self.down.pressed(),
+            self.left.pressed(),
+            self.right.pressed(),
+            self.center.pressed(),
+        ]
+        .iter()
+        .zip(self.mapping.0)
+        .fold(0, |acc, (&p, direction)| {
+            acc | ((p as u8) << direction as u8)
+        });
+
+        self.position = BUTTONS.into_iter().find(|&button| self.pressed(button));
 
         if self.prev_position != self.position {
             self.time_wo_change = 0;
@@ -141,3 +242,164 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    struct FakeButton<'a>(&'a Cell<bool>);
+
+    impl<'a> Button for FakeButton<'a> {
+        fn pressed(&self) -> bool {
+            self.0.get()
+        }
+    }
+
+    type FakeJoystick<'a> = AccessoryShieldJoystick<
+        FakeButton<'a>,
+        FakeButton<'a>,
+        FakeButton<'a>,
+        FakeButton<'a>,
+        FakeButton<'a>,
+    >;
+
+    /// Physical buttons ordered by [JoystickButton] value
+    fn joystick(buttons: &[Cell<bool>; 5], mapping: ButtonMapping) -> FakeJoystick<'_> {
+        let [up, down, left, right, center] = buttons;
+        AccessoryShieldJoystick::with_mapping(
+            FakeButton(up),
+            FakeButton(down),
+            FakeButton(left),
+            FakeButton(right),
+            FakeButton(center),
+            mapping,
+        )
+    }
+
+    #[test]
+    fn identity_reports_physical_buttons() {
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let [up, down, left, right, center] = &buttons;
+        let mut j = AccessoryShieldJoystick::new(
+            FakeButton(up),
+            FakeButton(down),
+            FakeButton(left),
+            FakeButton(right),
+            FakeButton(center),
+        );
+
+        for button in BUTTONS {
+            buttons[button as usize].set(true);
+            j.update();
+            assert_eq!(*j.position(), Some(button));
+            buttons[button as usize].set(false);
+        }
+    }
+
+    #[test]
+    fn rotated_mapping_swaps_directions() {
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let mut j = joystick(&buttons, ButtonMapping::ROTATED_180);
+
+        for (physical, reported) in [
+            (JoystickButton::Up, JoystickButton::Down),
+            (JoystickButton::Down, JoystickButton::Up),
+            (JoystickButton::Left, JoystickButton::Right),
+            (JoystickButton::Right, JoystickButton::Left),
+            (JoystickButton::Center, JoystickButton::Center),
+        ] {
+            buttons[physical as usize].set(true);
+            j.update();
+            assert_eq!(*j.position(), Some(reported));
+            assert!(j.pressed(reported));
+            buttons[physical as usize].set(false);
+        }
+    }
+
+    #[test]
+    fn custom_mapping_is_accepted() {
+        let mapping = ButtonMapping::new([
+            JoystickButton::Left,
+            JoystickButton::Right,
+            JoystickButton::Down,
+            JoystickButton::Up,
+            JoystickButton::Center,
+        ]);
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let mut j = joystick(&buttons, mapping);
+
+        buttons[JoystickButton::Up as usize].set(true);
+        j.update();
+        assert_eq!(*j.position(), Some(JoystickButton::Left));
+    }
+
+    #[test]
+    #[should_panic(expected = "Each direction must be assigned to one button")]
+    fn duplicate_direction_panics() {
+        ButtonMapping::new([
+            JoystickButton::Up,
+            JoystickButton::Up,
+            JoystickButton::Left,
+            JoystickButton::Right,
+            JoystickButton::Center,
+        ]);
+    }
+
+    #[test]
+    fn click_and_hold_are_tracked() {
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let mut j = joystick(&buttons, ButtonMapping::default());
+        let center = &buttons[JoystickButton::Center as usize];
+
+        center.set(true);
+        j.update();
+        assert!(j.clicked());
+        assert!(j.just_pressed(JoystickButton::Center));
+
+        j.update();
+        j.update();
+        assert!(!j.clicked());
+        assert_eq!(j.hold_time(), 2);
+
+        center.set(false);
+        j.update();
+        assert!(j.just_unpressed());
+        assert_eq!(*j.position(), None);
+    }
+
+    #[test]
+    fn press_while_other_button_held_is_seen() {
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let mut j = joystick(&buttons, ButtonMapping::default());
+
+        buttons[JoystickButton::Up as usize].set(true);
+        j.update();
+        buttons[JoystickButton::Center as usize].set(true);
+        j.update();
+
+        // Position still reports held Up, so it is not a click
+        assert_eq!(*j.position(), Some(JoystickButton::Up));
+        assert!(!j.clicked());
+        assert!(j.just_pressed(JoystickButton::Center));
+        assert!(!j.just_pressed(JoystickButton::Up));
+
+        j.update();
+        assert!(!j.just_pressed(JoystickButton::Center));
+        assert!(j.pressed(JoystickButton::Center));
+    }
+
+    #[test]
+    fn position_takes_first_of_pressed_buttons() {
+        let buttons: [Cell<bool>; 5] = Default::default();
+        let mut j = joystick(&buttons, ButtonMapping::default());
+
+        buttons[JoystickButton::Center as usize].set(true);
+        buttons[JoystickButton::Left as usize].set(true);
+        j.update();
+
+        assert_eq!(*j.position(), Some(JoystickButton::Left));
+        assert!(j.pressed(JoystickButton::Center));
+    }
+}
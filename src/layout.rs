@@ -0,0 +1,173 @@
+//! Screen regions shared by all states, so layout is changed in one place. Points are text baselines
+
+use embedded_graphics::{
+    mono_font::MonoFont,
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+
+pub const SCREEN_WIDTH: u32 = 128;
+/// Horizontal center of screen
+pub const CENTER_X: i32 = SCREEN_WIDTH as i32 / 2;
+
+/// Header, centered
+pub const HEADER: Point = Point::new(CENTER_X, 10);
+/// Main value of state, e.g. time. Centered, left and right navigation icons are beside it
+pub const CONTENT: Point = Point::new(CENTER_X, 34);
+/// Line below content, e.g. date. Centered, shares space with upper hint row
+pub const SUBLINE: Point = Point::new(CENTER_X, 51);
+
+/// Upper hint row. Icon is drawn left of text
+pub const HINT_UPPER: Point = Point::new(20, 46);
+/// Lower hint row, usually Center button. Icon is drawn left of text
+pub const HINT_LOWER: Point = Point::new(20, 56);
+/// Second hint in lower row
+pub const HINT_LOWER_RIGHT: Point = Point::new(76, 56);
+/// Short status in lower row, right aligned. Baseline matches hint text
+pub const STATUS_LOWER_RIGHT: Point = Point::new(SCREEN_WIDTH as i32 - 1, 59);
+
+/// Text of hint is placed right of its icon, see [HINT_UPPER]
+pub const HINT_TEXT_OFFSET: Point = Point::new(6, 3);
+
+/// Cell of content font. Layout assumes default 9x15 font, see `AppSharedState::new`
+pub const CONTENT_CHAR: Size = Size::new(9, 15);
+/// Baseline of content font from top of its cell
+pub const CONTENT_BASELINE: i32 = 11;
+/// Top row of content text
+const CONTENT_TOP: i32 = CONTENT.y - CONTENT_BASELINE;
+
+/// Navigation icons span this far from their center
+pub const ICON_HALF: i32 = 3;
+/// Center of arrow above edited field of content, just over its cell
+pub const FIELD_ABOVE: i32 = CONTENT_TOP - ICON_HALF - 1;
+/// Center of arrow below edited field of content. Its top row is the last row of cell, empty below digits
+pub const FIELD_BELOW: i32 = CONTENT_TOP + CONTENT_CHAR.height as i32 + ICON_HALF - 1;
+/// Pixels between content and suffix drawn after it, e.g. AM/PM
+const SUFFIX_GAP: i32 = 3;
+
+/// Left edge of centered content of `chars` characters
+pub const fn content_left(chars: u32) -> i32 {
+    CENTER_X - (chars * CONTENT_CHAR.width / 2) as i32
+}
+
+/// Center of two-digit `field` of centered content of `chars` characters, e.g. minutes of "HH:MM:SS" are
+/// field 1. Fields are split by one character separator
+pub const fn field_x(chars: u32, field: u32) -> i32 {
+    let width = CONTENT_CHAR.width as i32;
+    content_left(chars) + field as i32 * 3 * width + width - 1
+}
+
+/// Frame around edited field at [field_x], shown when its value is clamped
+pub fn field_frame(x: i32) -> Rectangle {
+    let width = CONTENT_CHAR.width;
+    Rectangle::new(
+        Point::new(x - width as i32 - 1, CONTENT_TOP - 1),
+        Size::new(2 * width + 2, CONTENT_CHAR.height + 2),
+    )
+}
+
+/// Baseline of suffix after centered content of `chars` characters
+pub const fn content_suffix(chars: u32) -> Point {
+    Point::new(
+        content_left(chars) + (chars * CONTENT_CHAR.width) as i32 + SUFFIX_GAP,
+        CONTENT.y,
+    )
+}
+
+/// Spaces between end of scrolling text and its start
+pub const SCROLL_GAP: usize = 3;
+
+/// Width of `text` in pixels drawn with `font`. Counts characters, not bytes: Cyrillic takes 2 bytes in UTF-8
+pub fn text_width(text: &str, font: &MonoFont) -> u32 {
+    let chars = text.chars().count() as u32;
+    if chars == 0 {
+        return 0;
+    }
+
+    chars * font.character_size.width + (chars - 1) * font.character_spacing
+}
+
+/// How many characters of `font` fit in `width` pixels
+pub fn chars_fitting(width: u32, font: &MonoFont) -> usize {
+    let step = font.character_size.width + font.character_spacing;
+    ((width + font.character_spacing) / step) as usize
+}
+
+/// Characters of `text` scrolled by `offset` characters, `visible` of them. Text is followed by [SCROLL_GAP]
+/// spaces before it starts again
+pub fn scroll_window(text: &str, visible: usize, offset: usize) -> impl Iterator<Item = char> + '_ {
+    let round = text.chars().count() + SCROLL_GAP;
+    text.chars()
+        .chain(core::iter::repeat_n(' ', SCROLL_GAP))
+        .cycle()
+        .skip(offset % round)
+        .take(visible)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::mono_font::iso_8859_5::{FONT_6X10, FONT_9X15_BOLD};
+
+    #[test]
+    fn width_counts_chars_not_bytes() {
+        assert_eq!(text_width("", &FONT_9X15_BOLD), 0);
+        assert_eq!(text_width("СЕКУНДОМЕР", &FONT_9X15_BOLD), 90);
+        assert_eq!(text_width("STOPWATCH", &FONT_9X15_BOLD), 81);
+        assert_eq!(text_width("ДИАГНОСТИКА", &FONT_6X10), 66);
+    }
+
+    #[test]
+    fn fitting_chars_match_screen_width() {
+        assert_eq!(chars_fitting(SCREEN_WIDTH, &FONT_9X15_BOLD), 14);
+        assert_eq!(chars_fitting(SCREEN_WIDTH, &FONT_6X10), 21);
+
+        let fitting = "Ж".repeat(14);
+        assert!(text_width(&fitting, &FONT_9X15_BOLD) <= SCREEN_WIDTH);
+        assert!(text_width(&(fitting + "Ж"), &FONT_9X15_BOLD) > SCREEN_WIDTH);
+    }
+
+    #[test]
+    fn content_metrics_match_default_font() {
+        assert_eq!(FONT_9X15_BOLD.character_size, CONTENT_CHAR);
+        assert_eq!(FONT_9X15_BOLD.baseline as i32, CONTENT_BASELINE);
+    }
+
+    /// Positions screens used before they were derived here
+    #[test]
+    fn edit_selectors_keep_baseline_positions() {
+        assert_eq!((FIELD_ABOVE, FIELD_BELOW), (19, 40));
+        // Alarm "HH:MM"
+        assert_eq!((field_x(5, 0), field_x(5, 1)), (50, 77));
+        // Clock and timer "HH:MM:SS". Minutes and seconds were placed at 64 and 92 by hand, 1 and 2 px
+        // right of digit centers
+        assert_eq!(field_x(8, 0), 36);
+        assert_eq!((field_x(8, 1), field_x(8, 2)), (63, 90));
+
+        let frame = field_frame(36);
+        assert_eq!(frame.top_left, Point::new(26, 22));
+        assert_eq!(frame.size, Size::new(20, 17));
+
+        assert_eq!(HINT_UPPER + HINT_TEXT_OFFSET, Point::new(26, 49));
+        assert_eq!(HINT_LOWER + HINT_TEXT_OFFSET, Point::new(26, 59));
+        assert_eq!(content_suffix(8), Point::new(103, 34));
+    }
+
+    fn window(text: &str, visible: usize, offset: usize) -> String {
+        scroll_window(text, visible, offset).collect()
+    }
+
+    #[test]
+    fn scroll_starts_from_text_start() {
+        assert_eq!(window("НАСТРОЙКИ", 4, 0), "НАСТ");
+        assert_eq!(window("НАСТРОЙКИ", 4, 2), "СТРО");
+    }
+
+    #[test]
+    fn scroll_shows_gap_before_next_round() {
+        assert_eq!(window("АБВГ", 5, 2), "ВГ   ");
+        assert_eq!(window("АБВГ", 5, 4), "   АБ");
+        // Round is text and gap
+        assert_eq!(window("АБВГ", 5, 4 + SCROLL_GAP), window("АБВГ", 5, 0));
+    }
+}
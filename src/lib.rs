@@ -0,0 +1,71 @@
+//! Drivers and logic independent of RTIC app. Parts using MCU peripherals are built for MCU only,
+//! so the rest builds for host too and is tested there: `cargo test-host`
+#![cfg_attr(not(test), no_std)]
+
+/// HAL library for our device
+#[cfg(target_os = "none")]
+extern crate stm32f4xx_hal as hal;
+
+/// I2C that can use DMA
+pub mod i2c;
+
+/// RTC
+pub mod ds3231;
+
+/// SSD1306 driver
+pub mod ssd1306;
+
+/// Joystick driver
+pub mod joystick;
+
+/// Stopwatch abstraction for Timer
+pub mod stopwatchtimer;
+
+/// Countdown timer to implement timer
+#[cfg(target_os = "none")]
+pub mod countdowntimer;
+
+/// Buzzer to make sounds
+#[cfg(target_os = "none")]
+pub mod buzzer;
+
+/// Control changing speed of digits
+pub mod speedchanger;
+
+/// EEPROM for settings storage
+pub mod eeprom24;
+
+/// CRC-32 of hardware CRC unit
+pub mod crc;
+
+/// Settings persisted across power cycles
+pub mod settings;
+
+/// Text formatting helpers without heap and float formatting
+pub mod format;
+
+/// Screen regions shared by app states
+pub mod layout;
+
+/// Value handed over between owners
+pub mod slot;
+
+/// Battery voltage monitor
+#[cfg(target_os = "none")]
+pub mod battery;
+
+/// Line commands over serial port
+#[cfg(any(feature = "commands", test))]
+pub mod command;
+
+/// Time from GPS receiver
+#[cfg(any(feature = "gps", test))]
+pub mod gps;
+
+/// Stopwatch kept across resets
+#[cfg(any(feature = "stopwatch-backup", test))]
+pub mod stopwatchbackup;
+
+/// Panic message on display instead of silent halt
+#[cfg(all(feature = "panic-display", target_os = "none"))]
+pub mod panic_display;
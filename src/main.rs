@@ -12,35 +12,29 @@ extern crate stm32f4xx_hal as hal;
 /// Peripheral Access Crate for our device
 pub use hal::pac;
 
-/// I2C that can use DMA
-mod i2c;
-
-/// RTC
-mod ds3231;
-
-/// SSD1306 driver
-mod ssd1306;
-
-/// Joystick driver
-mod joystick;
-
-/// Stopwatch abstraction for Timer
-mod stopwatchtimer;
-
-/// Countdown timer to implement timer
-mod countdowntimer;
-
-/// Buzzer to make sounds
-mod buzzer;
-
-/// Control changing speed of digits
-mod speedchanger;
+use dp_rust::{
+    battery, buzzer, countdowntimer, crc, ds3231, eeprom24, format, i2c, joystick, layout,
+    settings, slot, speedchanger, ssd1306, stopwatchtimer,
+};
+
+#[cfg(feature = "commands")]
+use dp_rust::command;
+#[cfg(feature = "gps")]
+use dp_rust::gps;
+#[cfg(feature = "panic-display")]
+use dp_rust::panic_display;
+#[cfg(feature = "stopwatch-backup")]
+use dp_rust::stopwatchbackup;
+
+/// Time since boot
+mod uptime;
 
 mod app_state;
 
+#[cfg(not(feature = "panic-display"))]
 use panic_halt as _;
 
-#[rtic::app(device = crate::pac, peripherals = true, dispatchers = [USART6, SPI5, SPI4])]
+#[rtic::app(device = crate::pac, peripherals = true, dispatchers = [USART6, SPI5, SPI4, SPI3])]
 mod app {
 
     // Standart library imports
@@ -54,8 +48,10 @@ mod app {
     use hal::gpio::*;
     use hal::prelude::*;
     use hal::timer::MonoTimerUs;
+    use hal::watchdog::IndependentWatchdog;
 
     // External helpers libraries
+    use chrono::Timelike;
     use critical_section::Mutex;
     use embedded_graphics::pixelcolor::BinaryColor;
     use embedded_graphics::prelude::*;
@@ -63,15 +59,20 @@ mod app {
 
     // This crate exports
     use crate::app_state::prelude::*;
+    use crate::battery::BatteryMonitor;
     use crate::buzzer::Buzzer;
-    use crate::ds3231::DS3231;
+    use crate::crc::HardwareCrc32;
+    use crate::ds3231::{self, DS3231};
+    use crate::eeprom24::Eeprom24;
     use crate::i2c::I2c1Handle;
     use crate::joystick::*;
-    use crate::ssd1306::SSD1306;
+    use crate::settings::*;
+    use crate::ssd1306::{FRAME_BYTES, SSD1306};
 
     // Type defs
     pub type StopwatchTimer = crate::stopwatchtimer::StopwatchTimer<crate::pac::TIM2>;
-    pub type CountdownTimer = crate::countdowntimer::CountdownTimer<crate::pac::TIM4>;
+    pub type CountdownTimer =
+        crate::countdowntimer::CountdownTimer<crate::pac::TIM4, COUNTDOWN_TIMER_FREQ>;
     pub type I2c1HandleProtected = Mutex<RefCell<I2c1Handle>>;
 
     pub type UpButton = ButtonPullUp<PA1>;
@@ -83,17 +84,89 @@ mod app {
     pub type JoystickImpl =
         AccessoryShieldJoystick<UpButton, DownButton, LeftButton, RightButton, CenterButton>;
 
+    /// Stopwatch hardware counter period. TIM2 is 32-bit, so long period is possible and means rare interrupts
+    const STOPWATCH_PERIOD_MS: u32 = 1000;
+    /// Countdown hardware counter frequency
+    const COUNTDOWN_TIMER_FREQ: u32 = 2000;
+    /// Longest countdown which can be set in timer edit: 99:59:59
+    const COUNTDOWN_MAX_SECONDS: u32 = crate::countdowntimer::MAX_COUNTDOWN;
+
+    /// I2C bus clock. STM32F411 I2C peripheral supports Standard and Fast mode only, so 400 kHz is the maximum:
+    /// Fast-mode Plus is not available
+    const I2C_SPEED_KHZ: u32 = 400;
+    /// Interval between frames
+    const DRAW_INTERVAL_MS: u32 = 100;
+    /// Time of frame transfer to display in ms, rounded up. Each byte takes 9 clocks with ACK, plus address byte
+    const FRAME_TRANSFER_MS: u32 =
+        ((FRAME_BYTES as u32 + 1) * 9 + I2C_SPEED_KHZ - 1) / I2C_SPEED_KHZ;
+    // Blocking RTC and EEPROM access waits for frame transfer, so at least half of each draw interval
+    // must be left for them. It is ~24 ms of 100 ms at 400 kHz
+    const _: () = assert!(
+        FRAME_TRANSFER_MS <= DRAW_INTERVAL_MS / 2,
+        "Frame transfer takes too much of draw interval: lower draw cadence or raise I2C speed"
+    );
+    /// Frames dropped in a row after which frame transfer is taken as stuck and aborted. Ten draw intervals
+    /// are far longer than [FRAME_TRANSFER_MS], so only lost completion interrupt gets there
+    const STUCK_TRANSFER_FRAMES: u32 = 10;
+
+    /// Directions reported by joystick buttons. [ButtonMapping::ROTATED_180] suits upside-down mounting
+    const JOYSTICK_MAPPING: ButtonMapping = ButtonMapping::IDENTITY;
+
+    /// How many times display initialization is tried on boot
+    const DISPLAY_INIT_ATTEMPTS: u32 = 3;
+
+    /// How long boot screen is shown
+    const SPLASH_MS: u32 = 1000;
+
+    /// Duration of key click
+    const KEY_CLICK_MS: u32 = 15;
+
+    /// Night hours are checked this often. Brightness changes within a minute after night start or end
+    const AUTO_DIM_PERIOD_S: u32 = 60;
+
+    /// Diagnostics screen info is read this often while shown
+    const DIAGNOSTICS_PERIOD_S: u32 = 2;
+
+    /// RTC converts temperature once per 64 seconds, reading more often gives nothing new
+    const TEMPERATURE_PERIOD_S: u32 = 64;
+
+    /// Telemetry line period
+    #[cfg(feature = "telemetry")]
+    const TELEMETRY_PERIOD_S: u32 = 10;
+    /// Baud rate of telemetry and commands. USART2 is wired to ST-LINK virtual COM port on Nucleo boards
+    #[cfg(any(feature = "telemetry", feature = "commands"))]
+    const SERIAL_BAUD: u32 = 115_200;
+
+    /// NMEA baud rate of most GPS receivers
+    #[cfg(feature = "gps")]
+    const GPS_BAUD: u32 = 9600;
+    /// RTC is written from GPS on first fix and then this often. Each write aligns RTC second to PPS edge
+    #[cfg(feature = "gps")]
+    const GPS_SYNC_PERIOD_S: u32 = 3600;
+
+    /// Watchdog resets MCU if not fed by [`tick`] during this time
+    const WATCHDOG_TIMEOUT_MS: u32 = 3000;
+
     #[shared]
     struct Shared {
         app_state: RwLock<AppStateHolder>,
 
         i2c: &'static I2c1HandleProtected,
+
+        /// Used in [`change_state`] and [`factory_reset`]
+        settings_store: SettingsStore<I2c1Handle, HardwareCrc32>,
+
+        /// Used in [`telemetry`] and [`handle_command`]
+        #[cfg(any(feature = "telemetry", feature = "commands"))]
+        serial_tx: hal::serial::Tx<crate::pac::USART2>,
     }
 
     #[local]
     struct Local {
         /// indicate work of plate. Used in `tick`
         led: PA5<Output>,
+        /// Fed in `tick`
+        watchdog: IndependentWatchdog,
 
         /// Used in [`draw`]
         display: SSD1306<'static, PA8<Output<PushPull>>, I2c1Handle>,
@@ -105,6 +178,46 @@ mod app {
         stopwatch: &'static StopwatchTimer,
         /// Countdown
         countdown: &'static CountdownTimer,
+        /// Used in [`key_click`]
+        buzzer: &'static Buzzer,
+
+        /// Used in [`battery_monitor`]
+        battery: BatteryMonitor,
+
+        /// Used in [`auto_dim`]
+        rtc: DS3231<I2c1Handle>,
+        /// Used in [`read_temperature`]
+        temperature_rtc: DS3231<I2c1Handle>,
+        /// Used in [`diagnose`]
+        diagnostics_rtc: DS3231<I2c1Handle>,
+
+        /// Used in [`telemetry`]
+        #[cfg(feature = "telemetry")]
+        telemetry_rtc: DS3231<I2c1Handle>,
+
+        /// Used in [`serial_rx`]
+        #[cfg(feature = "commands")]
+        serial_rx: hal::serial::Rx<crate::pac::USART2>,
+        /// Used in [`handle_command`]
+        #[cfg(feature = "commands")]
+        command_rtc: DS3231<I2c1Handle>,
+
+        /// Used in [`gps_rx`]
+        #[cfg(feature = "gps")]
+        gps_rx: hal::serial::Rx<crate::pac::USART1>,
+        /// Used in [`gps_pps`]
+        #[cfg(feature = "gps")]
+        gps_pps: PB4<Input>,
+        /// Used in [`gps_set_rtc`]
+        #[cfg(feature = "gps")]
+        gps_rtc: DS3231<I2c1Handle>,
+
+        /// Used in [`backup_stopwatch`]
+        #[cfg(feature = "stopwatch-backup")]
+        stopwatch_backup: crate::stopwatchbackup::StopwatchBackup<I2c1Handle>,
+        /// Used in [`backup_stopwatch`]
+        #[cfg(feature = "stopwatch-backup")]
+        backed_stopwatch: &'static StopwatchTimer,
     }
 
     #[monotonic(binds = TIM5, default = true)]
@@ -115,11 +228,15 @@ mod app {
     /// * Configures clocks to 100 MHz
     /// * Configures PA5(User LED) for tick indication
     /// * Creates I2C bus, display, RTC
+    /// * Shows boot screen
     /// * Configures joystick
+    /// * Configures ADC for battery monitor
+    /// * Starts watchdog
     /// * Starts repeating tasks
     #[init(local = [
         _stopwatch: Option<StopwatchTimer> = None,
         _countdown: Option<CountdownTimer> = None,
+        _buzzer: Option<Buzzer> = None,
         _i2c_bus: Option<I2c1HandleProtected> = None,
     ])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
@@ -128,20 +245,34 @@ mod app {
 
         let rcc = dp.RCC.constrain();
         let clocks = rcc.cfgr.use_hse(8.MHz()).sysclk(100.MHz()).freeze();
+        #[cfg(feature = "panic-display")]
+        crate::panic_display::set_pclk1(clocks.pclk1().raw());
 
         // Timers
         let mono = dp.TIM5.monotonic_us(&clocks);
 
         let gpioa = dp.GPIOA.split();
-        let buzzer = Buzzer::new(dp.TIM3, gpioa.pa7, &clocks);
+        *ctx.local._buzzer = Some(Buzzer::new(
+            dp.TIM3,
+            gpioa.pa7,
+            AppSharedState::volume_source(),
+            &clocks,
+        ));
+        let buzzer_ref = ctx.local._buzzer.as_ref().unwrap();
 
-        *ctx.local._stopwatch = Some(StopwatchTimer::new(dp.TIM2, hal::interrupt::TIM2, &clocks));
+        *ctx.local._stopwatch = Some(StopwatchTimer::new(
+            dp.TIM2,
+            hal::interrupt::TIM2,
+            STOPWATCH_PERIOD_MS,
+            &clocks,
+        ));
         let stopwatch_ref = ctx.local._stopwatch.as_ref().unwrap();
 
         *ctx.local._countdown = Some(CountdownTimer::new(
             dp.TIM4,
             hal::interrupt::TIM4,
-            buzzer,
+            buzzer_ref,
+            AppSharedState::alarm_escalation_source(),
             &clocks,
         ));
         let countdown_ref = ctx.local._countdown.as_ref().unwrap();
@@ -157,7 +288,7 @@ mod app {
                 gpiob.pb8.into_alternate_open_drain(),
                 gpiob.pb9.into_alternate_open_drain(),
             ),
-            400.kHz(),
+            I2C_SPEED_KHZ.kHz(),
             &clocks,
         );
 
@@ -169,11 +300,24 @@ mod app {
         let i2c_bus_ref = ctx.local._i2c_bus.as_ref().unwrap();
 
         // Display and sensors
+        // Failures are not fatal: device is still usable as far as possible
         let mut display = SSD1306::new(gpioa.pa8.into_push_pull_output(), i2c_bus_ref);
-        display.init().expect("Display init failure");
+        let display_ready = (0..DISPLAY_INIT_ATTEMPTS).any(|_| display.init().is_ok());
+
+        // Sent without DMA: its interrupt is not served until init returns, bus would stay busy for RTC and EEPROM.
+        // Watchdog is not started yet, so the wait can't reset MCU
+        if display_ready {
+            draw_splash(&mut display).ok();
+            if display.swap_blocking().is_ok() {
+                cortex_m::asm::delay(clocks.sysclk().raw() / 1000 * SPLASH_MS);
+            }
+        }
 
+        // Clock state reports RTC absence itself
         let rtc = DS3231::new(i2c_bus_ref);
-        rtc.update_time().unwrap();
+
+        let mut settings_store =
+            SettingsStore::new(Eeprom24::new(i2c_bus_ref), HardwareCrc32::new(dp.CRC));
 
         // Configure buttons
         let gpioc = dp.GPIOC.split();
@@ -184,35 +328,146 @@ mod app {
         let right = ButtonPullUp::new(gpioa.pa4.into_pull_up_input());
         let center = ButtonPullUp::new(gpioc.pc1.into_pull_up_input());
 
-        let joy = AccessoryShieldJoystick::new(up, down, left, right, center);
-
-        let clock_state = ClockState::new(rtc);
+        let joy =
+            AccessoryShieldJoystick::with_mapping(up, down, left, right, center, JOYSTICK_MAPPING);
+
+        // Battery
+        let battery = BatteryMonitor::new(dp.ADC1, gpioa.pa0.into_analog());
+
+        // Serial port. Responses are sent by blocking write, only received bytes use interrupt
+        #[cfg(any(feature = "telemetry", feature = "commands"))]
+        let serial_config = hal::serial::config::Config::default().baudrate(SERIAL_BAUD.bps());
+        #[cfg(all(feature = "telemetry", not(feature = "commands")))]
+        let serial_tx = dp
+            .USART2
+            .tx(gpioa.pa2.into_alternate(), serial_config, &clocks)
+            .unwrap();
+        #[cfg(feature = "commands")]
+        let (serial_tx, serial_rx) = {
+            let mut serial = dp
+                .USART2
+                .serial(
+                    (gpioa.pa2.into_alternate(), gpioa.pa3.into_alternate()),
+                    serial_config,
+                    &clocks,
+                )
+                .unwrap();
+            serial.listen(hal::serial::Event::Rxne);
+            serial.split()
+        };
+
+        // GPS. Without fix there are no valid sentences, usually no PPS too: RTC keeps running on its own
+        #[cfg(feature = "gps")]
+        let (gps_rx, gps_pps) = {
+            let mut gps_rx = dp
+                .USART1
+                .rx(
+                    gpioa.pa10.into_alternate(),
+                    hal::serial::config::Config::default().baudrate(GPS_BAUD.bps()),
+                    &clocks,
+                )
+                .unwrap();
+            gps_rx.listen();
+
+            let mut syscfg = dp.SYSCFG.constrain();
+            let mut exti = dp.EXTI;
+            let mut gps_pps = gpiob.pb4.into_floating_input();
+            gps_pps.make_interrupt_source(&mut syscfg);
+            gps_pps.trigger_on_edge(&mut exti, Edge::Rising);
+            gps_pps.enable_interrupt(&mut exti);
+
+            (gps_rx, gps_pps)
+        };
+
+        let alarm_state = AlarmState::new(rtc.clone(), buzzer_ref);
+        let clock_state = ClockState::new(rtc.clone());
         let stopwatch_state = StopwatchState::new(stopwatch_ref);
-        let timer_state = TimerState::new(countdown_ref);
+        let timer_state = TimerState::new(countdown_ref, COUNTDOWN_MAX_SECONDS);
+        let settings_state = SettingsState::new();
+        let diagnostics_state = DiagnosticsState::new();
 
-        let app_state = RwLock::new(AppStateHolder::new(
+        let mut app_state_holder = AppStateHolder::new(
             clock_state,
-            timer_state,
+            alarm_state,
             stopwatch_state,
+            timer_state,
+            settings_state,
+            diagnostics_state,
             AppSharedState::default(),
-        ));
+        );
+
+        // Restore last state. Missing EEPROM or empty blob leave defaults
+        if let Some(settings) = settings_store.load() {
+            app_state_holder.restore(&settings);
+        }
+
+        // Stopwatch continues counting from its start time by RTC. Without RTC or backup it stays reset
+        #[cfg(feature = "stopwatch-backup")]
+        let stopwatch_backup = {
+            let mut backup = crate::stopwatchbackup::StopwatchBackup::new(
+                Eeprom24::new(i2c_bus_ref),
+                rtc.clone(),
+            );
+            if let Err(ds3231::Error::Bus(_)) = backup.restore(stopwatch_ref) {
+                AppSharedState::report_i2c_error();
+            }
+            backup
+        };
+
+        let app_state = RwLock::new(app_state_holder);
+
+        // Watchdog started last: init itself may take longer than timeout
+        // Stop it while core halted by debugger
+        dp.DBGMCU.apb1_fz.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+        watchdog.start(WATCHDOG_TIMEOUT_MS.millis());
 
         // Spawn repeating tasks
         draw::spawn().unwrap();
         handle_input::spawn().unwrap();
         tick::spawn().unwrap();
+        battery_monitor::spawn().unwrap();
+        auto_dim::spawn().unwrap();
+        read_temperature::spawn().unwrap();
+        #[cfg(feature = "telemetry")]
+        telemetry::spawn().unwrap();
 
         (
             Shared {
                 app_state,
                 i2c: i2c_bus_ref,
+                settings_store,
+                #[cfg(any(feature = "telemetry", feature = "commands"))]
+                serial_tx,
             },
             Local {
                 led,
+                watchdog,
                 display,
                 joy,
                 stopwatch: stopwatch_ref,
                 countdown: countdown_ref,
+                buzzer: buzzer_ref,
+                battery,
+                temperature_rtc: rtc.clone(),
+                diagnostics_rtc: rtc.clone(),
+                #[cfg(feature = "telemetry")]
+                telemetry_rtc: rtc.clone(),
+                #[cfg(feature = "commands")]
+                serial_rx,
+                #[cfg(feature = "commands")]
+                command_rtc: rtc.clone(),
+                #[cfg(feature = "gps")]
+                gps_rx,
+                #[cfg(feature = "gps")]
+                gps_pps,
+                #[cfg(feature = "gps")]
+                gps_rtc: rtc.clone(),
+                #[cfg(feature = "stopwatch-backup")]
+                stopwatch_backup,
+                #[cfg(feature = "stopwatch-backup")]
+                backed_stopwatch: stopwatch_ref,
+                rtc,
             },
             init::Monotonics(mono),
         )
@@ -220,6 +475,11 @@ mod app {
 
     /// Idle function runs when nothing to do
     /// Used for sleep
+    ///
+    /// Only Sleep mode is used. Stop mode halts every clock except LSI/LSE, including TIM5 used
+    /// as monotonic, so scheduled tasks(`tick`, `draw`, `handle_input`) would never wake the core
+    /// and IWDG(running from LSI) would reset it. Stop mode needs EXTI-based wake sources(RTC
+    /// wakeup timer, buttons) and LPTIM/RTC-based monotonic first
     #[idle(local = [], shared = [])]
     fn idle(_ctx: idle::Context) -> ! {
         loop {
@@ -228,20 +488,27 @@ mod app {
     }
 
     /// tick is top-priority task. It updates clock without sync with real RTC module
-    #[task(local = [led], shared=[&app_state], priority = 5)]
+    ///
+    /// Uptime seconds are counted before state lock: clock time is derived from them, so tick skipped
+    /// due busy lock delays displayed second, but never loses it
+    ///
+    /// Watchdog fed only when app state is readable, so lock held for too long resets MCU
+    #[task(local = [led, watchdog], shared=[&app_state], priority = 5)]
     fn tick(ctx: tick::Context) {
         tick::spawn_after(1000.millis()).unwrap();
         ctx.local.led.toggle();
+        crate::uptime::count_second();
 
         if let Some(s) = ctx.shared.app_state.try_read() {
             s.tick();
+            ctx.local.watchdog.feed();
         }
     }
 
     /// handle_input handles joystick
     #[task(local = [joy], shared = [&app_state], priority = 3)]
     fn handle_input(ctx: handle_input::Context) {
-        let update_interval = 50.millis();
+        let update_interval = UPDATE_INTERVAL_MS.millis();
         handle_input::spawn_after(update_interval).unwrap();
 
         let j = ctx.local.joy;
@@ -253,35 +520,440 @@ mod app {
     }
 
     /// Draw task draws content of `display_info` onto screen
-    #[task(local = [display], shared = [&app_state], priority = 1, capacity = 1)]
+    ///
+    /// Frame is skipped if state version not changed since last sent frame
+    #[task(local = [display, brightness: Option<u8> = None, display_on: Option<bool> = None, last_version: Option<u32> = None, dropped_frames: u32 = 0], shared = [&app_state], priority = 1, capacity = 1)]
     fn draw(ctx: draw::Context) {
-        draw::spawn_after(100.millis()).ok();
+        draw::spawn_after(DRAW_INTERVAL_MS.millis()).ok();
 
         let display = ctx.local.display;
 
+        // Apply brightness only on change: it costs 2 blocking commands
+        let brightness = AppSharedState::display_brightness();
+        if *ctx.local.brightness != Some(brightness) {
+            match display.set_contrast((brightness as u32 * 255 / 100) as u8) {
+                Ok(()) => *ctx.local.brightness = Some(brightness),
+                Err(_) => AppSharedState::report_i2c_error(),
+            }
+        }
+
+        // Panel power is applied only on change too
+        let display_on = !AppSharedState::display_asleep();
+        if *ctx.local.display_on != Some(display_on) {
+            match display.set_display_on(display_on) {
+                Ok(()) => *ctx.local.display_on = Some(display_on),
+                Err(_) => AppSharedState::report_i2c_error(),
+            }
+        }
+        // Nothing is visible while asleep. Frame is drawn again on wake
+        if !display_on {
+            *ctx.local.last_version = None;
+            return;
+        }
+
         // We will skip usage if borrowed mutably beacuse it is means that we're changing state
         if let Some(s) = ctx.shared.app_state.try_read() {
+            // Read before drawing: change during draw will be caught in next frame
+            let version = s.version();
+            if *ctx.local.last_version == Some(version) {
+                return;
+            }
+
             display.clear(BinaryColor::Off).unwrap();
 
             s.draw(display).ok();
 
-            // Swap buffers to display
-            display.swap();
+            // Swap buffers to display. Dropped frame must be drawn again
+            match display.swap() {
+                Ok(true) => {
+                    *ctx.local.last_version = Some(version);
+                    *ctx.local.dropped_frames = 0;
+                }
+                Ok(false) => {
+                    // Transfer flag left set would freeze display forever
+                    *ctx.local.dropped_frames += 1;
+                    if *ctx.local.dropped_frames >= STUCK_TRANSFER_FRAMES {
+                        *ctx.local.dropped_frames = 0;
+                        if display.abort_transfer() {
+                            AppSharedState::report_i2c_error();
+                        }
+                    }
+                }
+                Err(_) => {
+                    *ctx.local.dropped_frames = 0;
+                    AppSharedState::report_i2c_error();
+                    // Display may be reinitialized by failed swap, contrast and power must be sent again
+                    *ctx.local.brightness = None;
+                    *ctx.local.display_on = None;
+                }
+            }
+        }
+    }
+
+    /// Clicks buzzer to confirm accepted input. Spawned with `start` from `handle_input`, ends itself
+    #[task(local = [buzzer], priority = 5, capacity = 2)]
+    fn key_click(ctx: key_click::Context, start: bool) {
+        let buzzer = ctx.local.buzzer;
+
+        if !start {
+            buzzer.click_end();
+        } else if buzzer.click_start() {
+            key_click::spawn_after(KEY_CLICK_MS.millis(), false).ok();
+        }
+    }
+
+    /// Reads real time for clock state. Spawned from `tick`: I2C access is too slow for its priority
+    #[task(shared = [&app_state], priority = 2, capacity = 1)]
+    fn sync_rtc(ctx: sync_rtc::Context) {
+        if let Some(s) = ctx.shared.app_state.try_read() {
+            s.sync_rtc();
+        }
+    }
+
+    /// Checks alarm against RTC. Spawned from `tick` each second while alarm is enabled: I2C access is too
+    /// slow for its priority
+    #[task(shared = [&app_state], priority = 2, capacity = 1)]
+    fn check_alarm(ctx: check_alarm::Context) {
+        if let Some(s) = ctx.shared.app_state.try_read() {
+            s.check_alarm();
+        }
+    }
+
+    /// Switches display between day and night brightness by local time from RTC
+    ///
+    /// Only sets night flag: contrast itself is sent by `draw` on brightness change
+    #[task(local = [rtc], shared = [&app_state], priority = 2)]
+    fn auto_dim(ctx: auto_dim::Context) {
+        auto_dim::spawn_after(AUTO_DIM_PERIOD_S.secs()).unwrap();
+
+        let hour = match ctx.local.rtc.update_time() {
+            Ok(now) => now
+                .with_timezone(&crate::app_state::local_timezone())
+                .hour() as u8,
+            Err(e) => {
+                if let ds3231::Error::Bus(_) = e {
+                    AppSharedState::report_i2c_error();
+                }
+                return;
+            }
+        };
+
+        // Retried on next period if state is switching
+        if let Some(s) = ctx.shared.app_state.try_read() {
+            let state = s.state();
+            state.set_night(state.is_night_hour(hour));
+        }
+    }
+
+    /// Reads RTC sensor temperature for clock screen
+    ///
+    /// Lowest priority as `draw`, so it never delays frame start. Blocking read retries while frame DMA
+    /// holds the bus
+    #[task(local = [temperature_rtc], priority = 1)]
+    fn read_temperature(ctx: read_temperature::Context) {
+        read_temperature::spawn_after(TEMPERATURE_PERIOD_S.secs()).unwrap();
+
+        match ctx.local.temperature_rtc.temperature() {
+            Ok(celsius) => AppSharedState::set_temperature(Some(celsius)),
+            Err(e) => {
+                if let ds3231::Error::Bus(_) = e {
+                    AppSharedState::report_i2c_error();
+                }
+                AppSharedState::set_temperature(None);
+            }
+        }
+    }
+
+    /// Refreshes info of diagnostics screen while it is shown. Spawned on its enter
+    ///
+    /// Blocking RTC read and bus scan are kept out of `draw`: stalled bus would stall frames
+    #[task(local = [diagnostics_rtc], shared = [&i2c], priority = 1, capacity = 1)]
+    fn diagnose(ctx: diagnose::Context) {
+        if DiagnosticsState::refresh(ctx.local.diagnostics_rtc, ctx.shared.i2c) {
+            diagnose::spawn_after(DIAGNOSTICS_PERIOD_S.secs()).ok();
+        }
+    }
+
+    /// Sends CSV line with UTC time, temperature and current state, e.g. "2024-05-01 12:30:00,24.5,Clock".
+    /// Header is sent first. Unknown values are left empty
+    ///
+    /// Line is formatted into buffer first, so slow blocking send doesn't hold app state lock
+    #[cfg(feature = "telemetry")]
+    #[task(local = [telemetry_rtc, header_sent: bool = false], shared = [&app_state, serial_tx], priority = 1)]
+    fn telemetry(mut ctx: telemetry::Context) {
+        use core::fmt::Write;
+
+        use crate::format::{write_celsius, write_date_time, FormatBuffer};
+
+        telemetry::spawn_after(TELEMETRY_PERIOD_S.secs()).unwrap();
+
+        let send_header = !core::mem::replace(ctx.local.header_sent, true);
+
+        let mut buf = [0_u8; 64];
+        let mut w = FormatBuffer::new(&mut buf);
+
+        match ctx.local.telemetry_rtc.update_time() {
+            Ok(now) => write_date_time(&mut w, &now).ok(),
+            Err(e) => {
+                if let ds3231::Error::Bus(_) = e {
+                    AppSharedState::report_i2c_error();
+                }
+                None
+            }
+        };
+        w.write_char(',').ok();
+
+        if let Some(s) = ctx.shared.app_state.try_read() {
+            if let Some(celsius) = s.state().temperature() {
+                write_celsius(&mut w, celsius).ok();
+            }
+            write!(&mut w, ",{}", s.state_name()).ok();
+        } else {
+            // State is switching
+            w.write_char(',').ok();
+        }
+
+        let line = w.as_str();
+        ctx.shared.serial_tx.lock(|tx| {
+            if send_header {
+                tx.write_str("time,temperature,state\r\n").ok();
+            }
+            tx.write_str(line).ok();
+            tx.write_str("\r\n").ok();
+        });
+    }
+
+    /// Collects received bytes into command lines and spawns [`handle_command`] for each
+    ///
+    /// Overrun error only loses bytes: broken line gets error response
+    #[cfg(feature = "commands")]
+    #[task(binds = USART2, local = [serial_rx, line: crate::command::LineBuffer = crate::command::LineBuffer::new()], priority = 4)]
+    fn serial_rx(ctx: serial_rx::Context) {
+        while let Ok(byte) = ctx.local.serial_rx.read() {
+            if let Some(line) = ctx.local.line.push(byte) {
+                // Busy handler means host doesn't wait for responses, command is dropped then
+                handle_command::spawn(line.and_then(crate::command::parse)).ok();
+            }
+        }
+    }
+
+    /// Executes command and sends response line: result or "OK" on success, "ERR ..." otherwise
+    ///
+    /// Time is local, as shown on clock screen
+    #[cfg(feature = "commands")]
+    #[task(local = [command_rtc], shared = [&app_state, serial_tx], priority = 1, capacity = 2)]
+    fn handle_command(
+        mut ctx: handle_command::Context,
+        command: Result<crate::command::Command, crate::command::Error>,
+    ) {
+        use core::fmt::Write;
+
+        use crate::app_state::local_timezone;
+        use crate::command::Command;
+        use crate::format::{write_celsius, write_date_time, FormatBuffer};
+
+        let rtc_error = |e: ds3231::Error| {
+            if let ds3231::Error::Bus(_) = e {
+                AppSharedState::report_i2c_error();
+            }
+            "ERR RTC"
+        };
+
+        let rtc = ctx.local.command_rtc;
+        let mut buf = [0_u8; 32];
+        let mut w = FormatBuffer::new(&mut buf);
+
+        let result = match command {
+            Err(e) => Err(e.as_str()),
+            Ok(Command::Get) => match rtc.update_time() {
+                Ok(now) => {
+                    write_date_time(&mut w, &now.with_timezone(&local_timezone())).ok();
+                    Ok(())
+                }
+                Err(e) => Err(rtc_error(e)),
+            },
+            Ok(Command::Set(time)) => {
+                let tz = local_timezone();
+                // Date is kept. Local date may differ from UTC one
+                let set = rtc.update_time().and_then(|now| {
+                    let local = now.with_timezone(&tz).naive_local().date().and_time(time);
+                    let utc = local - chrono::Duration::seconds(tz.local_minus_utc() as i64);
+                    rtc.set_time(chrono::DateTime::from_utc(utc, chrono::Utc))
+                });
+
+                match set {
+                    Ok(()) => {
+                        // Clock counts time itself, it must take new one from RTC
+                        if let Some(s) = ctx.shared.app_state.try_read() {
+                            s.sync_rtc();
+                        }
+                        crate::app_state::request_stopwatch_backup();
+                        w.write_str("OK").ok();
+                        Ok(())
+                    }
+                    Err(e) => Err(rtc_error(e)),
+                }
+            }
+            Ok(Command::Temp) => match ctx.shared.app_state.try_read() {
+                Some(s) => match s.state().temperature() {
+                    Some(celsius) => {
+                        write_celsius(&mut w, celsius).ok();
+                        Ok(())
+                    }
+                    None => Err("ERR NO DATA"),
+                },
+                None => Err("ERR BUSY"),
+            },
+        };
+
+        let response = match result {
+            Ok(()) => w.as_str(),
+            Err(e) => e,
+        };
+        ctx.shared.serial_tx.lock(|tx| {
+            tx.write_str(response).ok();
+            tx.write_str("\r\n").ok();
+        });
+    }
+
+    /// Collects NMEA sentences and pairs their time with last PPS edge
+    #[cfg(feature = "gps")]
+    #[task(binds = USART1, local = [gps_rx, sentence: crate::gps::SentenceBuffer = crate::gps::SentenceBuffer::new()], priority = 4)]
+    fn gps_rx(ctx: gps_rx::Context) {
+        while let Ok(byte) = ctx.local.gps_rx.read() {
+            if let Some(time) = ctx
+                .local
+                .sentence
+                .push(byte)
+                .and_then(crate::gps::parse_sentence)
+            {
+                crate::gps::on_sentence(time, monotonics::now().ticks());
+            }
+        }
+    }
 
-            let _s = 0;
+    /// Latches PPS edge. Highest priority below bus interrupts: latency is error of synced time
+    #[cfg(feature = "gps")]
+    #[task(binds = EXTI4, local = [gps_pps, last_sync: Option<u32> = None], priority = 6)]
+    fn gps_pps(ctx: gps_pps::Context) {
+        let now = monotonics::now().ticks();
+        ctx.local.gps_pps.clear_interrupt_pending_bit();
+
+        let time = match crate::gps::on_pps(now) {
+            Some(time) => time,
+            None => return,
+        };
+
+        let uptime = crate::uptime::uptime_seconds();
+        let due = ctx
+            .local
+            .last_sync
+            .map_or(true, |last| uptime.wrapping_sub(last) >= GPS_SYNC_PERIOD_S);
+        if due && gps_set_rtc::spawn(time).is_ok() {
+            *ctx.local.last_sync = Some(uptime);
         }
     }
 
+    /// Writes GPS time to RTC. Seconds write restarts RTC second, so it is aligned to PPS edge
+    /// up to bus wait for frame transfer
+    #[cfg(feature = "gps")]
+    #[task(local = [gps_rtc], priority = 4)]
+    fn gps_set_rtc(ctx: gps_set_rtc::Context, time: chrono::NaiveDateTime) {
+        if let Err(e) = ctx.local.gps_rtc.write_naive(time) {
+            if let ds3231::Error::Bus(_) = e {
+                AppSharedState::report_i2c_error();
+            }
+            return;
+        }
+
+        // Clock counts time itself, it must take new one from RTC
+        sync_rtc::spawn().ok();
+        crate::app_state::request_stopwatch_backup();
+    }
+
+    /// Measures battery voltage. Voltage changes slowly so rare updates are enough
+    #[task(local = [battery], priority = 2)]
+    fn battery_monitor(ctx: battery_monitor::Context) {
+        battery_monitor::spawn_after(10.secs()).unwrap();
+
+        ctx.local.battery.update();
+    }
+
     /// Task for switch next state
     /// Should be lowest priority
-    #[task(priority = 1, local=[], shared = [&app_state])]
-    fn change_state(ctx: change_state::Context, next: bool) {
-        let mut cur_state = ctx.shared.app_state.write();
+    ///
+    /// Settings saved on each switch, so edits made in a state persist after leaving it
+    ///
+    /// Spawned by [`crate::app_state::request_switch`]. Makes all switches requested since last run at once
+    #[task(priority = 1, shared = [&app_state, settings_store])]
+    fn change_state(mut ctx: change_state::Context) {
+        let steps = take_pending_switch();
+        if steps == 0 {
+            // Already made by previous run
+            return;
+        }
 
-        if next {
-            cur_state.next();
-        } else {
-            cur_state.prev();
+        let settings = {
+            let mut cur_state = ctx.shared.app_state.write();
+            cur_state.switch_by(steps);
+            cur_state.settings()
+        };
+
+        // Save outside of lock: EEPROM write takes several ms and other tasks skip work while locked
+        save_settings(&mut ctx.shared.settings_store, &settings);
+    }
+
+    /// Returns to clock screen after inactivity. Spawned from `tick`
+    /// Should be lowest priority
+    #[task(priority = 1, shared = [&app_state, settings_store])]
+    fn return_home(mut ctx: return_home::Context) {
+        let settings = {
+            let mut cur_state = ctx.shared.app_state.write();
+            cur_state.return_home();
+            cur_state.settings()
+        };
+
+        save_settings(&mut ctx.shared.settings_store, &settings);
+    }
+
+    /// Opens diagnostics info. Spawned from `handle_input` on combo
+    /// Should be lowest priority
+    #[task(priority = 1, shared = [&app_state])]
+    fn show_diagnostics(ctx: show_diagnostics::Context) {
+        ctx.shared.app_state.write().show_diagnostics();
+    }
+
+    /// Restores default settings and erases saved ones
+    /// Should be lowest priority
+    #[task(priority = 1, shared = [&app_state, settings_store])]
+    fn factory_reset(mut ctx: factory_reset::Context) {
+        ctx.shared.app_state.write().reset_to_defaults();
+
+        // Erased blob makes next boot use defaults even if no state switch follows
+        let result = ctx.shared.settings_store.lock(|store| store.erase());
+        if result.is_err() {
+            AppSharedState::report_i2c_error();
+        }
+    }
+
+    /// Saves stopwatch state to EEPROM. Spawned by [`crate::app_state::request_stopwatch_backup`]
+    /// Should be lowest priority
+    #[cfg(feature = "stopwatch-backup")]
+    #[task(local = [stopwatch_backup, backed_stopwatch], priority = 1)]
+    fn backup_stopwatch(ctx: backup_stopwatch::Context) {
+        let stopwatch = *ctx.local.backed_stopwatch;
+        if let Err(ds3231::Error::Bus(_)) = ctx.local.stopwatch_backup.save(stopwatch) {
+            AppSharedState::report_i2c_error();
+        }
+    }
+
+    /// Saves settings from low priority task. Failure is only shown: next switch saves again
+    fn save_settings(
+        store: &mut impl rtic::Mutex<T = SettingsStore<I2c1Handle, HardwareCrc32>>,
+        settings: &Settings,
+    ) {
+        if store.lock(|store| store.save(settings)).is_err() {
+            AppSharedState::report_i2c_error();
         }
     }
 
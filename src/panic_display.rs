@@ -0,0 +1,275 @@
+//! Panic handler showing panic message and location on display before halt
+//!
+//! Display driver and bus handle can't be used here: panic may happen while they are locked, in the middle of
+//! DMA transfer or due the bus itself. So I2C1 is reset and driven by registers with bounded waits, failure of
+//! any step just halts as `panic_halt` does
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{self, AtomicBool, AtomicU32, Ordering};
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+
+use crate::format::FormatBuffer;
+use crate::ssd1306::{
+    BUFFER_SIZE as FRAME_SIZE, DATA_CONTROL_BYTE, I2C_ADDRESS, PAGE_HEIGHT, SCREEN_HEIGHT,
+    SCREEN_WIDTH,
+};
+use hal::pac;
+
+/// Text grid of 6x10 font
+const COLUMNS: usize = SCREEN_WIDTH / 6;
+const ROWS: usize = SCREEN_HEIGHT / 10;
+const ROW_HEIGHT: i32 = 10;
+
+/// Standard mode: slow, but tolerates bad wiring that may be the cause of panic
+const I2C_SPEED_HZ: u32 = 100_000;
+/// Status polls before step is given up. Far longer than one byte takes at any clock
+const MAX_POLLS: u32 = 100_000;
+/// Half period of SCL pulses clocking out stuck device, in core cycles. About 5 µs at 100 MHz
+const RECOVERY_HALF_PERIOD_CYCLES: u32 = 500;
+/// I2C1 pins on port B
+const SCL_PIN: u32 = 8;
+const SDA_PIN: u32 = 9;
+/// Alternate function of I2C1 on PB8 and PB9
+const I2C_AF: u32 = 4;
+
+/// Bus clock after reset, HSI. Replaced by configured one in init
+static PCLK1_HZ: AtomicU32 = AtomicU32::new(16_000_000);
+/// Set on first panic. Panic during render goes straight to halt
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// Frame in display page layout
+static mut FRAME: [u8; FRAME_SIZE] = [0; FRAME_SIZE];
+
+/// Remembers APB1 clock, I2C timing depends on it. Must be called after clocks are configured
+pub fn set_pclk1(hz: u32) {
+    PCLK1_HZ.store(hz, Ordering::Relaxed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        // Safe: interrupts are disabled and nested panic doesn't get here, so frame has single user
+        let frame = unsafe { &mut *core::ptr::addr_of_mut!(FRAME) };
+        render(frame, info);
+        show(frame).ok();
+    }
+
+    loop {
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Draws header and panic text wrapped by characters into `frame`
+fn render(frame: &mut [u8; FRAME_SIZE], info: &PanicInfo) {
+    let mut buf = [0_u8; COLUMNS * (ROWS - 1)];
+    let mut w = FormatBuffer::new(&mut buf);
+    // Truncated text is still shown
+    write!(&mut w, "{}", info).ok();
+    let text = w.as_str();
+
+    let mut target = Frame(frame);
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let mut draw_row = |row: usize, line: &str| {
+        let position = Point::new(0, row as i32 * ROW_HEIGHT);
+        Text::with_baseline(line, position, style, Baseline::Top)
+            .draw(&mut target)
+            .ok();
+    };
+
+    draw_row(0, "PANIC");
+
+    let mut row = 1;
+    for line in text.lines() {
+        let mut rest = line;
+        while row < ROWS {
+            let split = rest
+                .char_indices()
+                .nth(COLUMNS)
+                .map_or(rest.len(), |(i, _)| i);
+            let (part, tail) = rest.split_at(split);
+            draw_row(row, part);
+            row += 1;
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Sends frame to display with I2C1 reinitialized from scratch
+fn show(frame: &[u8; FRAME_SIZE]) -> Result<(), ()> {
+    // Safe: interrupts are disabled and nothing else runs after panic
+    let dp = unsafe { pac::Peripherals::steal() };
+
+    stop_dma(&dp.DMA1)?;
+    setup_pins(&dp.RCC, &dp.GPIOB);
+    recover_bus(&dp.GPIOB);
+    reset_i2c(&dp.RCC, &dp.I2C1);
+
+    let i2c = &dp.I2C1;
+    // Display may be asleep or dimmed. Window covers whole screen in horizontal addressing
+    write(
+        i2c,
+        &[
+            0x00, 0x8D, 0x14, 0x81, 0xCF, 0x20, 0x00, 0x21, 0x00, 0x7F, 0x22, 0x00, 0x07, 0xAF,
+        ],
+        &[],
+    )?;
+    write(i2c, &[DATA_CONTROL_BYTE], frame)
+}
+
+/// Stops frame transfer in progress, its stream would keep feeding the bus
+fn stop_dma(dma: &pac::DMA1) -> Result<(), ()> {
+    for stream in &dma.st[..2] {
+        stream.cr.modify(|_, w| w.en().clear_bit());
+        poll(|| stream.cr.read().en().bit_is_clear())?;
+    }
+
+    Ok(())
+}
+
+/// Configures PB8 and PB9 as I2C1 open drain pins: panic may come before init configured them
+fn setup_pins(rcc: &pac::RCC, gpiob: &pac::GPIOB) {
+    rcc.ahb1enr.modify(|_, w| w.gpioben().set_bit());
+
+    // Safe for all: only bits of I2C1 pins are changed
+    gpiob
+        .otyper
+        .modify(|r, w| unsafe { w.bits(r.bits() | (1 << SCL_PIN) | (1 << SDA_PIN)) });
+    gpiob.afrh.modify(|r, w| unsafe {
+        let mask = (0xF << ((SCL_PIN - 8) * 4)) | (0xF << ((SDA_PIN - 8) * 4));
+        let af = (I2C_AF << ((SCL_PIN - 8) * 4)) | (I2C_AF << ((SDA_PIN - 8) * 4));
+        w.bits((r.bits() & !mask) | af)
+    });
+    set_mode(gpiob, SCL_PIN, 0b10);
+    set_mode(gpiob, SDA_PIN, 0b10);
+}
+
+/// Device stuck in the middle of read holds SDA low. Clock pulses make it finish the byte and release SDA
+fn recover_bus(gpiob: &pac::GPIOB) {
+    if gpiob.idr.read().bits() & (1 << SDA_PIN) != 0 {
+        return;
+    }
+
+    // SCL as open drain output
+    set_mode(gpiob, SCL_PIN, 0b01);
+    for _ in 0..9 {
+        gpiob.bsrr.write(|w| unsafe { w.bits(1 << (SCL_PIN + 16)) });
+        cortex_m::asm::delay(RECOVERY_HALF_PERIOD_CYCLES);
+        gpiob.bsrr.write(|w| unsafe { w.bits(1 << SCL_PIN) });
+        cortex_m::asm::delay(RECOVERY_HALF_PERIOD_CYCLES);
+    }
+    set_mode(gpiob, SCL_PIN, 0b10);
+}
+
+fn set_mode(gpiob: &pac::GPIOB, pin: u32, mode: u32) {
+    gpiob
+        .moder
+        .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << (pin * 2))) | (mode << (pin * 2))) });
+}
+
+/// Resets I2C1, so state left by interrupted transfer is dropped, and configures standard mode
+fn reset_i2c(rcc: &pac::RCC, i2c: &pac::I2C1) {
+    rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit());
+    rcc.apb1rstr.modify(|_, w| w.i2c1rst().set_bit());
+    rcc.apb1rstr.modify(|_, w| w.i2c1rst().clear_bit());
+
+    let pclk1 = PCLK1_HZ.load(Ordering::Relaxed);
+    let freq_mhz = pclk1 / 1_000_000;
+    // Safe for all: values are in register ranges for APB1 clock of 2..50 MHz
+    i2c.cr2.write(|w| unsafe { w.freq().bits(freq_mhz as u8) });
+    i2c.ccr
+        .write(|w| unsafe { w.ccr().bits((pclk1 / (I2C_SPEED_HZ * 2)) as u16) });
+    i2c.trise
+        .write(|w| unsafe { w.trise().bits(freq_mhz as u8 + 1) });
+    i2c.cr1.write(|w| w.pe().set_bit());
+}
+
+/// Writes `head` and then `data` to display in single transaction
+fn write(i2c: &pac::I2C1, head: &[u8], data: &[u8]) -> Result<(), ()> {
+    let result = transfer(i2c, head, data);
+    i2c.cr1.modify(|_, w| w.stop().set_bit());
+    result
+}
+
+fn transfer(i2c: &pac::I2C1, head: &[u8], data: &[u8]) -> Result<(), ()> {
+    poll(|| i2c.sr2.read().busy().bit_is_clear())?;
+
+    i2c.cr1.modify(|_, w| w.start().set_bit());
+    poll(|| i2c.sr1.read().sb().bit_is_set())?;
+
+    i2c.dr
+        .write(|w| unsafe { w.bits((I2C_ADDRESS as u32) << 1) });
+    poll(|| {
+        let sr1 = i2c.sr1.read();
+        sr1.addr().bit_is_set() || sr1.af().bit_is_set()
+    })?;
+    if i2c.sr1.read().af().bit_is_set() {
+        // Display is missing or has other address
+        return Err(());
+    }
+    // ADDR is cleared by reading SR1 and then SR2
+    i2c.sr2.read();
+
+    for &byte in head.iter().chain(data) {
+        poll(|| i2c.sr1.read().tx_e().bit_is_set())?;
+        i2c.dr.write(|w| unsafe { w.bits(byte as u32) });
+    }
+
+    poll(|| i2c.sr1.read().btf().bit_is_set())
+}
+
+/// Waits for `done` up to [MAX_POLLS] times
+fn poll(mut done: impl FnMut() -> bool) -> Result<(), ()> {
+    if (0..MAX_POLLS).any(|_| done()) {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Draw target over frame in display page layout
+struct Frame<'f>(&'f mut [u8; FRAME_SIZE]);
+
+impl<'f> OriginDimensions for Frame<'f> {
+    fn size(&self) -> Size {
+        Size::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+    }
+}
+
+impl<'f> DrawTarget for Frame<'f> {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            let (x, y) = match (usize::try_from(p.x), usize::try_from(p.y)) {
+                (Ok(x), Ok(y)) if x < SCREEN_WIDTH && y < SCREEN_HEIGHT => (x, y),
+                _ => continue,
+            };
+
+            let index = y / PAGE_HEIGHT * SCREEN_WIDTH + x;
+            let bit = 1 << (y % PAGE_HEIGHT);
+            if color.is_on() {
+                self.0[index] |= bit;
+            } else {
+                self.0[index] &= !bit;
+            }
+        }
+
+        Ok(())
+    }
+}
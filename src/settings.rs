@@ -0,0 +1,312 @@
+use crate::crc::Crc32;
+use crate::eeprom24::{Eeprom24, Error};
+use crate::i2c::BlockingI2C;
+
+/// Marks written settings blob
+const MAGIC: u8 = 0xD5;
+/// Increment on layout change to ignore old blobs
+const VERSION: u8 = 10;
+/// Address of settings blob in EEPROM
+pub const SETTINGS_ADDRESS: u16 = 0;
+/// Serialized size: magic, version, fields and CRC
+pub const SETTINGS_SIZE: usize = 21;
+/// CRC is stored in last bytes, little-endian
+const CRC_OFFSET: usize = SETTINGS_SIZE - 4;
+/// Stored in place of world clock offset while it is off
+const WORLD_OFFSET_OFF: u8 = 0x80;
+/// Stored in place of night brightness while auto dim is off
+const NIGHT_BRIGHTNESS_OFF: u8 = 0xFF;
+/// Limit of temperature offset magnitude in half degrees
+pub const MAX_TEMPERATURE_OFFSET: i8 = 20;
+
+/// Application settings restored on boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// Index of selected app state
+    pub app_state: u8,
+    pub alarm_hour: u8,
+    pub alarm_minute: u8,
+    pub alarm_enabled: bool,
+    /// Display brightness in percents
+    pub brightness: u8,
+    /// Buzzer volume in percents
+    pub volume: u8,
+    pub hour_format_12: bool,
+    /// UTC offset in 15 minutes units
+    pub utc_offset: i8,
+    pub key_click: bool,
+    /// World clock UTC offset in 15 minutes units, None if it is off
+    pub world_offset: Option<i8>,
+    /// Countdown alarm grows louder over time
+    pub alarm_escalation: bool,
+    /// Local hour of auto dim start
+    pub night_start: u8,
+    /// Local hour of auto dim end
+    pub night_end: u8,
+    /// Display brightness in night hours in percents, None if auto dim is off
+    pub night_brightness: Option<u8>,
+    /// Correction added to measured temperature in half degrees
+    pub temperature_offset: i8,
+}
+
+impl Settings {
+    pub fn to_bytes(&self, crc: &mut impl Crc32) -> [u8; SETTINGS_SIZE] {
+        let mut data = [
+            MAGIC,
+            VERSION,
+            self.app_state,
+            self.alarm_hour,
+            self.alarm_minute,
+            self.alarm_enabled as u8,
+            self.brightness,
+            self.volume,
+            self.hour_format_12 as u8,
+            self.utc_offset as u8,
+            self.key_click as u8,
+            self.world_offset.map_or(WORLD_OFFSET_OFF, |o| o as u8),
+            self.alarm_escalation as u8,
+            self.night_start,
+            self.night_end,
+            self.night_brightness.unwrap_or(NIGHT_BRIGHTNESS_OFF),
+            self.temperature_offset as u8,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let crc = crc.crc32(&data[..CRC_OFFSET]);
+        data[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+
+        data
+    }
+
+    /// Returns None if blob is empty, corrupted or written by other version
+    pub fn from_bytes(data: &[u8; SETTINGS_SIZE], crc: &mut impl Crc32) -> Option<Self> {
+        if data[0] != MAGIC || data[1] != VERSION {
+            return None;
+        }
+
+        let stored_crc = u32::from_le_bytes(data[CRC_OFFSET..].try_into().ok()?);
+        if crc.crc32(&data[..CRC_OFFSET]) != stored_crc {
+            return None;
+        }
+
+        if data[3] > 23 || data[4] > 59 || data[6] > 100 || data[7] > 100 {
+            return None;
+        }
+
+        if data[13] > 23 || data[14] > 23 || (data[15] > 100 && data[15] != NIGHT_BRIGHTNESS_OFF) {
+            return None;
+        }
+
+        if (data[16] as i8).unsigned_abs() > MAX_TEMPERATURE_OFFSET as u8 {
+            return None;
+        }
+
+        Some(Self {
+            app_state: data[2],
+            alarm_hour: data[3],
+            alarm_minute: data[4],
+            alarm_enabled: data[5] != 0,
+            brightness: data[6],
+            volume: data[7],
+            hour_format_12: data[8] != 0,
+            utc_offset: data[9] as i8,
+            key_click: data[10] != 0,
+            world_offset: match data[11] {
+                WORLD_OFFSET_OFF => None,
+                offset => Some(offset as i8),
+            },
+            alarm_escalation: data[12] != 0,
+            night_start: data[13],
+            night_end: data[14],
+            night_brightness: match data[15] {
+                NIGHT_BRIGHTNESS_OFF => None,
+                percent => Some(percent),
+            },
+            temperature_offset: data[16] as i8,
+        })
+    }
+}
+
+/// Settings blob in EEPROM. Remembers last written settings to avoid needless write cycles
+pub struct SettingsStore<I2C: BlockingI2C + 'static, CRC: Crc32> {
+    eeprom: Eeprom24<I2C>,
+    /// Validates blob
+    crc: CRC,
+    last: Option<Settings>,
+}
+
+impl<I2C: BlockingI2C, CRC: Crc32> SettingsStore<I2C, CRC> {
+    pub fn new(eeprom: Eeprom24<I2C>, crc: CRC) -> Self {
+        Self {
+            eeprom,
+            crc,
+            last: None,
+        }
+    }
+
+    /// Returns None if EEPROM is missing or blob is not valid
+    pub fn load(&mut self) -> Option<Settings> {
+        let mut blob = [0_u8; SETTINGS_SIZE];
+        self.eeprom.read(SETTINGS_ADDRESS, &mut blob).ok()?;
+
+        let settings = Settings::from_bytes(&blob, &mut self.crc)?;
+        self.last = Some(settings.clone());
+        Some(settings)
+    }
+
+    /// Writes settings if they differ from last written
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+        if self.last.as_ref() == Some(settings) {
+            return Ok(());
+        }
+
+        let blob = settings.to_bytes(&mut self.crc);
+        self.eeprom.write(SETTINGS_ADDRESS, &blob)?;
+        self.last = Some(settings.clone());
+        Ok(())
+    }
+
+    /// Zeroes blob, so defaults are used on next boot
+    pub fn erase(&mut self) -> Result<(), Error> {
+        // Forget before write: partially written blob must be overwritten by next save
+        self.last = None;
+        self.eeprom.write(SETTINGS_ADDRESS, &[0; SETTINGS_SIZE])
+    }
+}
+
+/// Temperature measured by sensor corrected by [Settings::temperature_offset] of `half_degrees`
+pub fn corrected_temperature(celsius: f32, half_degrees: i8) -> f32 {
+    celsius + half_degrees as f32 / 2.0
+}
+
+/// Checksum of small EEPROM blobs
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0_u8, |acc, b| acc.rotate_left(1) ^ b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::SoftwareCrc32;
+
+    fn settings() -> Settings {
+        Settings {
+            app_state: 2,
+            alarm_hour: 7,
+            alarm_minute: 30,
+            alarm_enabled: true,
+            brightness: 80,
+            volume: 50,
+            hour_format_12: true,
+            utc_offset: -14,
+            key_click: false,
+            world_offset: Some(36),
+            alarm_escalation: true,
+            night_start: 22,
+            night_end: 7,
+            night_brightness: Some(10),
+            temperature_offset: -3,
+        }
+    }
+
+    #[test]
+    fn temperature_offset_limits_are_kept() {
+        for offset in [-MAX_TEMPERATURE_OFFSET, MAX_TEMPERATURE_OFFSET] {
+            let settings = Settings {
+                temperature_offset: offset,
+                ..settings()
+            };
+            let blob = settings.to_bytes(&mut SoftwareCrc32);
+            assert_eq!(
+                Settings::from_bytes(&blob, &mut SoftwareCrc32),
+                Some(settings)
+            );
+        }
+    }
+
+    #[test]
+    fn temperature_offset_out_of_range_is_rejected() {
+        for offset in [
+            -MAX_TEMPERATURE_OFFSET - 1,
+            MAX_TEMPERATURE_OFFSET + 1,
+            i8::MIN,
+        ] {
+            let settings = Settings {
+                temperature_offset: offset,
+                ..settings()
+            };
+            let blob = settings.to_bytes(&mut SoftwareCrc32);
+            assert_eq!(Settings::from_bytes(&blob, &mut SoftwareCrc32), None);
+        }
+    }
+
+    #[test]
+    fn temperature_offset_composes_with_quarter_degree_readings() {
+        // RTC sensor reports quarters of degree
+        assert_eq!(corrected_temperature(25.25, -3), 23.75);
+        assert_eq!(corrected_temperature(-0.75, 1), -0.25);
+        assert_eq!(corrected_temperature(0.0, MAX_TEMPERATURE_OFFSET), 10.0);
+        assert_eq!(corrected_temperature(21.5, 0), 21.5);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let blob = settings().to_bytes(&mut SoftwareCrc32);
+        assert_eq!(
+            Settings::from_bytes(&blob, &mut SoftwareCrc32),
+            Some(settings())
+        );
+
+        let off = Settings {
+            world_offset: None,
+            night_brightness: None,
+            ..settings()
+        };
+        let blob = off.to_bytes(&mut SoftwareCrc32);
+        assert_eq!(Settings::from_bytes(&blob, &mut SoftwareCrc32), Some(off));
+    }
+
+    #[test]
+    fn crc_is_stored_little_endian_after_fields() {
+        let blob = settings().to_bytes(&mut SoftwareCrc32);
+        let crc = SoftwareCrc32.crc32(&blob[..CRC_OFFSET]);
+        assert_eq!(blob[CRC_OFFSET..], crc.to_le_bytes());
+    }
+
+    #[test]
+    fn corrupted_blob_is_rejected() {
+        let blob = settings().to_bytes(&mut SoftwareCrc32);
+
+        // Each bit of fields and CRC
+        for byte in 2..SETTINGS_SIZE {
+            for bit in 0..8 {
+                let mut corrupted = blob;
+                corrupted[byte] ^= 1 << bit;
+                assert_eq!(
+                    Settings::from_bytes(&corrupted, &mut SoftwareCrc32),
+                    None,
+                    "byte {} bit {}",
+                    byte,
+                    bit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn other_version_and_empty_blob_are_rejected() {
+        let mut blob = settings().to_bytes(&mut SoftwareCrc32);
+        blob[1] = VERSION - 1;
+        assert_eq!(Settings::from_bytes(&blob, &mut SoftwareCrc32), None);
+
+        // Erased by `SettingsStore::erase` or new EEPROM
+        for fill in [0x00, 0xFF] {
+            assert_eq!(
+                Settings::from_bytes(&[fill; SETTINGS_SIZE], &mut SoftwareCrc32),
+                None
+            );
+        }
+    }
+}
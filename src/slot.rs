@@ -0,0 +1,75 @@
+/// Holds value moved between owners, e.g. shared state passed from one app state to another on switch
+///
+/// Misuse doesn't panic, since panic halts firmware: redundant [Slot::put] keeps held value, [Slot::take]
+/// from empty slot gives default value
+pub struct Slot<T> {
+    value: Option<T>,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<T> Slot<T> {
+    pub const fn empty() -> Self {
+        Self { value: None }
+    }
+
+    /// Stores `value` unless slot already holds one. Returns false if `value` is dropped
+    pub fn put(&mut self, value: T) -> bool {
+        if self.value.is_some() {
+            return false;
+        }
+
+        self.value = Some(value);
+        true
+    }
+
+    /// Held value, None if slot is empty
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+impl<T: Default> Slot<T> {
+    /// Takes held value, leaving slot empty. Returns default value if slot is already empty
+    pub fn take(&mut self) -> T {
+        self.value.take().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_then_take_moves_value() {
+        let mut slot = Slot::empty();
+        assert!(slot.put(5));
+        assert_eq!(slot.get(), Some(&5));
+
+        assert_eq!(slot.take(), 5);
+        assert_eq!(slot.get(), None);
+    }
+
+    #[test]
+    fn redundant_put_keeps_held_value() {
+        let mut slot = Slot::empty();
+        slot.put(5);
+
+        assert!(!slot.put(7));
+        assert_eq!(slot.take(), 5);
+    }
+
+    #[test]
+    fn take_from_empty_slot_gives_default() {
+        let mut slot = Slot::<u32>::empty();
+        assert_eq!(slot.take(), 0);
+
+        slot.put(5);
+        slot.take();
+        assert_eq!(slot.take(), 0);
+    }
+}
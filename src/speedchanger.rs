@@ -1,5 +1,11 @@
 use core::sync::atomic::{AtomicU32, Ordering};
 
+/// Lowest divider reachable by [SpeedChanger::decrement_max_div]
+///
+/// Divider 0 runs function on every call, so fully accelerated speed would be bound only by
+/// caller's rate. 1 keeps it at every second call
+const MIN_DIV: u32 = 1;
+
 /// Controls speed by changing how often function will be run
 pub struct SpeedChanger<const RESET_DIV: u32> {
     current_max_div: AtomicU32,
@@ -30,12 +36,13 @@ impl<const RESET_DIV: u32> SpeedChanger<RESET_DIV> {
 
     pub fn decrement_max_div(&self) {
         let current = self.current_max_div.load(Ordering::Acquire);
-        if current > 0 {
+        if current > MIN_DIV {
             self.current_max_div.fetch_sub(1, Ordering::Release);
             // Also skip current step
             self.next_div();
         } else {
-            self.current_max_div.store(0, Ordering::Release);
+            self.current_max_div
+                .store(MIN_DIV.min(RESET_DIV), Ordering::Release);
         }
     }
 
@@ -50,3 +57,50 @@ impl<const RESET_DIV: u32> SpeedChanger<RESET_DIV> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Number of `execute` calls out of `calls` that ran function
+    fn runs<const RESET_DIV: u32>(changer: &SpeedChanger<RESET_DIV>, calls: u32) -> u32 {
+        let runs = Cell::new(0);
+        for _ in 0..calls {
+            changer.execute(|| runs.set(runs.get() + 1));
+        }
+        runs.get()
+    }
+
+    #[test]
+    fn reset_divider_runs_once_per_divider_plus_one_calls() {
+        let changer = SpeedChanger::<3>::default();
+        assert_eq!(runs(&changer, 40), 10);
+    }
+
+    #[test]
+    fn fully_accelerated_runs_every_second_call() {
+        let changer = SpeedChanger::<10>::default();
+        for _ in 0..100 {
+            changer.decrement_max_div();
+        }
+
+        assert_eq!(changer.current_max_div.load(Ordering::Relaxed), MIN_DIV);
+        // Steady: more decrements don't speed up further
+        for _ in 0..10 {
+            assert_eq!(runs(&changer, 100), 50);
+            changer.decrement_max_div();
+        }
+    }
+
+    #[test]
+    fn reset_restores_initial_speed() {
+        let changer = SpeedChanger::<3>::default();
+        for _ in 0..10 {
+            changer.decrement_max_div();
+        }
+        changer.reset();
+
+        assert_eq!(runs(&changer, 40), 10);
+    }
+}
@@ -1,55 +1,162 @@
-use core::{
-    cell::RefCell,
-    sync::atomic::{AtomicBool, Ordering},
-};
-
-use cortex_m::asm::nop;
+#[cfg(target_os = "none")]
+use core::cell::RefCell;
 
+#[cfg(target_os = "none")]
 use critical_section::Mutex;
+#[cfg(target_os = "none")]
 use stm32f4xx_hal::{
     gpio::{Output, Pin, PushPull},
     i2c::dma::I2CMasterWriteDMA,
 };
 
-use crate::i2c::BlockingI2C;
+#[cfg(target_os = "none")]
+use crate::i2c::{dma_transfer, set_dma_transfer, AbortDma, BlockingI2C, BlockingI2CBus};
+use crate::i2c::{BusError, Error};
 
 use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
 
 /// We use only this address. Additional 0x3D unsupported
-const I2C_ADDRESS: u8 = 0x3C;
-const SCREEN_WIDTH: usize = 128;
-const SCREEN_HEIGHT: usize = 64;
-const PAGE_COUNT: usize = 64 / 8;
-/// Buffer size - 128x64 resolutions /8 - each pixel is one bit, not byte.
-const BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT / 8;
-
-static DRAWING: AtomicBool = AtomicBool::new(false);
+#[cfg(target_os = "none")]
+pub(crate) const I2C_ADDRESS: u8 = 0x3C;
+pub(crate) const SCREEN_WIDTH: usize = 128;
+pub(crate) const SCREEN_HEIGHT: usize = 64;
+/// Each page is a row of bytes, one bit per pixel row
+pub(crate) const PAGE_HEIGHT: usize = 8;
+const PAGE_COUNT: usize = SCREEN_HEIGHT / PAGE_HEIGHT;
+/// How many times frame send retried after failure
+#[cfg(target_os = "none")]
+const SWAP_ATTEMPTS: u32 = 3;
+/// Failed swaps in a row after which display is initialized again: it may be reset by power glitch
+#[cfg(target_os = "none")]
+const REINIT_AFTER_FAILED_SWAPS: u32 = 5;
+/// Buffer size - byte per column of each page, each pixel is one bit, not byte.
+pub(crate) const BUFFER_SIZE: usize = SCREEN_WIDTH * PAGE_COUNT;
+/// Bytes sent after address on each swap: control byte and buffer
+pub const FRAME_BYTES: usize = BUFFER_SIZE + 1;
+/// Control byte before frame: following bytes are display data
+pub(crate) const DATA_CONTROL_BYTE: u8 = 0x40;
 
 #[derive(Debug)]
 pub enum OperationError {
-    I2CError,
+    I2CError(Error),
     Busy,
 }
 
+impl From<BusError> for OperationError {
+    fn from(e: BusError) -> Self {
+        match e {
+            BusError::Busy => OperationError::Busy,
+            BusError::I2C(e) => OperationError::I2CError(e),
+        }
+    }
+}
+
+/// Frame in display memory layout: byte per column of each page, bit per pixel row. Control byte goes
+/// first, so frame is sent as is
+pub struct FrameBuffer {
+    bytes: [u8; FRAME_BYTES],
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        let mut bytes = [0; FRAME_BYTES];
+        bytes[0] = DATA_CONTROL_BYTE;
+        Self { bytes }
+    }
+}
+
+impl FrameBuffer {
+    /// Control byte and frame data
+    pub fn bytes(&self) -> &[u8; FRAME_BYTES] {
+        &self.bytes
+    }
+
+    /// Sets pixel. Points outside of screen are skipped, as [DrawTarget] requires
+    #[inline(always)]
+    pub fn dot(&mut self, p: Point, filled: bool) {
+        let (x, y) = match (usize::try_from(p.x), usize::try_from(p.y)) {
+            (Ok(x), Ok(y)) if x < SCREEN_WIDTH && y < SCREEN_HEIGHT => (x, y),
+            _ => return,
+        };
+
+        let page = y / PAGE_HEIGHT;
+        let index = page * SCREEN_WIDTH + x + 1; // +1 skip 1 data byte
+        let bit = 1 << (y % PAGE_HEIGHT);
+
+        if filled {
+            self.bytes[index] |= bit;
+        } else {
+            self.bytes[index] &= !bit;
+        }
+    }
+
+    /// Fills `area` with `color`. Area is clipped to screen
+    ///
+    /// Pages fully covered by area are written by whole bytes, partly covered ones are masked
+    pub fn clear_region(&mut self, area: Rectangle, color: BinaryColor) {
+        let screen = Rectangle::new(
+            Point::zero(),
+            Size::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32),
+        );
+        let area = area.intersection(&screen);
+        let bottom_right = match area.bottom_right() {
+            Some(p) => p,
+            None => return,
+        };
+
+        // Clipped to screen, so coordinates are not negative
+        let (x_start, x_end) = (area.top_left.x as usize, bottom_right.x as usize + 1);
+        let (y_start, y_end) = (area.top_left.y as usize, bottom_right.y as usize + 1);
+
+        for page in y_start / PAGE_HEIGHT..=(y_end - 1) / PAGE_HEIGHT {
+            let page_top = page * PAGE_HEIGHT;
+            let first_bit = y_start.max(page_top) - page_top;
+            let last_bit = y_end.min(page_top + PAGE_HEIGHT) - page_top;
+            let mask = (0xFF_u8 >> (PAGE_HEIGHT - (last_bit - first_bit))) << first_bit;
+
+            let row_start = page * SCREEN_WIDTH + 1; // +1 skip 1 data byte
+            for byte in &mut self.bytes[row_start + x_start..row_start + x_end] {
+                if color.is_on() {
+                    *byte |= mask;
+                } else {
+                    *byte &= !mask;
+                }
+            }
+        }
+    }
+
+    /// Fills whole frame with `color`
+    pub fn fill(&mut self, color: BinaryColor) {
+        let fill_byte = if color == BinaryColor::Off { 0 } else { 255 };
+        self.bytes[1..].fill(fill_byte); // Skip 1 data byte
+    }
+}
+
+#[cfg(target_os = "none")]
 pub struct SSD1306<'bus, PIN, I2C: BlockingI2C + I2CMasterWriteDMA + 'bus> {
     reset_pin: PIN,
     i2c: &'bus Mutex<RefCell<I2C>>,
 
-    buffer: [u8; BUFFER_SIZE + 1], // The first byte is Control byte 0x40
-    send_buffer: [u8; BUFFER_SIZE + 1], // Buffer used to send
+    buffer: FrameBuffer,
+    send_buffer: [u8; FRAME_BYTES], // Buffer used to send
+
+    /// Swaps failed in a row. See [REINIT_AFTER_FAILED_SWAPS]
+    failed_swaps: u32,
 }
 
+#[cfg(target_os = "none")]
 impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA>
     SSD1306<'bus, Pin<P, N, Output<PushPull>>, I2C>
 {
     /// Creates SSD1306 driver
     pub fn new(reset_pin: Pin<P, N, Output<PushPull>>, i2c: &'bus Mutex<RefCell<I2C>>) -> Self {
-        DRAWING.store(false, Ordering::Relaxed);
+        set_dma_transfer(false);
         Self {
             reset_pin,
             i2c,
-            buffer: [0x40; BUFFER_SIZE + 1],
-            send_buffer: [0x40; BUFFER_SIZE + 1],
+            buffer: FrameBuffer::default(),
+            send_buffer: [DATA_CONTROL_BYTE; FRAME_BYTES],
+            failed_swaps: 0,
         }
     }
 
@@ -104,72 +211,116 @@ impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA>
         Ok(())
     }
 
-    #[inline(always)]
-    pub fn dot(&mut self, p: Point, filled: bool) {
-        debug_assert!(self.bounding_box().contains(p));
+    /// Runs init sequence again to recover display lost its configuration. Buffer is cleared and contrast is default
+    pub fn reinit(&mut self) -> Result<(), OperationError> {
+        self.failed_swaps = 0;
+        self.init()
+    }
 
-        let page = p.y / (PAGE_COUNT as i32);
-        let index = page * (SCREEN_WIDTH as i32) + p.x + 1; // +1 skip 1 data byte
+    /// Sets display contrast. 0 is the dimmest, but display still on
+    pub fn set_contrast(&mut self, contrast: u8) -> Result<(), OperationError> {
+        self.send_command(0x81)?;
+        self.send_command(contrast)?;
 
-        if filled {
-            self.buffer[index as usize] |= 1 << (p.y % 8);
-        } else {
-            self.buffer[index as usize] &= !(1 << (p.y % 8));
-        }
+        Ok(())
     }
 
-    pub fn swap(&mut self) {
-        if DRAWING.load(Ordering::Relaxed) {
-            return;
-        }
+    /// Turns panel on or off. Buffer and display RAM are kept, so content returns on wake
+    pub fn set_display_on(&mut self, on: bool) -> Result<(), OperationError> {
+        self.send_command(if on { 0xAF } else { 0xAE })?;
 
-        while self.send_image().is_err() {
-            self.reset_position()
-        }
+        Ok(())
     }
 
-    fn reset_position(&mut self) {
-        while self
-            .send_command(0x21)
-            .and(self.send_command(0))
-            .and(self.send_command(127))
-            .and(self.send_command(0x22))
-            .and(self.send_command(0))
-            .and(self.send_command(7))
-            .is_err()
-        {
-            nop();
-        }
+    /// Sets pixel in buffer. Points outside of screen are skipped, as [DrawTarget] requires
+    #[inline(always)]
+    pub fn dot(&mut self, p: Point, filled: bool) {
+        self.buffer.dot(p, filled)
     }
 
-    fn send_command(&mut self, cmd: u8) -> Result<(), OperationError> {
-        while let Err(OperationError::Busy) = critical_section::with(|cs| {
-            let mut bus = self.i2c.borrow(cs).borrow_mut();
+    /// Fills `area` of buffer with `color`, so state can redraw only its changed part. See [FrameBuffer::clear_region]
+    pub fn clear_region(&mut self, area: Rectangle, color: BinaryColor) {
+        self.buffer.clear_region(area, color)
+    }
 
-            if let Err(e) = bus.write(I2C_ADDRESS, &[0x0, cmd]) {
-                if e == nb::Error::WouldBlock {
-                    return Err(OperationError::Busy);
+    /// Sends buffer to display. Returns `Ok(false)` if frame dropped because previous one still transferred
+    /// or bus is taken, last error if all attempts failed
+    ///
+    /// After [REINIT_AFTER_FAILED_SWAPS] failed swaps in a row display is reinitialized, see [SSD1306::reinit]
+    pub fn swap(&mut self) -> Result<bool, OperationError> {
+        if dma_transfer() {
+            return Ok(false);
+        }
+
+        let mut last_error = OperationError::Busy;
+        for _ in 0..SWAP_ATTEMPTS {
+            match self.send_image() {
+                Ok(()) => {
+                    self.failed_swaps = 0;
+                    return Ok(true);
                 }
-                return Err(OperationError::I2CError);
+                // Busy bus is not a failure: frame is dropped and sent by next swap
+                Err(OperationError::Busy) => return Ok(false),
+                Err(e) => last_error = e,
             }
 
-            Ok(())
-        }) {
-            // Do nothing, retry
+            // Failed transfer may leave display at random position
+            self.reset_position().ok();
         }
 
+        self.failed_swaps += 1;
+        if self.failed_swaps >= REINIT_AFTER_FAILED_SWAPS {
+            // Reinit resets counter, so failed one is retried after the same amount of failed swaps
+            self.reinit().ok();
+        }
+
+        Err(last_error)
+    }
+
+    /// Sends buffer to display without DMA. For use before interrupts are enabled, e.g. in init
+    pub fn swap_blocking(&mut self) -> Result<(), OperationError> {
+        Ok(self.i2c.write_blocking(I2C_ADDRESS, self.buffer.bytes())?)
+    }
+
+    fn reset_position(&mut self) -> Result<(), OperationError> {
+        self.set_window(0, (SCREEN_WIDTH - 1) as u8, 0, (PAGE_COUNT - 1) as u8)
+    }
+
+    /// Limits area written by following data to columns and pages in given inclusive ranges.
+    /// Write position is moved to start of window
+    pub fn set_window(
+        &mut self,
+        col_start: u8,
+        col_end: u8,
+        page_start: u8,
+        page_end: u8,
+    ) -> Result<(), OperationError> {
+        debug_assert!(col_start <= col_end && (col_end as usize) < SCREEN_WIDTH);
+        debug_assert!(page_start <= page_end && (page_end as usize) < PAGE_COUNT);
+
+        self.send_command(0x21)?; /*set column address*/
+        self.send_command(col_start)?;
+        self.send_command(col_end)?;
+        self.send_command(0x22)?; /*set page address*/
+        self.send_command(page_start)?;
+        self.send_command(page_end)?;
+
         Ok(())
     }
 
+    fn send_command(&mut self, cmd: u8) -> Result<(), OperationError> {
+        Ok(self.i2c.write_blocking(I2C_ADDRESS, &[0x0, cmd])?)
+    }
+
     fn send_image(&mut self) -> Result<(), OperationError> {
         let callback = |_| {
-            DRAWING.store(false, Ordering::Relaxed);
+            set_dma_transfer(false);
         };
 
-        self.send_buffer.copy_from_slice(&self.buffer);
+        self.send_buffer.copy_from_slice(self.buffer.bytes());
 
         critical_section::with(|cs| {
-            DRAWING.store(true, Ordering::Relaxed);
+            set_dma_transfer(true);
             let mut bus = self.i2c.borrow(cs).borrow_mut();
 
             // Safe: self.send_buffer will live forever, because display itself 'static
@@ -177,11 +328,13 @@ impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA>
 
             if let Err(e) = result {
                 // Revert drawing state
-                DRAWING.store(false, Ordering::Relaxed);
+                set_dma_transfer(false);
 
-                if let nb::Error::Other(_) = e {
-                    return Err(OperationError::I2CError);
-                }
+                return Err(match e {
+                    nb::Error::Other(e) => OperationError::I2CError(e.into()),
+                    // Bus is taken by other transfer, nothing is sent
+                    nb::Error::WouldBlock => OperationError::Busy,
+                });
             }
 
             Ok(())
@@ -191,6 +344,37 @@ impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA>
     }
 }
 
+#[cfg(target_os = "none")]
+impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA + AbortDma>
+    SSD1306<'bus, Pin<P, N, Output<PushPull>>, I2C>
+{
+    /// Stops frame transfer in progress, so bus is quiet before clocks are reconfigured, e.g. on entering
+    /// low-power mode, or display isn't frozen by transfer that never ends. Returns false if there was no transfer
+    ///
+    /// Aborted in critical section, so DMA completion interrupt can't run in between. If transfer has just
+    /// finished and interrupt is pending, its flags are cleared and handler does nothing: the transfer flag
+    /// is cleared here instead of completion callback
+    pub fn abort_transfer(&mut self) -> bool {
+        let aborted = critical_section::with(|cs| {
+            if !dma_transfer() {
+                return false;
+            }
+
+            self.i2c.borrow(cs).borrow_mut().abort_dma();
+            set_dma_transfer(false);
+            true
+        });
+
+        if aborted {
+            // Display stopped in the middle of frame, next one must start from top left
+            self.reset_position().ok();
+        }
+
+        aborted
+    }
+}
+
+#[cfg(target_os = "none")]
 impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA> Dimensions
     for SSD1306<'bus, Pin<P, N, Output<PushPull>>, I2C>
 {
@@ -205,6 +389,7 @@ impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA> Dim
     }
 }
 
+#[cfg(target_os = "none")]
 impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA> DrawTarget
     for SSD1306<'bus, Pin<P, N, Output<PushPull>>, I2C>
 {
@@ -222,10 +407,145 @@ impl<'bus, const P: char, const N: u8, I2C: BlockingI2C + I2CMasterWriteDMA> Dra
         Ok(())
     }
 
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.clear_region(*area, color);
+
+        Ok(())
+    }
+
     fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
-        let fill_byte = if color == BinaryColor::Off { 0 } else { 255 };
-        self.buffer[1..].fill(fill_byte); // Skip 1 data byte
+        self.buffer.fill(color);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame bytes after control byte
+    fn data(frame: &FrameBuffer) -> &[u8] {
+        &frame.bytes()[1..]
+    }
+
+    #[test]
+    fn points_outside_screen_are_skipped() {
+        let mut frame = FrameBuffer::default();
+        for p in [
+            Point::new(-1, 0),
+            Point::new(0, -1),
+            Point::new(SCREEN_WIDTH as i32, 0),
+            Point::new(0, SCREEN_HEIGHT as i32),
+            Point::new(i32::MIN, i32::MAX),
+        ] {
+            frame.dot(p, true);
+        }
+
+        assert_eq!(frame.bytes()[0], DATA_CONTROL_BYTE);
+        assert!(data(&frame).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn screen_corners_are_drawn() {
+        let mut frame = FrameBuffer::default();
+        frame.dot(Point::new(0, 0), true);
+        frame.dot(Point::new(127, 63), true);
+
+        assert_eq!(data(&frame)[0], 0x01);
+        assert_eq!(data(&frame)[BUFFER_SIZE - 1], 0x80);
+        assert_eq!(data(&frame).iter().filter(|&&b| b != 0).count(), 2);
+    }
+
+    #[test]
+    fn row_maps_to_page_by_page_height() {
+        // (point, byte index after control byte, bit)
+        let cases = [
+            (Point::new(0, 7), 0, 0x80),
+            (Point::new(0, 8), SCREEN_WIDTH, 0x01),
+            (Point::new(5, 9), SCREEN_WIDTH + 5, 0x02),
+            (Point::new(127, 31), 3 * SCREEN_WIDTH + 127, 0x80),
+            (Point::new(64, 60), 7 * SCREEN_WIDTH + 64, 0x10),
+        ];
+
+        for (p, index, bit) in cases {
+            let mut frame = FrameBuffer::default();
+            frame.dot(p, true);
+            assert_eq!(data(&frame)[index], bit, "{:?}", p);
+
+            frame.dot(p, false);
+            assert_eq!(data(&frame)[index], 0, "{:?}", p);
+        }
+    }
+
+    /// Frame with `area` drawn pixel by pixel
+    fn filled_by_dots(area: Rectangle, color: BinaryColor, background: BinaryColor) -> FrameBuffer {
+        let mut frame = FrameBuffer::default();
+        frame.fill(background);
+        for y in 0..area.size.height as i32 {
+            for x in 0..area.size.width as i32 {
+                frame.dot(area.top_left + Point::new(x, y), color.is_on());
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn page_aligned_region_matches_full_clear() {
+        let mut frame = FrameBuffer::default();
+        frame.fill(BinaryColor::On);
+        frame.clear_region(
+            Rectangle::new(Point::zero(), Size::new(128, 64)),
+            BinaryColor::Off,
+        );
+
+        let mut cleared = FrameBuffer::default();
+        cleared.fill(BinaryColor::Off);
+        assert_eq!(frame.bytes(), cleared.bytes());
+    }
+
+    #[test]
+    fn region_matches_pixel_by_pixel_fill() {
+        let areas = [
+            // Page aligned
+            Rectangle::new(Point::new(10, 16), Size::new(20, 16)),
+            // Inside single page
+            Rectangle::new(Point::new(0, 2), Size::new(5, 3)),
+            // Partial pages at top and bottom
+            Rectangle::new(Point::new(100, 5), Size::new(28, 20)),
+        ];
+
+        for area in areas {
+            for (color, background) in [
+                (BinaryColor::Off, BinaryColor::On),
+                (BinaryColor::On, BinaryColor::Off),
+            ] {
+                let mut frame = FrameBuffer::default();
+                frame.fill(background);
+                frame.clear_region(area, color);
+
+                let expected = filled_by_dots(area, color, background);
+                assert_eq!(frame.bytes(), expected.bytes(), "{:?}", area);
+            }
+        }
+    }
+
+    #[test]
+    fn region_is_clipped_to_screen() {
+        let area = Rectangle::new(Point::new(-10, 60), Size::new(20, 20));
+        let mut frame = FrameBuffer::default();
+        frame.clear_region(area, BinaryColor::On);
+
+        let visible = Rectangle::new(Point::new(0, 60), Size::new(10, 4));
+        let expected = filled_by_dots(visible, BinaryColor::On, BinaryColor::Off);
+        assert_eq!(frame.bytes(), expected.bytes());
+
+        // Fully outside
+        let mut frame = FrameBuffer::default();
+        frame.clear_region(
+            Rectangle::new(Point::new(200, 0), Size::new(5, 5)),
+            BinaryColor::On,
+        );
+        assert!(data(&frame).iter().all(|&b| b == 0));
+    }
+}
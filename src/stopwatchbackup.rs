@@ -0,0 +1,301 @@
+use crate::ds3231::{Error, DS3231};
+use crate::eeprom24::Eeprom24;
+use crate::i2c::BlockingI2C;
+use crate::stopwatchtimer::Stopwatch;
+
+/// Marks written backup blob
+const MAGIC: u8 = 0x5B;
+/// Increment on layout change to ignore old blobs
+const VERSION: u8 = 1;
+/// Address of backup blob in EEPROM: page after settings, so its writes never touch settings
+pub const BACKUP_ADDRESS: u16 = 32;
+/// Serialized size: magic, version, kind, value and checksum
+const BACKUP_SIZE: usize = 12;
+
+const _: () = assert!(
+    crate::settings::SETTINGS_SIZE <= BACKUP_ADDRESS as usize,
+    "Stopwatch backup overlaps settings"
+);
+
+/// Stopwatch state kept across resets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backup {
+    /// Stopped and reset, nothing to restore
+    Reset,
+    /// Paused with elapsed milliseconds
+    Paused(u32),
+    /// Running since UTC time in milliseconds from Unix epoch: RTC time minus elapsed time
+    Running(i64),
+}
+
+impl Backup {
+    fn to_bytes(self) -> [u8; BACKUP_SIZE] {
+        let (kind, value) = match self {
+            Backup::Reset => (0, 0),
+            Backup::Paused(elapsed_ms) => (1, elapsed_ms as i64),
+            Backup::Running(start_ms) => (2, start_ms),
+        };
+
+        let mut data = [0_u8; BACKUP_SIZE];
+        data[0] = MAGIC;
+        data[1] = VERSION;
+        data[2] = kind;
+        data[3..11].copy_from_slice(&value.to_le_bytes());
+        data[BACKUP_SIZE - 1] = crate::settings::checksum(&data[..BACKUP_SIZE - 1]);
+
+        data
+    }
+
+    /// Returns None if blob is empty, corrupted or written by other version
+    fn from_bytes(data: &[u8; BACKUP_SIZE]) -> Option<Self> {
+        if data[0] != MAGIC || data[1] != VERSION {
+            return None;
+        }
+
+        if crate::settings::checksum(&data[..BACKUP_SIZE - 1]) != data[BACKUP_SIZE - 1] {
+            return None;
+        }
+
+        let value = i64::from_le_bytes(data[3..11].try_into().ok()?);
+        match data[2] {
+            0 => Some(Backup::Reset),
+            1 => Some(Backup::Paused(value.try_into().ok()?)),
+            2 => Some(Backup::Running(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Stopwatch backup in EEPROM. Running stopwatch is kept as its start time by RTC, so elapsed time
+/// is counted by RTC while MCU is off and is restored on boot within a second
+///
+/// Start time is taken as RTC time minus elapsed time, so it must be saved again after each RTC
+/// write: time set while stopwatch runs shouldn't change elapsed time
+pub struct StopwatchBackup<I2C: BlockingI2C + 'static> {
+    eeprom: Eeprom24<I2C>,
+    rtc: DS3231<I2C>,
+    /// Last written or read backup to avoid needless write cycles
+    last: Option<Backup>,
+}
+
+impl<I2C: BlockingI2C> StopwatchBackup<I2C> {
+    pub fn new(eeprom: Eeprom24<I2C>, rtc: DS3231<I2C>) -> Self {
+        Self {
+            eeprom,
+            rtc,
+            last: None,
+        }
+    }
+
+    /// Restores stopwatch from backup. Must be called on boot while stopwatch is reset
+    ///
+    /// Start time later than RTC time means clock was set back while MCU was off: elapsed time is
+    /// unknown then, so stopwatch stays reset
+    pub fn restore(&mut self, stopwatch: &impl Stopwatch) -> Result<(), Error> {
+        let mut blob = [0_u8; BACKUP_SIZE];
+        self.eeprom.read(BACKUP_ADDRESS, &mut blob)?;
+
+        let backup = match Backup::from_bytes(&blob) {
+            Some(backup) => backup,
+            None => return Ok(()),
+        };
+
+        match backup {
+            Backup::Reset => {}
+            Backup::Paused(elapsed_ms) => stopwatch.restore(elapsed_ms as u64 * 1000, false),
+            Backup::Running(start_ms) => {
+                let elapsed_ms = self.rtc.update_time()?.timestamp_millis() - start_ms;
+                if elapsed_ms < 0 {
+                    // Stale backup is overwritten by next save
+                    return Ok(());
+                }
+                stopwatch.restore(elapsed_ms as u64 * 1000, true);
+            }
+        }
+
+        self.last = Some(backup);
+        Ok(())
+    }
+
+    /// Writes current stopwatch state if it differs from last written
+    pub fn save(&mut self, stopwatch: &impl Stopwatch) -> Result<(), Error> {
+        let elapsed_ms = stopwatch.elapsed();
+        let backup = if stopwatch.started() {
+            let now_ms = self.rtc.update_time()?.timestamp_millis();
+            Backup::Running(now_ms - elapsed_ms as i64)
+        } else if elapsed_ms > 0 {
+            Backup::Paused(elapsed_ms)
+        } else {
+            Backup::Reset
+        };
+
+        if self.last == Some(backup) {
+            return Ok(());
+        }
+
+        self.eeprom.write(BACKUP_ADDRESS, &backup.to_bytes())?;
+        self.last = Some(backup);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+
+    use critical_section::Mutex;
+
+    use super::*;
+    use crate::i2c::mock::MockI2c;
+    use crate::stopwatchtimer::StopwatchEvent;
+
+    /// RTC registers of 2024-03-09 13:45:07
+    const RTC_REGISTERS: [u8; 7] = [0x07, 0x45, 0x13, 0x06, 0x09, 0x03, 0x24];
+    /// The same time in milliseconds from Unix epoch
+    const RTC_MS: i64 = 1_709_991_907_000;
+
+    /// Stopwatch remembering last restore
+    #[derive(Default)]
+    struct FakeStopwatch {
+        elapsed_ms: Cell<u32>,
+        started: Cell<bool>,
+        restored: Cell<Option<(u64, bool)>>,
+    }
+
+    impl Stopwatch for FakeStopwatch {
+        fn start(&self) {
+            self.started.set(true);
+        }
+
+        fn stop(&self) {
+            self.started.set(false);
+            self.elapsed_ms.set(0);
+        }
+
+        fn pause(&self) {
+            self.started.set(false);
+        }
+
+        fn elapsed(&self) -> u32 {
+            self.elapsed_ms.get()
+        }
+
+        fn started(&self) -> bool {
+            self.started.get()
+        }
+
+        fn take_event(&self) -> Option<StopwatchEvent> {
+            None
+        }
+
+        fn restore(&self, elapsed_us: u64, started: bool) {
+            self.restored.set(Some((elapsed_us, started)));
+        }
+    }
+
+    fn backup(bus: &'static Mutex<RefCell<MockI2c>>) -> StopwatchBackup<MockI2c> {
+        StopwatchBackup::new(Eeprom24::new(bus), DS3231::new(bus))
+    }
+
+    #[test]
+    fn backup_round_trips() {
+        for backup in [
+            Backup::Reset,
+            Backup::Paused(0),
+            Backup::Paused(u32::MAX),
+            Backup::Running(RTC_MS),
+            Backup::Running(-1),
+        ] {
+            assert_eq!(Backup::from_bytes(&backup.to_bytes()), Some(backup));
+        }
+    }
+
+    #[test]
+    fn corrupted_backup_is_rejected() {
+        let blob = Backup::Running(RTC_MS).to_bytes();
+        for byte in 0..BACKUP_SIZE {
+            let mut corrupted = blob;
+            corrupted[byte] ^= 0x01;
+            assert_eq!(Backup::from_bytes(&corrupted), None, "byte {}", byte);
+        }
+
+        // Erased EEPROM
+        assert_eq!(Backup::from_bytes(&[0xFF; BACKUP_SIZE]), None);
+    }
+
+    #[test]
+    fn unknown_kind_and_negative_pause_are_rejected() {
+        for (kind, value) in [(3_u8, 0_i64), (1, -1)] {
+            let mut blob = Backup::Reset.to_bytes();
+            blob[2] = kind;
+            blob[3..11].copy_from_slice(&value.to_le_bytes());
+            blob[BACKUP_SIZE - 1] = crate::settings::checksum(&blob[..BACKUP_SIZE - 1]);
+
+            assert_eq!(Backup::from_bytes(&blob), None);
+        }
+    }
+
+    #[test]
+    fn save_writes_only_changes() {
+        let bus = MockI2c::bus(&[]);
+        let mut backup = backup(bus);
+        let stopwatch = FakeStopwatch::default();
+        stopwatch.elapsed_ms.set(1500);
+
+        backup.save(&stopwatch).unwrap();
+        backup.save(&stopwatch).unwrap();
+
+        let writes = MockI2c::writes(bus);
+        assert_eq!(writes.len(), 1);
+        let (_, bytes) = &writes[0];
+        assert_eq!(bytes[..2], BACKUP_ADDRESS.to_be_bytes());
+        assert_eq!(bytes[2..], Backup::Paused(1500).to_bytes());
+    }
+
+    #[test]
+    fn running_stopwatch_is_saved_as_start_time() {
+        let bus = MockI2c::bus(&[&RTC_REGISTERS]);
+        let stopwatch = FakeStopwatch::default();
+        stopwatch.elapsed_ms.set(90_000);
+        stopwatch.started.set(true);
+
+        backup(bus).save(&stopwatch).unwrap();
+
+        let (_, bytes) = &MockI2c::writes(bus)[1];
+        assert_eq!(bytes[2..], Backup::Running(RTC_MS - 90_000).to_bytes());
+    }
+
+    #[test]
+    fn running_backup_counts_time_by_rtc() {
+        let blob = Backup::Running(RTC_MS - 90_000).to_bytes();
+        let bus = MockI2c::bus(&[&blob, &RTC_REGISTERS]);
+        let stopwatch = FakeStopwatch::default();
+
+        backup(bus).restore(&stopwatch).unwrap();
+
+        assert_eq!(stopwatch.restored.get(), Some((90_000_000, true)));
+    }
+
+    #[test]
+    fn paused_backup_is_restored_paused() {
+        let blob = Backup::Paused(1500).to_bytes();
+        let bus = MockI2c::bus(&[&blob]);
+        let stopwatch = FakeStopwatch::default();
+
+        backup(bus).restore(&stopwatch).unwrap();
+
+        assert_eq!(stopwatch.restored.get(), Some((1_500_000, false)));
+    }
+
+    #[test]
+    fn start_after_rtc_time_is_ignored() {
+        // Clock was set back while MCU was off
+        let blob = Backup::Running(RTC_MS + 1000).to_bytes();
+        let bus = MockI2c::bus(&[&blob, &RTC_REGISTERS]);
+        let stopwatch = FakeStopwatch::default();
+
+        backup(bus).restore(&stopwatch).unwrap();
+
+        assert_eq!(stopwatch.restored.get(), None);
+    }
+}
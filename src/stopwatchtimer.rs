@@ -1,9 +1,14 @@
+#[cfg(target_os = "none")]
 use core::{
-    cell::RefCell,
-    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
 };
 
+#[cfg(target_os = "none")]
 use cortex_m::peripheral::NVIC;
+#[cfg(target_os = "none")]
+use critical_section::{CriticalSection, Mutex};
+#[cfg(target_os = "none")]
 use stm32f4xx_hal::{
     pac::Interrupt,
     prelude::*,
@@ -11,42 +16,209 @@ use stm32f4xx_hal::{
     timer::{CounterUs, Event, Instance, TimerExt},
 };
 
-/// Step between timer interrupts
-const TIMER_MS_STEP: u32 = 100;
+/// Maximum measured time: 99 hours, 59 mins, 59.99 secs. Stopwatch pauses itself on reaching it
+pub const MAX_ELAPSED_US: u64 = (60 * 60 * 99 + 60 * 59 + 59) * 1_000_000 + 990_000;
 
+/// Stored in place of event while there is none to take
+#[cfg(target_os = "none")]
+const NO_EVENT: u8 = 0;
+
+/// Transition of stopwatch between running and not running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopwatchEvent {
+    Started = 1,
+    Paused = 2,
+    /// Stopped and reset
+    Stopped = 3,
+    /// Paused itself on reaching [MAX_ELAPSED_US]
+    LimitReached = 4,
+}
+
+#[cfg(target_os = "none")]
+impl StopwatchEvent {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(StopwatchEvent::Started),
+            2 => Some(StopwatchEvent::Paused),
+            3 => Some(StopwatchEvent::Stopped),
+            4 => Some(StopwatchEvent::LimitReached),
+            _ => None,
+        }
+    }
+}
+
+/// Stopwatch used by app states. Hides timer peripheral behind it
+pub trait Stopwatch {
+    /// Starts counting or continues it after pause
+    fn start(&self);
+    /// Stops counting and resets elapsed time
+    fn stop(&self);
+    /// Stops counting keeping elapsed time
+    fn pause(&self);
+    /// Elapsed time in milliseconds
+    fn elapsed(&self) -> u32;
+    fn started(&self) -> bool;
+    /// Takes last transition since previous call, so it is handled once. Only the latest one is kept
+    fn take_event(&self) -> Option<StopwatchEvent>;
+    /// Sets elapsed time restored after reset and continues counting if `started`. Must be called
+    /// while stopwatch is reset
+    ///
+    /// Time at [MAX_ELAPSED_US] or above is clamped and reported as [StopwatchEvent::LimitReached]
+    fn restore(&self, elapsed_us: u64, started: bool);
+}
+
+/// Elapsed time after one more full counter period, saturated at [MAX_ELAPSED_US]
+#[cfg(any(target_os = "none", test))]
+fn add_period(accumulated_us: u64, period_us: u64) -> u64 {
+    (accumulated_us + period_us).min(MAX_ELAPSED_US)
+}
+
+/// Elapsed time of running stopwatch: accumulated periods, period whose interrupt is pending and counter
+///
+/// Clamped: period before limit is accounted by interrupt only at its end
+#[cfg(any(target_os = "none", test))]
+fn running_us(accumulated_us: u64, pending_period_us: u64, counter_us: u64) -> u64 {
+    (accumulated_us + pending_period_us + counter_us).min(MAX_ELAPSED_US)
+}
+
+/// Pending period and counter read consistently while period interrupt is blocked: pending flag, counter,
+/// pending flag again
+///
+/// Flag set between its reads means counter wrapped near counter read, so read value may be either side
+/// of wrap. Counter read again is after wrap for sure, pending period accounts the rest
+#[cfg(any(target_os = "none", test))]
+fn read_counter(
+    period_us: u64,
+    mut pending: impl FnMut() -> bool,
+    mut counter_us: impl FnMut() -> u64,
+) -> (u64, u64) {
+    let pending_before = pending();
+    let counter_before = counter_us();
+
+    if pending_before {
+        (period_us, counter_before)
+    } else if pending() {
+        (period_us, counter_us())
+    } else {
+        (0, counter_before)
+    }
+}
+
+#[cfg(target_os = "none")]
 pub struct StopwatchTimer<TIM: Instance> {
     timer: RefCell<CounterUs<TIM>>,
     it: Interrupt,
-    elapsed: AtomicU32,
+    /// Period of hardware counter. Interrupt only accounts full periods, elapsed time inside period is read from counter
+    period_ms: u32,
+    /// Microseconds of full counter periods and of partial periods before pauses
+    accumulated_us: Mutex<Cell<u64>>,
 
     started: AtomicBool,
+    /// Last [StopwatchEvent] not taken yet, [NO_EVENT] if none. Written from interrupt too, so single atomic
+    event: AtomicU8,
 }
 
+#[cfg(target_os = "none")]
 impl<TIM: Instance> StopwatchTimer<TIM> {
-    pub fn new(timer: TIM, tim_interrupt: Interrupt, clocks: &Clocks) -> Self {
+    /// Creates stopwatch counting with hardware counter of `period_ms`. Period must fit timer counter
+    /// at 1 MHz: up to 65 ms for 16-bit timers, longer periods need 32-bit TIM2 or TIM5
+    pub fn new(timer: TIM, tim_interrupt: Interrupt, period_ms: u32, clocks: &Clocks) -> Self {
+        assert!(period_ms > 0, "Stopwatch period must be positive");
+
         let mut tim = timer.counter(clocks);
-        tim.start(TIMER_MS_STEP.millis())
-            .expect("Failed to start timer");
+        tim.start(period_ms.millis())
+            .expect("Stopwatch period out of timer range");
         tim.listen(Event::Update);
         NVIC::mask(tim_interrupt);
 
         Self {
             timer: RefCell::new(tim),
             it: tim_interrupt,
-            elapsed: AtomicU32::new(0),
+            period_ms,
+            accumulated_us: Mutex::new(Cell::new(0)),
             started: AtomicBool::new(false),
+            event: AtomicU8::new(NO_EVENT),
         }
     }
 
+    /// Accounts full counter period. Interrupt don't affect precision, only rollover
+    ///
+    /// Time saturates at [MAX_ELAPSED_US] and stopwatch is paused there
+    #[inline]
+    pub fn handle_it(&self) {
+        critical_section::with(|cs| {
+            self.timer.borrow_mut().clear_interrupt(Event::Update);
+            if self.started() {
+                let acc = self.accumulated_us.borrow(cs);
+                let elapsed = add_period(acc.get(), self.period_us());
+                acc.set(elapsed);
+
+                if elapsed == MAX_ELAPSED_US {
+                    self.started.store(false, Ordering::Relaxed);
+                    NVIC::mask(self.it);
+                    self.report(StopwatchEvent::LimitReached);
+                }
+            }
+        });
+    }
+
+    /// Elapsed time in microseconds read from hardware counter
+    #[inline]
+    pub fn elapsed_us(&self) -> u64 {
+        critical_section::with(|cs| {
+            if self.started() {
+                self.current_us(cs)
+            } else {
+                self.accumulated_us.borrow(cs).get()
+            }
+        })
+    }
+
+    #[inline]
+    fn report(&self, event: StopwatchEvent) {
+        self.event.store(event as u8, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn period_us(&self) -> u64 {
+        self.period_ms as u64 * 1000
+    }
+
+    /// Accumulated time plus counter value. Must be called only while started
+    fn current_us(&self, cs: CriticalSection<'_>) -> u64 {
+        // Counter may be already wrapped while interrupt blocked by critical section
+        let (pending_period, counter_us) = read_counter(
+            self.period_us(),
+            || NVIC::is_pending(self.it),
+            || self.timer.borrow().now().ticks() as u64,
+        );
+
+        running_us(
+            self.accumulated_us.borrow(cs).get(),
+            pending_period,
+            counter_us,
+        )
+    }
+}
+
+#[cfg(target_os = "none")]
+impl<TIM: Instance> Stopwatch for StopwatchTimer<TIM> {
     #[inline]
-    pub fn start(&self) {
-        self.started.store(true, Ordering::Relaxed);
+    fn start(&self) {
+        if self.started() {
+            return;
+        }
+
+        critical_section::with(|_| {
+            // Restart timer, elapsed time before is accounted in `pause`
+            let mut timer = self.timer.borrow_mut();
+            timer.start(self.period_ms.millis()).unwrap();
+            timer.clear_interrupt(Event::Update);
+            NVIC::unpend(self.it);
 
-        // Restart timer
-        self.timer
-            .borrow_mut()
-            .start(TIMER_MS_STEP.millis())
-            .unwrap();
+            self.started.store(true, Ordering::Relaxed);
+        });
+        self.report(StopwatchEvent::Started);
 
         // Safe: TIM interrupts doesn't affect any critical-section locked resources
         unsafe {
@@ -55,36 +227,142 @@ impl<TIM: Instance> StopwatchTimer<TIM> {
     }
 
     #[inline]
-    pub fn stop(&self) {
-        self.elapsed.store(0, Ordering::Relaxed);
+    fn stop(&self) {
         self.started.store(false, Ordering::Relaxed);
         NVIC::mask(self.it);
+        critical_section::with(|cs| self.accumulated_us.borrow(cs).set(0));
+        self.report(StopwatchEvent::Stopped);
     }
 
     #[inline]
-    pub fn pause(&self) {
-        self.started.store(false, Ordering::Relaxed);
-        NVIC::mask(self.it);
+    fn pause(&self) {
+        critical_section::with(|cs| {
+            if !self.started.swap(false, Ordering::Relaxed) {
+                // Already paused, partial period is accounted
+                return;
+            }
+            NVIC::mask(self.it);
+
+            // Keep part of period passed since last interrupt, because `start` restarts timer from zero
+            self.accumulated_us.borrow(cs).set(self.current_us(cs));
+            self.report(StopwatchEvent::Paused);
+        });
     }
 
+    /// Elapsed time in milliseconds. Fits u32: [MAX_ELAPSED_US] is far below its limit
     #[inline]
-    pub fn handle_it(&self) {
-        self.timer.borrow_mut().clear_interrupt(Event::Update);
-        if self.started() {
-            self.elapsed.fetch_add(TIMER_MS_STEP, Ordering::Relaxed);
-        }
+    fn elapsed(&self) -> u32 {
+        (self.elapsed_us() / 1000) as u32
     }
 
     #[inline]
-    pub fn elapsed(&self) -> u32 {
-        self.elapsed.load(Ordering::Relaxed)
+    fn started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
     }
 
     #[inline]
-    pub fn started(&self) -> bool {
-        self.started.load(Ordering::Relaxed)
+    fn take_event(&self) -> Option<StopwatchEvent> {
+        StopwatchEvent::from_u8(self.event.swap(NO_EVENT, Ordering::Relaxed))
+    }
+
+    fn restore(&self, elapsed_us: u64, started: bool) {
+        critical_section::with(|cs| {
+            self.accumulated_us
+                .borrow(cs)
+                .set(elapsed_us.min(MAX_ELAPSED_US))
+        });
+
+        if elapsed_us >= MAX_ELAPSED_US {
+            self.report(StopwatchEvent::LimitReached);
+        } else if started {
+            self.start();
+        }
     }
 }
 
+#[cfg(target_os = "none")]
 unsafe impl<TIM: Instance> Sync for StopwatchTimer<TIM> {}
+#[cfg(target_os = "none")]
 unsafe impl<TIM: Instance> Send for StopwatchTimer<TIM> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_US: u64 = 50_000;
+
+    #[test]
+    fn pause_resume_cycles_keep_partial_periods() {
+        // Each cycle runs 2 full periods and 12.345 ms more, then pauses. Pause stores running time
+        // and resume restarts counter from zero
+        let mut accumulated_us = 0;
+        for _ in 0..1000 {
+            let mut periods_us = accumulated_us;
+            for _ in 0..2 {
+                periods_us = add_period(periods_us, PERIOD_US);
+            }
+            accumulated_us = running_us(periods_us, 0, 12_345);
+        }
+
+        assert_eq!(accumulated_us, 1000 * (2 * PERIOD_US + 12_345));
+    }
+
+    #[test]
+    fn pending_period_is_counted_once() {
+        // Counter wrapped to 100 µs while interrupt is blocked
+        assert_eq!(running_us(PERIOD_US, PERIOD_US, 100), 2 * PERIOD_US + 100);
+    }
+
+    /// Reads of pending flag and counter answered from lists in order
+    fn read_sequence(pending: &[bool], counter: &[u64]) -> (u64, u64) {
+        let mut pending = pending.iter().copied();
+        let mut counter = counter.iter().copied();
+        read_counter(
+            PERIOD_US,
+            || pending.next().unwrap(),
+            || counter.next().unwrap(),
+        )
+    }
+
+    #[test]
+    fn counter_without_wrap_is_read_once() {
+        assert_eq!(read_sequence(&[false, false], &[30_000]), (0, 30_000));
+        // Wrapped before reads: flag stays set while interrupt is blocked
+        assert_eq!(read_sequence(&[true], &[100]), (PERIOD_US, 100));
+    }
+
+    #[test]
+    fn wrap_between_reads_counts_pending_period() {
+        // Counter read after wrap: small value would lose whole period
+        assert_eq!(read_sequence(&[false, true], &[3, 4]), (PERIOD_US, 4));
+        // Counter read just before wrap: value after it is used, so time doesn't jump forward twice
+        assert_eq!(
+            read_sequence(&[false, true], &[PERIOD_US - 1, 2]),
+            (PERIOD_US, 2)
+        );
+    }
+
+    #[test]
+    fn periods_saturate_at_limit() {
+        let before_limit = MAX_ELAPSED_US - PERIOD_US / 2;
+        assert_eq!(add_period(before_limit, PERIOD_US), MAX_ELAPSED_US);
+        assert_eq!(add_period(MAX_ELAPSED_US, PERIOD_US), MAX_ELAPSED_US);
+    }
+
+    #[test]
+    fn running_time_is_clamped_before_limit_interrupt() {
+        let last_period = MAX_ELAPSED_US - PERIOD_US / 2;
+        assert_eq!(running_us(last_period, 0, PERIOD_US - 1), MAX_ELAPSED_US);
+        assert_eq!(running_us(last_period, PERIOD_US, 0), MAX_ELAPSED_US);
+    }
+
+    #[test]
+    fn limit_is_shown_as_99_59_59_99() {
+        let limit_ms = MAX_ELAPSED_US / 1000;
+        assert_eq!(limit_ms / 3_600_000, 99);
+        assert_eq!(limit_ms / 60_000 % 60, 59);
+        assert_eq!(limit_ms % 60_000, 59_990);
+        // Fits `Stopwatch::elapsed`
+        assert!(limit_ms <= u32::MAX as u64);
+    }
+}
@@ -0,0 +1,46 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use chrono::Duration;
+
+/// Seconds since boot. Counted by `tick` before taking state lock, so it is the time base of software clock
+static UPTIME_SECONDS: AtomicU32 = AtomicU32::new(0);
+/// Monotonic timestamp of last counted second, µs. Wraps each ~71 minutes, only difference with `now` is used
+static LAST_SECOND_US: AtomicU32 = AtomicU32::new(0);
+
+const US_PER_MS: u32 = 1000;
+const MS_PER_SECOND: u32 = 1000;
+
+/// Accounts one more second. Must be called only from `tick`
+#[inline]
+pub fn count_second() {
+    LAST_SECOND_US.store(now_us(), Ordering::Relaxed);
+    UPTIME_SECONDS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Seconds since boot. Resolution is one `tick`, use [uptime] for sub-second precision
+#[inline]
+pub fn uptime_seconds() -> u32 {
+    UPTIME_SECONDS.load(Ordering::Relaxed)
+}
+
+/// Time since boot with millisecond precision
+///
+/// Whole seconds come from `tick`, fraction is read from monotonic timer since last counted second
+pub fn uptime() -> Duration {
+    // `tick` has top priority and may run between loads: retry until seconds are unchanged around fraction read
+    loop {
+        let seconds = uptime_seconds();
+        let since_second_us = now_us().wrapping_sub(LAST_SECOND_US.load(Ordering::Relaxed));
+
+        if seconds == uptime_seconds() {
+            // `tick` may be late, but the fraction must not overflow into next second
+            let ms = (since_second_us / US_PER_MS).min(MS_PER_SECOND - 1);
+            return Duration::seconds(seconds as i64) + Duration::milliseconds(ms as i64);
+        }
+    }
+}
+
+#[inline]
+fn now_us() -> u32 {
+    crate::app::monotonics::now().ticks()
+}